@@ -2,16 +2,155 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use std::sync::Arc;
+use std::{fmt, sync::Arc, time::Duration};
 
 use glium::Texture2d;
 
 pub mod fake;
+pub mod file;
+mod gbm;
+pub mod limiter;
+mod memory;
+#[cfg(feature = "pipewire")]
+pub mod pipewire;
+pub mod retry;
 mod texture;
 pub mod wayland;
+pub mod x11;
 
 pub trait Capturer {
-    fn capture(&mut self) -> Arc<Texture2d>;
+    // Returns the latest frame. Size of the frame may change between captures.
+    fn capture(&mut self) -> Result<CapturedFrame, CaptureError>;
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CaptureError {
+    // Nothing has been captured yet
+    NotReady,
+    // The captured window has gone. Capture resumes when a matching window appears.
+    SourceLost,
+    // Capturing a frame failed, but the capturer keeps trying
+    Failed(String),
+    // No output has the specified name (yet). Capture resumes when it appears.
+    OutputNotFound(String),
+    // Connection to the compositor (or the capture thread) has been lost
+    Disconnected(String),
+    // The compositor lacks a protocol or requested a buffer format we cannot use
+    Unsupported(String),
+}
+
+impl CaptureError {
+    // Whether the capturer keeps working after this error.
+    // Otherwise it has to be created again (see `retry::Retrying`).
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            CaptureError::NotReady
+                | CaptureError::SourceLost
+                | CaptureError::Failed(_)
+                | CaptureError::OutputNotFound(_)
+        )
+    }
+}
+
+impl fmt::Display for CaptureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CaptureError::NotReady => write!(f, "Nothing has been captured yet"),
+            CaptureError::SourceLost => write!(f, "Captured window has gone"),
+            CaptureError::Failed(reason) => write!(f, "Capture failed: {}", reason),
+            CaptureError::OutputNotFound(name) => write!(f, "Output {} does not exist", name),
+            CaptureError::Disconnected(reason) => write!(f, "Disconnected: {}", reason),
+            CaptureError::Unsupported(what) => write!(f, "Unsupported: {}", what),
+        }
+    }
+}
+
+impl std::error::Error for CaptureError {}
+
+// Capturer which could not be created, e.g. a backend not built in. The screen shows a placeholder.
+pub struct Unavailable(pub CaptureError);
+
+impl Capturer for Unavailable {
+    fn capture(&mut self) -> Result<CapturedFrame, CaptureError> {
+        Err(self.0.clone())
+    }
+}
+
+pub struct CapturedFrame {
+    pub texture: Arc<Texture2d>,
+    // Regions changed since the previous capture (empty if nothing changed)
+    pub damage: Vec<DamageRect>,
+    // Timing of the latest frame, if the source provides it
+    pub timing: Option<FrameTiming>,
+}
+
+// Timestamps of a captured frame, in CLOCK_MONOTONIC (same as Wayland presentation times)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FrameTiming {
+    // When the compositor presented the content (when it was captured, if the compositor does not tell)
+    pub presented: Duration,
+    // When copying into our buffer finished
+    pub ready: Duration,
+}
+
+impl FrameTiming {
+    // For sources which generate or copy frames by themselves
+    pub fn now() -> Self {
+        let now = monotonic_now();
+        Self {
+            presented: now,
+            ready: now,
+        }
+    }
+}
+
+// Current time of CLOCK_MONOTONIC.
+// `Instant` uses the same clock, but cannot be converted from timestamps given by the compositor.
+pub fn monotonic_now() -> Duration {
+    let mut time = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // Cannot fail with a valid clock ID and pointer
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut time) };
+    Duration::new(time.tv_sec as u64, time.tv_nsec as u32)
+}
+
+impl CapturedFrame {
+    pub fn is_damaged(&self) -> bool {
+        !self.damage.is_empty()
+    }
+}
+
+// Rectangle in buffer coordinates (origin is top left)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DamageRect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl DamageRect {
+    pub fn full(width: u32, height: u32) -> Self {
+        Self {
+            x: 0,
+            y: 0,
+            width,
+            height,
+        }
+    }
 
-    fn resolution(&self) -> (u32, u32);
+    // Smallest rectangle containing both
+    pub fn union(self, other: Self) -> Self {
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        Self {
+            x,
+            y,
+            width: (self.x + self.width).max(other.x + other.width) - x,
+            height: (self.y + self.height).max(other.y + other.height) - y,
+        }
+    }
 }
@@ -2,48 +2,418 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+// Generated test patterns, for debugging and evaluation without a compositor
+
 use std::sync::Arc;
 
-use glium::{backend::Facade, Texture2d};
+use glium::{
+    backend::Facade,
+    texture::{ClientFormat, RawImage2d},
+    Rect, Texture2d,
+};
+
+use super::{
+    limiter::FrameLimiter, CaptureError, CapturedFrame, Capturer, DamageRect, FrameTiming,
+};
+use crate::config::Region;
+
+mod font;
 
-use super::Capturer;
+const WIDTH: u32 = 1920;
+const HEIGHT: u32 = 1080;
 
-const WIDTH: u32 = 640;
-const HEIGHT: u32 = 480;
+const BLACK: Color = (0, 0, 0);
+const WHITE: Color = (255, 255, 255);
+const GRAY: Color = (96, 96, 96);
+
+type Color = (u8, u8, u8);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Pattern {
+    Grid,          // Lines and circles for evaluating distortion, with a moving square
+    ColorBars,     // Color bars and gradients
+    TextSharpness, // Text and line pairs of several sizes
+    Latency,       // Flashing patch and frame counter for measuring motion-to-photon latency
+}
+
+impl Pattern {
+    // Selected by `output_name` of the capture config
+    pub fn from_output_name(name: &str) -> Option<Self> {
+        match name {
+            "_grid" => Some(Self::Grid),
+            "_color_bars" => Some(Self::ColorBars),
+            "_text_sharpness" => Some(Self::TextSharpness),
+            "_latency" => Some(Self::Latency),
+            // Showed an embedded screenshot in older versions
+            "_fake_desktop" => Some(Self::Grid),
+            _ => None,
+        }
+    }
+
+    fn is_animated(self) -> bool {
+        matches!(self, Self::Grid | Self::Latency)
+    }
+}
 
 pub struct FakeCapturer {
+    pattern: Pattern,
+    region: Option<Region>,
+    background: Canvas, // Part of the pattern which does not change
     texture: Arc<Texture2d>,
+    frame_count: u64,
+    first_frame: bool,
+    limiter: FrameLimiter,
+    timing: FrameTiming, // When the current frame was drawn
 }
 
 impl FakeCapturer {
-    pub fn new(facade: &impl Facade) -> Self {
-        log::warn!("Fake capture is used. This is only for debugging.");
-
-        let tex = Texture2d::new(
-            facade,
-            include_bytes!("fake_desktop.bin")
-                .chunks(4 * WIDTH as usize)
-                .map(|row| {
-                    row.chunks(4)
-                        .map(|pixel| (pixel[0], pixel[1], pixel[2], pixel[3]))
-                        .collect()
-                })
-                .collect::<Vec<_>>(),
-        )
-        .unwrap();
+    // The texture is shared only with the renderer on this thread
+    #[allow(clippy::arc_with_non_send_sync)]
+    pub fn new(
+        facade: &impl Facade,
+        pattern: Pattern,
+        region: Option<Region>,
+        limiter: FrameLimiter,
+    ) -> Self {
+        log::warn!(
+            "Fake capture ({:?}) is used. This is only for debugging.",
+            pattern
+        );
+
+        let background = match pattern {
+            Pattern::Grid => draw_grid(),
+            Pattern::ColorBars => draw_color_bars(),
+            Pattern::TextSharpness => draw_text_sharpness(),
+            Pattern::Latency => Canvas::new(WIDTH, HEIGHT, BLACK),
+        };
+
+        let frame = draw_frame(pattern, &background, 0, region);
+        let texture = Texture2d::new(facade, frame.to_raw_image()).unwrap();
 
         Self {
-            texture: Arc::new(tex),
+            pattern,
+            region,
+            background,
+            texture: Arc::new(texture),
+            frame_count: 0,
+            first_frame: true,
+            limiter,
+            timing: FrameTiming::now(),
         }
     }
 }
 
 impl Capturer for FakeCapturer {
-    fn capture(&mut self) -> Arc<Texture2d> {
-        Arc::clone(&self.texture)
+    fn capture(&mut self) -> Result<CapturedFrame, CaptureError> {
+        let damaged = if self.pattern.is_animated() && !self.first_frame && self.limiter.is_due() {
+            self.limiter.frame_captured();
+            self.frame_count += 1;
+            let frame = draw_frame(
+                self.pattern,
+                &self.background,
+                self.frame_count,
+                self.region,
+            );
+            self.texture.write(
+                Rect {
+                    left: 0,
+                    bottom: 0,
+                    width: frame.width,
+                    height: frame.height,
+                },
+                frame.to_raw_image(),
+            );
+            self.timing = FrameTiming::now();
+            true
+        } else {
+            // Static patterns are damaged only in the first frame
+            self.first_frame
+        };
+        self.first_frame = false;
+
+        let damage = if damaged {
+            let (width, height) = self.texture.dimensions();
+            vec![DamageRect::full(width, height)]
+        } else {
+            vec![]
+        };
+
+        Ok(CapturedFrame {
+            texture: Arc::clone(&self.texture),
+            damage,
+            timing: Some(self.timing),
+        })
+    }
+}
+
+// XRGB8888 image (BGRX in bytes), same as captured frames
+#[derive(Clone)]
+struct Canvas {
+    width: u32,
+    height: u32,
+    data: Vec<u8>,
+}
+
+impl Canvas {
+    fn new(width: u32, height: u32, color: Color) -> Self {
+        let mut canvas = Self {
+            width,
+            height,
+            data: vec![0; 4 * width as usize * height as usize],
+        };
+        canvas.fill_rect(0, 0, width, height, color);
+        canvas
     }
 
-    fn resolution(&self) -> (u32, u32) {
-        (WIDTH, HEIGHT)
+    fn set_pixel(&mut self, x: i32, y: i32, color: Color) {
+        if x < 0 || y < 0 || x >= self.width as i32 || y >= self.height as i32 {
+            return;
+        }
+        let index = 4 * (y as usize * self.width as usize + x as usize);
+        self.data[index..index + 4].copy_from_slice(&[color.2, color.1, color.0, 255]);
+    }
+
+    // Parts outside the canvas are clipped
+    fn fill_rect(&mut self, x: i32, y: i32, width: u32, height: u32, color: Color) {
+        let left = x.clamp(0, self.width as i32);
+        let top = y.clamp(0, self.height as i32);
+        let right = (x + width as i32).clamp(0, self.width as i32);
+        let bottom = (y + height as i32).clamp(0, self.height as i32);
+        for y in top..bottom {
+            for x in left..right {
+                self.set_pixel(x, y, color);
+            }
+        }
+    }
+
+    // Each pixel of the font becomes a square of `scale` pixels
+    fn draw_text(&mut self, x: i32, y: i32, scale: u32, text: &str, color: Color) {
+        for (i, c) in text.chars().enumerate() {
+            let glyph_x = x + (i as u32 * (font::GLYPH_WIDTH + 1) * scale) as i32;
+            for (row, bits) in font::glyph(c).iter().enumerate() {
+                for column in 0..font::GLYPH_WIDTH {
+                    if bits & (1 << (font::GLYPH_WIDTH - 1 - column)) != 0 {
+                        self.fill_rect(
+                            glyph_x + (column * scale) as i32,
+                            y + (row as u32 * scale) as i32,
+                            scale,
+                            scale,
+                            color,
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    // Crop the image, clamping the region into the image
+    fn crop(self, region: Option<Region>) -> Self {
+        let Some(region) = region else {
+            return self;
+        };
+        let left = region.x.clamp(0, self.width as i32 - 1) as usize;
+        let top = region.y.clamp(0, self.height as i32 - 1) as usize;
+        let right = (region.x + region.width).clamp(left as i32 + 1, self.width as i32) as usize;
+        let bottom = (region.y + region.height).clamp(top as i32 + 1, self.height as i32) as usize;
+
+        let data = self
+            .data
+            .chunks(4 * self.width as usize)
+            .skip(top)
+            .take(bottom - top)
+            .flat_map(|row| &row[4 * left..4 * right])
+            .copied()
+            .collect();
+
+        Self {
+            width: (right - left) as u32,
+            height: (bottom - top) as u32,
+            data,
+        }
+    }
+
+    fn to_raw_image(&self) -> RawImage2d<'_, u8> {
+        RawImage2d {
+            data: self.data.as_slice().into(),
+            width: self.width,
+            height: self.height,
+            format: ClientFormat::U8U8U8U8,
+        }
+    }
+}
+
+// Animated part is drawn over the background, and cropped to the region
+fn draw_frame(
+    pattern: Pattern,
+    background: &Canvas,
+    frame_count: u64,
+    region: Option<Region>,
+) -> Canvas {
+    let mut canvas = background.clone();
+    match pattern {
+        Pattern::Grid => {
+            // Moves 8 pixels per frame along the horizontal center line
+            let x = (frame_count * 8 % WIDTH as u64) as i32;
+            canvas.fill_rect(x - 20, HEIGHT as i32 / 2 - 20, 40, 40, WHITE);
+        }
+        Pattern::Latency => draw_latency(&mut canvas, frame_count),
+        _ => (),
+    }
+    canvas.crop(region)
+}
+
+fn draw_grid() -> Canvas {
+    let mut canvas = Canvas::new(WIDTH, HEIGHT, BLACK);
+    let (center_x, center_y) = (WIDTH as f32 / 2.0, HEIGHT as f32 / 2.0);
+
+    // Concentric circles every 200 pixels around the center
+    for y in 0..HEIGHT {
+        for x in 0..WIDTH {
+            let distance = (x as f32 - center_x).hypot(y as f32 - center_y);
+            if distance > 100.0 && distance % 200.0 < 2.0 {
+                canvas.set_pixel(x as i32, y as i32, (255, 160, 0));
+            }
+        }
+    }
+
+    // Thin lines every 40 pixels and thick lines every 200 pixels
+    for x in (0..WIDTH).step_by(40) {
+        let (width, color) = if x % 200 == 0 { (3, WHITE) } else { (1, GRAY) };
+        canvas.fill_rect(x as i32 - 1, 0, width, HEIGHT, color);
+    }
+    for y in (0..HEIGHT).step_by(40) {
+        let (height, color) = if y % 200 == 0 { (3, WHITE) } else { (1, GRAY) };
+        canvas.fill_rect(0, y as i32 - 1, WIDTH, height, color);
+    }
+
+    // Coordinates of intersections of thick lines
+    for x in (0..WIDTH).step_by(200) {
+        for y in (0..HEIGHT).step_by(200) {
+            canvas.draw_text(
+                x as i32 + 6,
+                y as i32 + 6,
+                2,
+                &format!("{} {}", x, y),
+                WHITE,
+            );
+        }
+    }
+
+    canvas
+}
+
+fn draw_color_bars() -> Canvas {
+    let mut canvas = Canvas::new(WIDTH, HEIGHT, BLACK);
+
+    // 75% bars
+    const BARS: [Color; 7] = [
+        (191, 191, 191),
+        (191, 191, 0),
+        (0, 191, 191),
+        (0, 191, 0),
+        (191, 0, 191),
+        (191, 0, 0),
+        (0, 0, 191),
+    ];
+    let bar_width = WIDTH.div_ceil(BARS.len() as u32);
+    let bar_height = HEIGHT * 2 / 3;
+    for (i, color) in BARS.iter().enumerate() {
+        canvas.fill_rect(
+            (i as u32 * bar_width) as i32,
+            0,
+            bar_width,
+            bar_height,
+            *color,
+        );
+    }
+
+    // Gradients of gray, red, green and blue
+    let ramp_height = (HEIGHT - bar_height) / 4;
+    for x in 0..WIDTH {
+        let value = (x * 256 / WIDTH) as u8;
+        let colors = [
+            (value, value, value),
+            (value, 0, 0),
+            (0, value, 0),
+            (0, 0, value),
+        ];
+        for (i, color) in colors.iter().enumerate() {
+            let y = bar_height + i as u32 * ramp_height;
+            canvas.fill_rect(x as i32, y as i32, 1, ramp_height, *color);
+        }
+    }
+
+    canvas
+}
+
+fn draw_text_sharpness() -> Canvas {
+    const TEXT: &str = "THE QUICK BROWN FOX JUMPS OVER THE LAZY DOG 0123456789";
+
+    let mut canvas = Canvas::new(WIDTH, HEIGHT, WHITE);
+
+    // Dark text on bright background, and bright text on dark background
+    let mut y = 20;
+    for scale in 1..=4 {
+        canvas.draw_text(20, y, scale, TEXT, BLACK);
+        y += (font::GLYPH_HEIGHT * scale + 12) as i32;
+    }
+    canvas.fill_rect(0, y, WIDTH, 200, BLACK);
+    y += 20;
+    for scale in 1..=4 {
+        canvas.draw_text(20, y, scale, TEXT, WHITE);
+        y += (font::GLYPH_HEIGHT * scale + 12) as i32;
+    }
+
+    // Line pairs of 1 to 4 pixels, vertical and horizontal
+    let top = 480;
+    for (i, width) in (1..=4).enumerate() {
+        let left = 20 + i as i32 * 460;
+        canvas.draw_text(left, top, 3, &format!("{}PX", width), BLACK);
+        for x in (0..200).step_by(2 * width as usize) {
+            canvas.fill_rect(left + x, top + 40, width, 200, BLACK);
+        }
+        for y in (0..200).step_by(2 * width as usize) {
+            canvas.fill_rect(left + 220, top + 40 + y, 200, width, BLACK);
+        }
+    }
+
+    // Checkerboard of 1 pixel
+    for y in 760..(HEIGHT as i32 - 20) {
+        for x in 20..(WIDTH as i32 - 20) {
+            if (x + y) % 2 == 0 {
+                canvas.set_pixel(x, y, BLACK);
+            }
+        }
+    }
+
+    canvas
+}
+
+fn draw_latency(canvas: &mut Canvas, frame_count: u64) {
+    // Patch flashing for 4 frames in every 60 frames (for photodiodes or high speed cameras)
+    let flash = if frame_count % 60 < 4 { WHITE } else { BLACK };
+    canvas.fill_rect(40, 40, 240, 240, flash);
+    canvas.fill_rect(40, 290, 240, 4, GRAY);
+
+    // Frame counter in decimal
+    let text = frame_count.to_string();
+    let scale = 24;
+    let text_width = text.len() as u32 * (font::GLYPH_WIDTH + 1) * scale;
+    canvas.draw_text(
+        (WIDTH as i32 - text_width as i32) / 2,
+        (HEIGHT as i32 - (font::GLYPH_HEIGHT * scale) as i32) / 2,
+        scale,
+        &text,
+        WHITE,
+    );
+
+    // Lower 32 bits of the frame counter in binary (most significant bit on the left).
+    // Gray marks below the bits show their positions.
+    for bit in 0..32 {
+        let x = 80 + (31 - bit) * 55;
+        if frame_count & (1 << bit) != 0 {
+            canvas.fill_rect(x, HEIGHT as i32 - 140, 40, 40, WHITE);
+        }
+        canvas.fill_rect(x, HEIGHT as i32 - 90, 40, 4, GRAY);
     }
 }
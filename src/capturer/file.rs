@@ -0,0 +1,228 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Capture source showing image files (PNG, JPEG or raw XRGB8888 dumps), for demos and testing
+
+use std::{
+    cell::Cell,
+    fs,
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use glium::{
+    backend::Facade,
+    texture::{ClientFormat, RawImage2d},
+    Texture2d,
+};
+use image::{imageops, RgbaImage};
+
+use super::{limiter::FrameLimiter, CaptureError, CapturedFrame, Capturer, DamageRect};
+use crate::config::{FileSource, Region};
+
+pub struct FileCapturer {
+    frames: Vec<Arc<Texture2d>>,
+    frame_interval: Option<Duration>, // None if the first frame is shown forever
+    clock: FileClock,
+    start_time: Instant,
+    current: Option<usize>,
+    limiter: FrameLimiter,
+}
+
+// Time which picks the image. Offscreen mode sets it from the IMU trace, so that its output
+// does not depend on the rendering speed.
+#[derive(Clone, Debug, Default)]
+pub enum FileClock {
+    #[default]
+    Wall,
+    // Time since the start
+    Manual(Rc<Cell<Duration>>),
+}
+
+impl FileCapturer {
+    // Textures are shared only with the renderer on this thread
+    #[allow(clippy::arc_with_non_send_sync)]
+    pub fn new(
+        facade: &impl Facade,
+        source: &FileSource,
+        region: Option<Region>,
+        limiter: FrameLimiter,
+        clock: FileClock,
+    ) -> Result<Self, CaptureError> {
+        let paths = list_files(&source.path)?;
+        if paths.is_empty() {
+            return Err(CaptureError::Unsupported(format!(
+                "no image file in {}",
+                source.path.display()
+            )));
+        }
+        log::info!("Loading {} image file(s)", paths.len());
+
+        let frames = paths
+            .iter()
+            .map(|path| {
+                let mut image = load_image(path, source.raw_size)?;
+                if let Some(region) = region {
+                    image = crop(&image, region);
+                }
+                Ok(Arc::new(upload(facade, image)?))
+            })
+            .collect::<Result<_, CaptureError>>()?;
+
+        let frame_interval = source
+            .frame_rate
+            .filter(|rate| *rate > 0.0)
+            .map(|rate| Duration::from_secs_f32(1.0 / rate));
+
+        Ok(Self {
+            frames,
+            frame_interval,
+            clock,
+            start_time: Instant::now(),
+            current: None,
+            limiter,
+        })
+    }
+
+    fn now(&self) -> Instant {
+        match &self.clock {
+            FileClock::Wall => Instant::now(),
+            FileClock::Manual(time) => self.start_time + time.get(),
+        }
+    }
+}
+
+impl Capturer for FileCapturer {
+    fn capture(&mut self) -> Result<CapturedFrame, CaptureError> {
+        // Frames are cycled at the frame rate
+        let now = self.now();
+        let mut index = match self.frame_interval {
+            Some(interval) => {
+                let elapsed = (now - self.start_time).as_secs_f64();
+                (elapsed / interval.as_secs_f64()) as usize % self.frames.len()
+            }
+            None => 0,
+        };
+        // Keep showing the current image until the capture frame rate limit allows the next one
+        if let Some(current) = self.current.filter(|_| !self.limiter.is_due_at(now)) {
+            index = current;
+        }
+
+        let texture = Arc::clone(&self.frames[index]);
+        let damage = if self.current != Some(index) {
+            self.limiter.frame_captured_at(now);
+            let (width, height) = texture.dimensions();
+            vec![DamageRect::full(width, height)]
+        } else {
+            vec![]
+        };
+        self.current = Some(index);
+
+        // Images are not presented by anything, so they have no timing
+        Ok(CapturedFrame {
+            texture,
+            damage,
+            timing: None,
+        })
+    }
+}
+
+// A file, or files in a directory in the order of their names
+fn list_files(path: &Path) -> Result<Vec<PathBuf>, CaptureError> {
+    if !path.is_dir() {
+        return Ok(vec![path.to_owned()]);
+    }
+
+    let mut paths: Vec<_> = fs::read_dir(path)
+        .and_then(|entries| {
+            entries
+                .map(|entry| Ok(entry?.path()))
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .map_err(|err| load_error(path, err))?
+        .into_iter()
+        .filter(|path| {
+            path.extension()
+                .and_then(|ext| ext.to_str())
+                .is_some_and(|ext| {
+                    ["png", "jpg", "jpeg", "raw", "bin"].contains(&ext.to_lowercase().as_str())
+                })
+        })
+        .collect();
+    paths.sort();
+    Ok(paths)
+}
+
+fn load_error(path: &Path, err: impl std::fmt::Display) -> CaptureError {
+    CaptureError::Failed(format!("cannot load {}: {}", path.display(), err))
+}
+
+// Files with the extension `raw` or `bin` are XRGB8888 frames of `raw_size`
+fn load_image(path: &Path, raw_size: Option<(u32, u32)>) -> Result<RgbaImage, CaptureError> {
+    let is_raw = path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("raw") || ext.eq_ignore_ascii_case("bin"));
+
+    if is_raw {
+        let (width, height) = raw_size.ok_or_else(|| {
+            CaptureError::Unsupported(format!("raw frame {} without raw_size", path.display()))
+        })?;
+        let data = fs::read(path).map_err(|err| load_error(path, err))?;
+        if data.len() != 4 * width as usize * height as usize {
+            return Err(load_error(path, "size does not match raw_size"));
+        }
+
+        // BGRX bytes to RGBA
+        let pixels = data
+            .chunks(4)
+            .flat_map(|pixel| [pixel[2], pixel[1], pixel[0], 255])
+            .collect();
+        Ok(RgbaImage::from_raw(width, height, pixels).unwrap())
+    } else {
+        Ok(image::open(path)
+            .map_err(|err| load_error(path, err))?
+            .into_rgba8())
+    }
+}
+
+// Crop the image, clamping the region into the image
+fn crop(image: &RgbaImage, region: Region) -> RgbaImage {
+    let (width, height) = (image.width() as i32, image.height() as i32);
+    let left = region.x.clamp(0, width - 1);
+    let top = region.y.clamp(0, height - 1);
+    let right = (region.x + region.width).clamp(left + 1, width);
+    let bottom = (region.y + region.height).clamp(top + 1, height);
+
+    imageops::crop_imm(
+        image,
+        left as u32,
+        top as u32,
+        (right - left) as u32,
+        (bottom - top) as u32,
+    )
+    .to_image()
+}
+
+fn upload(facade: &impl Facade, image: RgbaImage) -> Result<Texture2d, CaptureError> {
+    let (width, height) = image.dimensions();
+
+    // Textures of captured frames are XRGB8888 (BGRX in bytes)
+    let data = image
+        .pixels()
+        .flat_map(|pixel| [pixel[2], pixel[1], pixel[0], 255])
+        .collect::<Vec<_>>();
+
+    Texture2d::new(
+        facade,
+        RawImage2d {
+            data: data.into(),
+            width,
+            height,
+            format: ClientFormat::U8U8U8U8,
+        },
+    )
+    .map_err(|err| CaptureError::Failed(format!("cannot create texture: {}", err)))
+}
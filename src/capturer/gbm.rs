@@ -0,0 +1,190 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Allocation of DMA-BUFs with GBM, which works with any driver unlike exporting GL textures.
+// libgbm is loaded at runtime, so that it is not needed when textures are exported.
+
+use std::{
+    fs::OpenOptions,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd},
+        raw::{c_int, c_uint, c_void},
+    },
+    path::Path,
+};
+
+use drm_fourcc::{DrmFourcc, DrmModifier};
+use libloading::Library;
+
+use super::texture::DmabufPlane;
+
+const GBM_BO_USE_RENDERING: u32 = 1 << 2;
+
+type GbmCreateDevice = unsafe extern "C" fn(c_int) -> *mut c_void;
+type GbmDeviceDestroy = unsafe extern "C" fn(*mut c_void);
+type GbmBoCreate = unsafe extern "C" fn(*mut c_void, u32, u32, u32, u32) -> *mut c_void;
+type GbmBoCreateWithModifiers2 =
+    unsafe extern "C" fn(*mut c_void, u32, u32, u32, *const u64, c_uint, u32) -> *mut c_void;
+type GbmBoGetPlaneCount = unsafe extern "C" fn(*mut c_void) -> c_int;
+type GbmBoGetFdForPlane = unsafe extern "C" fn(*mut c_void, c_int) -> c_int;
+type GbmBoGetOffset = unsafe extern "C" fn(*mut c_void, c_int) -> u32;
+type GbmBoGetStrideForPlane = unsafe extern "C" fn(*mut c_void, c_int) -> u32;
+type GbmBoGetModifier = unsafe extern "C" fn(*mut c_void) -> u64;
+type GbmBoDestroy = unsafe extern "C" fn(*mut c_void);
+
+pub struct GbmDevice {
+    device: *mut c_void,
+    device_destroy: GbmDeviceDestroy,
+    bo_create: GbmBoCreate,
+    bo_create_with_modifiers2: GbmBoCreateWithModifiers2,
+    bo_get_plane_count: GbmBoGetPlaneCount,
+    bo_get_fd_for_plane: GbmBoGetFdForPlane,
+    bo_get_offset: GbmBoGetOffset,
+    bo_get_stride_for_plane: GbmBoGetStrideForPlane,
+    bo_get_modifier: GbmBoGetModifier,
+    bo_destroy: GbmBoDestroy,
+    _fd: OwnedFd, // Used by `device`
+    _library: Library,
+}
+
+// Buffer allocated by GBM. Its memory lives as long as any of the fds or imported images.
+pub struct GbmBuffer {
+    pub planes: Vec<DmabufPlane>,
+    pub modifier: DrmModifier,
+}
+
+impl GbmDevice {
+    // `node` is a DRM device, usually a render node such as /dev/dri/renderD128
+    pub fn open(node: &Path) -> Result<Self, String> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(node)
+            .map_err(|err| format!("cannot open {}: {}", node.display(), err))?;
+        let fd = OwnedFd::from(file);
+
+        unsafe {
+            let library = Library::new("libgbm.so.1").map_err(|err| err.to_string())?;
+            let symbol = |name: &str| -> Result<*const c_void, String> {
+                library
+                    .get::<*const c_void>(name.as_bytes())
+                    .map(|symbol| *symbol)
+                    .map_err(|err| err.to_string())
+            };
+
+            let create_device: GbmCreateDevice = std::mem::transmute(symbol("gbm_create_device")?);
+            let device_destroy: GbmDeviceDestroy =
+                std::mem::transmute(symbol("gbm_device_destroy")?);
+            let bo_create: GbmBoCreate = std::mem::transmute(symbol("gbm_bo_create")?);
+            let bo_create_with_modifiers2: GbmBoCreateWithModifiers2 =
+                std::mem::transmute(symbol("gbm_bo_create_with_modifiers2")?);
+            let bo_get_plane_count: GbmBoGetPlaneCount =
+                std::mem::transmute(symbol("gbm_bo_get_plane_count")?);
+            let bo_get_fd_for_plane: GbmBoGetFdForPlane =
+                std::mem::transmute(symbol("gbm_bo_get_fd_for_plane")?);
+            let bo_get_offset: GbmBoGetOffset = std::mem::transmute(symbol("gbm_bo_get_offset")?);
+            let bo_get_stride_for_plane: GbmBoGetStrideForPlane =
+                std::mem::transmute(symbol("gbm_bo_get_stride_for_plane")?);
+            let bo_get_modifier: GbmBoGetModifier =
+                std::mem::transmute(symbol("gbm_bo_get_modifier")?);
+            let bo_destroy: GbmBoDestroy = std::mem::transmute(symbol("gbm_bo_destroy")?);
+
+            let device = create_device(fd.as_raw_fd());
+            if device.is_null() {
+                return Err(format!("cannot create GBM device on {}", node.display()));
+            }
+
+            Ok(Self {
+                device,
+                device_destroy,
+                bo_create,
+                bo_create_with_modifiers2,
+                bo_get_plane_count,
+                bo_get_fd_for_plane,
+                bo_get_offset,
+                bo_get_stride_for_plane,
+                bo_get_modifier,
+                bo_destroy,
+                _fd: fd,
+                _library: library,
+            })
+        }
+    }
+
+    // Allocate a buffer with one of `modifiers`. The driver chooses the layout if it is only `Invalid`.
+    pub fn allocate(
+        &self,
+        width: u32,
+        height: u32,
+        fourcc: DrmFourcc,
+        modifiers: &[DrmModifier],
+    ) -> Result<GbmBuffer, String> {
+        let explicit: Vec<u64> = modifiers
+            .iter()
+            .filter(|&&modifier| modifier != DrmModifier::Invalid)
+            .map(|&modifier| modifier.into())
+            .collect();
+
+        unsafe {
+            let bo = if explicit.is_empty() {
+                (self.bo_create)(
+                    self.device,
+                    width,
+                    height,
+                    fourcc as u32,
+                    GBM_BO_USE_RENDERING,
+                )
+            } else {
+                (self.bo_create_with_modifiers2)(
+                    self.device,
+                    width,
+                    height,
+                    fourcc as u32,
+                    explicit.as_ptr(),
+                    explicit.len() as c_uint,
+                    GBM_BO_USE_RENDERING,
+                )
+            };
+            if bo.is_null() {
+                return Err(format!(
+                    "cannot allocate {}x{} {:?} buffer with modifiers {:?}",
+                    width, height, fourcc, modifiers
+                ));
+            }
+
+            // The buffer object is not needed once its planes are exported
+            let num_planes = (self.bo_get_plane_count)(bo);
+            let planes: Result<Vec<_>, _> = (0..num_planes)
+                .map(|plane| {
+                    let fd = (self.bo_get_fd_for_plane)(bo, plane);
+                    if fd < 0 {
+                        return Err("cannot export GBM buffer".to_owned());
+                    }
+                    Ok(DmabufPlane {
+                        fd: OwnedFd::from_raw_fd(fd),
+                        offset: (self.bo_get_offset)(bo, plane),
+                        stride: (self.bo_get_stride_for_plane)(bo, plane),
+                    })
+                })
+                .collect();
+            let modifier = if explicit.is_empty() {
+                DrmModifier::Invalid
+            } else {
+                (self.bo_get_modifier)(bo).into()
+            };
+            (self.bo_destroy)(bo);
+
+            Ok(GbmBuffer {
+                planes: planes?,
+                modifier,
+            })
+        }
+    }
+}
+
+impl Drop for GbmDevice {
+    fn drop(&mut self) {
+        unsafe { (self.device_destroy)(self.device) };
+    }
+}
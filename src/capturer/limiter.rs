@@ -0,0 +1,109 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Limit of the capture frame rate, independent of the render frame rate.
+// Capturing less often reduces the load of the compositor, e.g. on Raspberry Pi,
+// while head tracking still runs at the full refresh rate of the glasses.
+
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug)]
+pub struct FrameLimiter {
+    interval: Duration, // Zero if not limited
+    last_frame: Option<Instant>,
+}
+
+impl FrameLimiter {
+    pub fn new(max_frame_rate: Option<f32>) -> Self {
+        let interval = max_frame_rate
+            .filter(|rate| *rate > 0.0)
+            .map(|rate| Duration::from_secs_f32(1.0 / rate))
+            .unwrap_or_default();
+        if !interval.is_zero() {
+            log::info!("Capture frame interval: {:?}", interval);
+        }
+
+        Self {
+            interval,
+            last_frame: None,
+        }
+    }
+
+    // Time to wait before capturing the next frame
+    pub fn remaining(&self) -> Duration {
+        self.remaining_at(Instant::now())
+    }
+
+    pub fn is_due(&self) -> bool {
+        self.is_due_at(Instant::now())
+    }
+
+    // Call when a frame has been captured
+    pub fn frame_captured(&mut self) {
+        self.frame_captured_at(Instant::now());
+    }
+
+    // Same as above at the time `now`, for sources with their own clock (see `file::FileClock`)
+    pub fn remaining_at(&self, now: Instant) -> Duration {
+        match self.last_frame {
+            Some(last_frame) => self
+                .interval
+                .saturating_sub(now.saturating_duration_since(last_frame)),
+            None => Duration::ZERO,
+        }
+    }
+
+    pub fn is_due_at(&self, now: Instant) -> bool {
+        self.remaining_at(now).is_zero()
+    }
+
+    pub fn frame_captured_at(&mut self, now: Instant) {
+        self.last_frame = Some(now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unlimited() {
+        for rate in [None, Some(0.0), Some(-1.0)] {
+            let mut limiter = FrameLimiter::new(rate);
+            limiter.frame_captured();
+            assert!(limiter.is_due());
+            assert_eq!(limiter.remaining(), Duration::ZERO);
+        }
+    }
+
+    #[test]
+    fn waits_for_interval() {
+        let mut limiter = FrameLimiter::new(Some(10.0));
+        // The first frame is not delayed
+        assert!(limiter.is_due());
+
+        limiter.frame_captured();
+        assert!(!limiter.is_due());
+        let remaining = limiter.remaining();
+        assert!(remaining > Duration::from_millis(50) && remaining <= Duration::from_millis(100));
+
+        limiter.last_frame = Some(Instant::now() - Duration::from_millis(100));
+        assert!(limiter.is_due());
+        assert_eq!(limiter.remaining(), Duration::ZERO);
+    }
+
+    #[test]
+    fn own_clock() {
+        // 1/8 s is exact in binary
+        let start = Instant::now();
+        let mut limiter = FrameLimiter::new(Some(8.0));
+        limiter.frame_captured_at(start);
+        assert!(!limiter.is_due_at(start + Duration::from_millis(124)));
+        assert_eq!(
+            limiter.remaining_at(start + Duration::from_millis(25)),
+            Duration::from_millis(100)
+        );
+        assert!(limiter.is_due_at(start + Duration::from_millis(125)));
+    }
+}
@@ -0,0 +1,96 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Frames captured into CPU memory by a capture thread, and uploaded to a texture on the render thread
+
+use std::{
+    borrow::Cow,
+    rc::Rc,
+    sync::{mpsc::Receiver, Arc},
+};
+
+use glium::{
+    backend::{Context, Facade},
+    texture::{ClientFormat, RawImage2d},
+    Rect, Texture2d,
+};
+
+use super::{CaptureError, CapturedFrame, DamageRect, FrameTiming};
+
+// XRGB8888 (or ARGB8888) pixels without row padding
+pub struct MemoryFrame {
+    pub data: Vec<u8>,
+    pub width: u32,
+    pub height: u32,
+    pub timing: FrameTiming,
+}
+
+pub struct MemoryTexture {
+    context: Rc<Context>,
+    texture: Option<Arc<Texture2d>>,
+    timing: Option<FrameTiming>,
+}
+
+impl MemoryTexture {
+    pub fn new(facade: &impl Facade) -> Self {
+        Self {
+            context: Rc::clone(facade.get_context()),
+            texture: None,
+            timing: None,
+        }
+    }
+
+    // Uploads the most recent frame in the receiver, if any.
+    // After the capture thread has finished, the last frame is kept.
+    pub fn capture(
+        &mut self,
+        frame_receiver: &Receiver<MemoryFrame>,
+    ) -> Result<CapturedFrame, CaptureError> {
+        let mut latest = None;
+        while let Ok(frame) = frame_receiver.try_recv() {
+            latest = Some(frame);
+        }
+
+        let mut damage = Vec::new();
+        if let Some(frame) = latest {
+            self.timing = Some(frame.timing);
+            let image = RawImage2d {
+                data: Cow::Owned(frame.data),
+                width: frame.width,
+                height: frame.height,
+                format: ClientFormat::U8U8U8U8,
+            };
+
+            match self.texture {
+                Some(ref texture) if texture.dimensions() == (frame.width, frame.height) => {
+                    texture.write(
+                        Rect {
+                            left: 0,
+                            bottom: 0,
+                            width: frame.width,
+                            height: frame.height,
+                        },
+                        image,
+                    );
+                }
+                _ => {
+                    log::info!("Captured frame size: {}x{}", frame.width, frame.height);
+                    // Shared only with the renderer on this thread
+                    #[allow(clippy::arc_with_non_send_sync)]
+                    let texture = Arc::new(Texture2d::new(&self.context, image).unwrap());
+                    self.texture = Some(texture);
+                }
+            }
+
+            damage.push(DamageRect::full(frame.width, frame.height));
+        }
+
+        let texture = self.texture.as_ref().ok_or(CaptureError::NotReady)?;
+        Ok(CapturedFrame {
+            texture: Arc::clone(texture),
+            damage,
+            timing: self.timing,
+        })
+    }
+}
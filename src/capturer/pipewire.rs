@@ -0,0 +1,442 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Capture of a PipeWire video stream, usually obtained through the ScreenCast portal of xdg-desktop-portal.
+// Used on compositors without wlroots protocols (e.g. GNOME and KDE).
+
+use std::{
+    fmt,
+    os::fd::OwnedFd,
+    sync::mpsc,
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use ::pipewire as pw;
+use ashpd::desktop::{
+    screencast::{CursorMode, Screencast, SourceType},
+    PersistMode, ResponseError, Session,
+};
+use glium::backend::Facade;
+use pw::spa::{
+    param::{
+        format::{FormatProperties, MediaSubtype, MediaType},
+        format_utils,
+        video::{VideoFormat, VideoInfoRaw},
+        ParamType,
+    },
+    pod::{serialize::PodSerializer, Pod, Value},
+    utils::{Direction, Rectangle, SpaTypes},
+};
+
+use super::{
+    limiter::FrameLimiter,
+    memory::{MemoryFrame, MemoryTexture},
+    monotonic_now, CaptureError, CapturedFrame, Capturer, FrameTiming,
+};
+
+pub struct PipeWireCapturer {
+    texture: MemoryTexture,
+    frame_receiver: mpsc::Receiver<MemoryFrame>,
+    error_receiver: mpsc::Receiver<CaptureError>, // Why the capture thread has stopped
+    error: Option<CaptureError>,
+    quit_sender: pw::channel::Sender<()>,
+    join_handle: Option<JoinHandle<()>>,
+}
+
+// User data of the stream listener
+struct StreamData {
+    format: VideoInfoRaw,
+    frame_sender: mpsc::Sender<MemoryFrame>,
+    error_sender: mpsc::Sender<CaptureError>,
+    main_loop: pw::main_loop::MainLoopRc,
+    limiter: FrameLimiter,
+}
+
+impl PipeWireCapturer {
+    // If `node` is None, the user selects a screen through the ScreenCast portal.
+    // Otherwise the node is captured directly from the PipeWire daemon of the session (for testing).
+    pub fn new(
+        facade: &impl Facade,
+        node: Option<u32>,
+        embed_cursor: bool,
+        limiter: FrameLimiter,
+    ) -> Self {
+        let (frame_sender, frame_receiver) = mpsc::channel();
+        let (error_sender, error_receiver) = mpsc::channel();
+        let (quit_sender, quit_receiver) = pw::channel::channel();
+
+        let join_handle = thread::spawn(move || {
+            let result = run(
+                node,
+                embed_cursor,
+                frame_sender,
+                error_sender.clone(),
+                limiter,
+                quit_receiver,
+            );
+            if let Err(err) = result {
+                log::error!("PipeWire capture stopped: {}", err);
+                // Fails only if the capturer is being dropped
+                let _ = error_sender.send(err);
+            }
+        });
+
+        Self {
+            texture: MemoryTexture::new(facade),
+            frame_receiver,
+            error_receiver,
+            error: None,
+            quit_sender,
+            join_handle: Some(join_handle),
+        }
+    }
+}
+
+impl Capturer for PipeWireCapturer {
+    fn capture(&mut self) -> Result<CapturedFrame, CaptureError> {
+        if let Ok(error) = self.error_receiver.try_recv() {
+            self.error = Some(error);
+        }
+        if let Some(ref error) = self.error {
+            return Err(error.clone());
+        }
+        self.texture.capture(&self.frame_receiver)
+    }
+}
+
+impl Drop for PipeWireCapturer {
+    fn drop(&mut self) {
+        // Fails only if the thread has already finished
+        let _ = self.quit_sender.send(());
+        if let Some(join_handle) = self.join_handle.take() {
+            if join_handle.join().is_err() {
+                log::error!("PipeWire capture thread has panicked");
+            }
+        }
+    }
+}
+
+// Capture thread, until quit is requested or the stream ends
+fn run(
+    node: Option<u32>,
+    embed_cursor: bool,
+    frame_sender: mpsc::Sender<MemoryFrame>,
+    error_sender: mpsc::Sender<CaptureError>,
+    limiter: FrameLimiter,
+    quit_receiver: pw::channel::Receiver<()>,
+) -> Result<(), CaptureError> {
+    // Tokio runtime is needed only for D-Bus communication with the portal
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(|err| CaptureError::Disconnected(format!("cannot start Tokio: {}", err)))?;
+
+    let (session, remote, node) = match node {
+        Some(node) => (None, None, node),
+        None => {
+            let (session, remote, node) = runtime.block_on(start_portal_session(embed_cursor))?;
+            (Some(session), Some(remote), node)
+        }
+    };
+
+    let result = run_stream(
+        remote,
+        node,
+        frame_sender,
+        error_sender,
+        limiter,
+        quit_receiver,
+    );
+
+    if let Some(session) = session {
+        if let Err(err) = runtime.block_on(session.close()) {
+            log::warn!("Failed to close the ScreenCast session: {}", err);
+        }
+    }
+    result
+}
+
+// Asks the user to select a screen. Returns the session, a connection to PipeWire, and the node ID of the stream.
+async fn start_portal_session(
+    embed_cursor: bool,
+) -> Result<(Session<'static, Screencast<'static>>, OwnedFd, u32), CaptureError> {
+    let proxy = Screencast::new()
+        .await
+        .map_err(|err| CaptureError::Unsupported(format!("ScreenCast portal ({})", err)))?;
+    let session = proxy.create_session().await.map_err(portal_error)?;
+
+    match select_stream(&proxy, &session, embed_cursor).await {
+        Ok((remote, node)) => Ok((session, remote, node)),
+        Err(err) => {
+            let _ = session.close().await;
+            Err(err)
+        }
+    }
+}
+
+// Returns a connection to PipeWire and the node ID of the selected screen
+async fn select_stream(
+    proxy: &Screencast<'static>,
+    session: &Session<'static, Screencast<'static>>,
+    embed_cursor: bool,
+) -> Result<(OwnedFd, u32), CaptureError> {
+    let cursor_mode = if embed_cursor {
+        CursorMode::Embedded
+    } else {
+        CursorMode::Hidden
+    };
+
+    proxy
+        .select_sources(
+            session,
+            cursor_mode,
+            SourceType::Monitor | SourceType::Virtual,
+            false,
+            None,
+            PersistMode::DoNot,
+        )
+        .await
+        .map_err(portal_error)?;
+
+    let response = proxy
+        .start(session, None)
+        .await
+        .map_err(portal_error)?
+        .response()
+        .map_err(portal_error)?;
+    let node = response
+        .streams()
+        .first()
+        .ok_or_else(|| CaptureError::Disconnected("no screen was selected".to_owned()))?
+        .pipe_wire_node_id();
+    log::info!("Capturing PipeWire node {} from the portal", node);
+
+    let remote = proxy
+        .open_pipe_wire_remote(session)
+        .await
+        .map_err(portal_error)?;
+    Ok((remote, node))
+}
+
+// Cancelling the selection ends the capture, same as a lost connection
+fn portal_error(err: ashpd::Error) -> CaptureError {
+    match err {
+        ashpd::Error::Response(ResponseError::Cancelled) => {
+            CaptureError::Disconnected("screen cast was cancelled".to_owned())
+        }
+        err => CaptureError::Disconnected(format!("ScreenCast portal: {}", err)),
+    }
+}
+
+fn pipewire_error(what: &str, err: impl fmt::Display) -> CaptureError {
+    CaptureError::Disconnected(format!("{}: {}", what, err))
+}
+
+impl StreamData {
+    // Stop the capture thread with an error
+    fn fail(&self, err: CaptureError) {
+        log::error!("PipeWire capture stopped: {}", err);
+        // Fails only if the capturer is being dropped
+        let _ = self.error_sender.send(err);
+        self.main_loop.quit();
+    }
+}
+
+// Receives frames until quit is requested or the stream ends
+fn run_stream(
+    remote: Option<OwnedFd>,
+    node: u32,
+    frame_sender: mpsc::Sender<MemoryFrame>,
+    error_sender: mpsc::Sender<CaptureError>,
+    limiter: FrameLimiter,
+    quit_receiver: pw::channel::Receiver<()>,
+) -> Result<(), CaptureError> {
+    pw::init();
+
+    let main_loop = pw::main_loop::MainLoopRc::new(None)
+        .map_err(|err| pipewire_error("cannot create PipeWire main loop", err))?;
+    let context = pw::context::ContextRc::new(&main_loop, None)
+        .map_err(|err| pipewire_error("cannot create PipeWire context", err))?;
+    let core = match remote {
+        // PipeWire remote given by the portal can access only the shared screen
+        Some(fd) => context.connect_fd_rc(fd, None),
+        None => context.connect_rc(None),
+    }
+    .map_err(|err| pipewire_error("cannot connect to PipeWire", err))?;
+
+    let _quit = quit_receiver.attach(main_loop.loop_(), {
+        let main_loop = main_loop.clone();
+        move |_| main_loop.quit()
+    });
+
+    let stream = pw::stream::StreamBox::new(
+        &core,
+        "okulekrano",
+        pw::properties::properties! {
+            *pw::keys::MEDIA_TYPE => "Video",
+            *pw::keys::MEDIA_CATEGORY => "Capture",
+            *pw::keys::MEDIA_ROLE => "Screen",
+        },
+    )
+    .map_err(|err| pipewire_error("cannot create PipeWire stream", err))?;
+
+    let _listener = stream
+        .add_local_listener_with_user_data(StreamData {
+            format: Default::default(),
+            frame_sender,
+            error_sender,
+            main_loop: main_loop.clone(),
+            limiter,
+        })
+        .state_changed(|_, data, old, new| {
+            log::debug!("PipeWire stream state: {:?} -> {:?}", old, new);
+            match new {
+                pw::stream::StreamState::Error(reason) => {
+                    data.fail(pipewire_error("PipeWire stream failed", reason))
+                }
+                // E.g. the shared screen has been closed
+                pw::stream::StreamState::Unconnected => data.fail(CaptureError::Disconnected(
+                    "PipeWire stream has ended".to_owned(),
+                )),
+                _ => (),
+            }
+        })
+        .param_changed(|_, data, id, param| {
+            let Some(param) = param else {
+                return;
+            };
+            if id != ParamType::Format.as_raw() {
+                return;
+            }
+            let Ok((MediaType::Video, MediaSubtype::Raw)) = format_utils::parse_format(param)
+            else {
+                return;
+            };
+
+            if let Err(err) = data.format.parse(param) {
+                data.fail(CaptureError::Unsupported(format!(
+                    "PipeWire stream format ({})",
+                    err
+                )));
+                return;
+            }
+            log::debug!("PipeWire stream format: {:?}", data.format.format());
+        })
+        .process(|stream, data| {
+            let Some(mut buffer) = stream.dequeue_buffer() else {
+                return;
+            };
+            // Frames exceeding the frame rate limit are dropped (the buffer is queued back on drop)
+            if !data.limiter.is_due() {
+                return;
+            }
+            // PipeWire buffers may not have timestamps, so dequeue time is used instead
+            let presented = monotonic_now();
+            if let Some(frame) = copy_frame(&mut buffer, data.format.size(), presented) {
+                data.limiter.frame_captured();
+                // Fails only if the capturer is being dropped
+                let _ = data.frame_sender.send(frame);
+            }
+        })
+        .register()
+        .map_err(|err| pipewire_error("cannot listen to PipeWire stream", err))?;
+
+    // Only memory-mapped buffers (memfd or shm) are used, so DMA-BUF modifiers are not offered
+    let format = pw::spa::pod::object!(
+        SpaTypes::ObjectParamFormat,
+        ParamType::EnumFormat,
+        pw::spa::pod::property!(FormatProperties::MediaType, Id, MediaType::Video),
+        pw::spa::pod::property!(FormatProperties::MediaSubtype, Id, MediaSubtype::Raw),
+        pw::spa::pod::property!(
+            FormatProperties::VideoFormat,
+            Choice,
+            Enum,
+            Id,
+            VideoFormat::BGRx,
+            VideoFormat::BGRx,
+            VideoFormat::BGRA
+        ),
+        pw::spa::pod::property!(
+            FormatProperties::VideoSize,
+            Choice,
+            Range,
+            Rectangle,
+            Rectangle {
+                width: 1920,
+                height: 1080
+            },
+            Rectangle {
+                width: 1,
+                height: 1
+            },
+            Rectangle {
+                width: 8192,
+                height: 8192
+            }
+        ),
+    );
+    let format = PodSerializer::serialize(std::io::Cursor::new(Vec::new()), &Value::Object(format))
+        .map_err(|err| CaptureError::Unsupported(format!("PipeWire format ({})", err)))?
+        .0
+        .into_inner();
+    let format = Pod::from_bytes(&format)
+        .ok_or_else(|| CaptureError::Unsupported("PipeWire format".to_owned()))?;
+
+    stream
+        .connect(
+            Direction::Input,
+            Some(node),
+            pw::stream::StreamFlags::AUTOCONNECT | pw::stream::StreamFlags::MAP_BUFFERS,
+            &mut [format],
+        )
+        .map_err(|err| pipewire_error(&format!("cannot connect to PipeWire node {}", node), err))?;
+
+    main_loop.run();
+    Ok(())
+}
+
+// Copy pixels out of a buffer, removing row padding. Returns None if the buffer has no image.
+fn copy_frame(
+    buffer: &mut pw::buffer::Buffer,
+    size: Rectangle,
+    presented: Duration,
+) -> Option<MemoryFrame> {
+    let plane = buffer.datas_mut().first_mut()?;
+    let chunk = plane.chunk();
+    if chunk.size() == 0 {
+        // Buffers without image are sent e.g. when only the cursor has moved
+        return None;
+    }
+
+    let offset = chunk.offset() as usize;
+    let row_len = 4 * size.width as usize;
+    let stride = match chunk.stride() {
+        stride if stride > 0 => stride as usize,
+        _ => row_len,
+    };
+
+    // Not mapped (e.g. DMA-BUF), or smaller than expected
+    let bytes = plane.data()?;
+    if size.height == 0 || bytes.len() < offset + stride * (size.height as usize - 1) + row_len {
+        return None;
+    }
+
+    let data = bytes[offset..]
+        .chunks(stride)
+        .take(size.height as usize)
+        .flat_map(|row| &row[..row_len])
+        .copied()
+        .collect();
+
+    Some(MemoryFrame {
+        data,
+        width: size.width,
+        height: size.height,
+        timing: FrameTiming {
+            presented,
+            ready: monotonic_now(),
+        },
+    })
+}
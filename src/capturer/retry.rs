@@ -0,0 +1,179 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Recreation of capturers after errors they cannot recover from by themselves,
+// e.g. a restarted compositor
+
+use std::time::{Duration, Instant};
+
+use super::{CaptureError, CapturedFrame, Capturer};
+
+const RETRY_INTERVAL: Duration = Duration::from_secs(2);
+
+pub struct Retrying<T> {
+    create: Box<dyn FnMut() -> Result<T, CaptureError>>,
+    inner: Option<T>,
+    error: Option<CaptureError>, // Why `inner` is None
+    retry_at: Instant,
+}
+
+impl<T> Retrying<T> {
+    pub fn new(create: impl FnMut() -> Result<T, CaptureError> + 'static) -> Self {
+        let mut retrying = Self {
+            create: Box::new(create),
+            inner: None,
+            error: None,
+            retry_at: Instant::now(),
+        };
+        retrying.recreate();
+        retrying
+    }
+
+    // Call `f` with the current instance. The instance is dropped if `f` returns a non-transient error,
+    // and created again after a while.
+    pub fn with<U>(
+        &mut self,
+        f: impl FnOnce(&mut T) -> Result<U, CaptureError>,
+    ) -> Result<U, CaptureError> {
+        if self.inner.is_none() {
+            // Unsupported protocols or formats do not appear by retrying
+            let retryable = !matches!(self.error, Some(CaptureError::Unsupported(_)));
+            if retryable && Instant::now() >= self.retry_at {
+                self.recreate();
+            }
+        }
+
+        let result = match self.inner {
+            Some(ref mut inner) => f(inner),
+            None => return Err(self.error.clone().unwrap()),
+        };
+        if let Err(ref err) = result {
+            if !err.is_transient() {
+                self.fail(err.clone());
+            }
+        }
+        result
+    }
+
+    fn recreate(&mut self) {
+        match (self.create)() {
+            Ok(inner) => {
+                if self.error.is_some() {
+                    log::info!("Capture restarted");
+                }
+                self.inner = Some(inner);
+                self.error = None;
+            }
+            Err(err) => self.fail(err),
+        }
+    }
+
+    fn fail(&mut self, err: CaptureError) {
+        // Log only changes, not to repeat the same message every retry
+        if self.error.as_ref() != Some(&err) {
+            if let CaptureError::Unsupported(_) = err {
+                log::error!("{}", err);
+            } else {
+                log::warn!("{}. Retrying every {:?}", err, RETRY_INTERVAL);
+            }
+        }
+
+        self.inner = None;
+        self.error = Some(err);
+        self.retry_at = Instant::now() + RETRY_INTERVAL;
+    }
+}
+
+impl<T: Capturer> Capturer for Retrying<T> {
+    fn capture(&mut self) -> Result<CapturedFrame, CaptureError> {
+        self.with(T::capture)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, rc::Rc};
+
+    use super::*;
+
+    // Instances are numbered from 1 in the order of creation. Creation fails while `fail` returns an error.
+    fn numbered(
+        fail: impl Fn(u32) -> Option<CaptureError> + 'static,
+    ) -> (Retrying<u32>, Rc<Cell<u32>>) {
+        let attempts = Rc::new(Cell::new(0));
+        let retrying = Retrying::new({
+            let attempts = Rc::clone(&attempts);
+            move || {
+                attempts.set(attempts.get() + 1);
+                match fail(attempts.get()) {
+                    Some(err) => Err(err),
+                    None => Ok(attempts.get()),
+                }
+            }
+        });
+        (retrying, attempts)
+    }
+
+    fn number(retrying: &mut Retrying<u32>) -> Result<u32, CaptureError> {
+        retrying.with(|n| Ok(*n))
+    }
+
+    fn disconnected() -> CaptureError {
+        CaptureError::Disconnected("test".to_owned())
+    }
+
+    #[test]
+    fn transient_error_keeps_instance() {
+        let (mut retrying, attempts) = numbered(|_| None);
+        let failed = CaptureError::Failed("test".to_owned());
+        assert_eq!(retrying.with(|_| Err::<(), _>(failed.clone())), Err(failed));
+        assert_eq!(number(&mut retrying), Ok(1));
+        assert_eq!(attempts.get(), 1);
+    }
+
+    #[test]
+    fn recreated_after_interval() {
+        let (mut retrying, attempts) = numbered(|_| None);
+        assert_eq!(number(&mut retrying), Ok(1));
+
+        assert_eq!(
+            retrying.with(|_| Err::<(), _>(disconnected())),
+            Err(disconnected())
+        );
+        // The error is returned until the interval passes
+        assert_eq!(number(&mut retrying), Err(disconnected()));
+        assert_eq!(attempts.get(), 1);
+        assert!(retrying.retry_at > Instant::now() + RETRY_INTERVAL / 2);
+
+        retrying.retry_at = Instant::now();
+        assert_eq!(number(&mut retrying), Ok(2));
+    }
+
+    #[test]
+    fn failed_creation_is_retried() {
+        let (mut retrying, attempts) =
+            numbered(|attempt| (attempt < 3).then(|| CaptureError::OutputNotFound("A".to_owned())));
+        let not_found = Err(CaptureError::OutputNotFound("A".to_owned()));
+        assert_eq!(number(&mut retrying), not_found);
+
+        retrying.retry_at = Instant::now();
+        assert_eq!(number(&mut retrying), not_found);
+        retrying.retry_at = Instant::now();
+        assert_eq!(number(&mut retrying), Ok(3));
+        assert_eq!(attempts.get(), 3);
+    }
+
+    #[test]
+    fn unsupported_is_not_retried() {
+        let unsupported = CaptureError::Unsupported("test".to_owned());
+        let (mut retrying, attempts) = numbered({
+            let unsupported = unsupported.clone();
+            move |_| Some(unsupported.clone())
+        });
+
+        retrying.retry_at = Instant::now();
+        assert_eq!(number(&mut retrying), Err(unsupported));
+        assert_eq!(attempts.get(), 1);
+    }
+}
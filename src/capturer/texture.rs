@@ -3,39 +3,88 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use std::{
+    ffi::CStr,
     os::{
-        fd::{AsFd, BorrowedFd, FromRawFd, OwnedFd},
-        raw::c_void,
+        fd::{AsRawFd, FromRawFd, OwnedFd},
+        raw::{c_char, c_void},
     },
+    path::PathBuf,
     sync::Arc,
 };
 
 use drm_fourcc::{DrmFourcc, DrmModifier};
-use glium::{GlObject, Texture2d};
-use khronos_egl::{ClientBuffer, EGLDisplay, EGLImage, ATTRIB_NONE, GL_TEXTURE_2D};
+use glium::{
+    backend::Facade,
+    glutin::surface::WindowSurface,
+    texture::{Dimensions, MipmapsOption, UncompressedFloatFormat},
+    Display, GlObject, Texture2d,
+};
+use khronos_egl::{
+    Attrib, Boolean, ClientBuffer, Context, EGLDisplay, EGLImage, Int, ATTRIB_NONE, FALSE,
+    GL_TEXTURE_2D, HEIGHT, NO_CONTEXT, WIDTH,
+};
 
 type EglExportDmabufImageQueryMesaFunc =
     unsafe extern "C" fn(EGLDisplay, EGLImage, *mut i32, *mut i32, *mut u64) -> bool;
 type EglExportDmabufImageMesaFunc =
     unsafe extern "C" fn(EGLDisplay, EGLImage, *mut i32, *mut i32, *mut i32) -> bool;
+type EglQueryDmabufFormatsExtFunc =
+    unsafe extern "C" fn(EGLDisplay, Int, *mut Int, *mut Int) -> Boolean;
+type EglQueryDmabufModifiersExtFunc =
+    unsafe extern "C" fn(EGLDisplay, Int, Int, *mut u64, *mut Boolean, *mut Int) -> Boolean;
+type EglQueryDisplayAttribExtFunc = unsafe extern "C" fn(EGLDisplay, Int, *mut Attrib) -> Boolean;
+type EglQueryDeviceStringExtFunc = unsafe extern "C" fn(*mut c_void, Int) -> *const c_char;
+type GlGetIntegervFunc = unsafe extern "C" fn(u32, *mut i32);
+type GlGenTexturesFunc = unsafe extern "C" fn(i32, *mut u32);
+type GlDeleteTexturesFunc = unsafe extern "C" fn(i32, *const u32);
+type GlBindTextureFunc = unsafe extern "C" fn(u32, u32);
+type GlGetErrorFunc = unsafe extern "C" fn() -> u32;
+type GlEglImageTargetTexture2dOesFunc = unsafe extern "C" fn(u32, *mut c_void);
+
+// EGL_EXT_image_dma_buf_import(_modifiers)
+const EGL_LINUX_DMA_BUF_EXT: u32 = 0x3270;
+const EGL_LINUX_DRM_FOURCC_EXT: Attrib = 0x3271;
+// FD, offset, pitch, lower and upper half of modifier of each plane
+const EGL_DMA_BUF_PLANE_ATTRIBS: [[Attrib; 5]; MAX_PLANES] = [
+    [0x3272, 0x3273, 0x3274, 0x3443, 0x3444],
+    [0x3275, 0x3276, 0x3277, 0x3445, 0x3446],
+    [0x3278, 0x3279, 0x327A, 0x3447, 0x3448],
+    [0x3440, 0x3441, 0x3442, 0x3449, 0x344A],
+];
+// EGL_EXT_device_query and EGL_EXT_device_drm(_render_node)
+const EGL_DEVICE_EXT: Int = 0x322C;
+const EGL_DRM_DEVICE_FILE_EXT: Int = 0x3233;
+const EGL_DRM_RENDER_NODE_FILE_EXT: Int = 0x3377;
+
+const GL_TEXTURE_BINDING_2D: u32 = 0x8069;
+const GL_NO_ERROR: u32 = 0;
+
+// Planes of a DMA-BUF. More than one are used e.g. for compression metadata of some modifiers.
+const MAX_PLANES: usize = 4;
+
+pub struct DmabufPlane {
+    pub fd: OwnedFd,
+    pub offset: u32,
+    pub stride: u32,
+}
 
-// DMABUF-capable texture
+// DMABUF-capable texture. The memory is shared by the GL texture and the fds of the planes.
 pub struct DmabufTexture {
     texture: Arc<Texture2d>,
-    fd: OwnedFd,
     fourcc: DrmFourcc,
-    num_planes: u32,
     modifier: DrmModifier,
-    offset: u32,
-    stride: u32,
+    planes: Vec<DmabufPlane>,
 }
 
 impl DmabufTexture {
-    pub fn new(texture: Texture2d) -> Self {
+    // Export a texture allocated by GL (only on Mesa)
+    pub fn export(texture: Texture2d) -> Result<Self, String> {
         let egl = khronos_egl::Instance::new(khronos_egl::Static);
 
-        let egl_ctx = egl.get_current_context().unwrap();
-        let display = egl.get_current_display().unwrap();
+        let (Some(egl_ctx), Some(display)) = (egl.get_current_context(), egl.get_current_display())
+        else {
+            return Err("no current EGL context".to_owned());
+        };
 
         let egl_image = unsafe {
             egl.create_image(
@@ -45,55 +94,105 @@ impl DmabufTexture {
                 ClientBuffer::from_ptr(texture.get_id() as *mut c_void),
                 &[ATTRIB_NONE],
             )
-            .unwrap()
-        };
+        }
+        .map_err(|err| format!("cannot create EGL image of texture ({})", err))?;
+        let exported = unsafe { export_image(&egl, display.as_ptr(), egl_image.as_ptr()) };
 
-        let mut fourcc: i32 = 0;
-        let mut num_planes: i32 = 0;
-        let mut modifier: u64 = 0;
-        let mut offset: i32 = 0;
-        let mut stride: i32 = 0;
+        // The texture and the fds keep the memory
+        let _ = egl.destroy_image(display, egl_image);
 
-        let fd = unsafe {
-            let export_dmabuf_image_query: EglExportDmabufImageQueryMesaFunc = std::mem::transmute(
-                egl.get_proc_address("eglExportDMABUFImageQueryMESA")
-                    .unwrap(),
-            );
-            let export_dmabuf_image: EglExportDmabufImageMesaFunc =
-                std::mem::transmute(egl.get_proc_address("eglExportDMABUFImageMESA").unwrap());
-
-            if !export_dmabuf_image_query(
-                display.as_ptr(),
-                egl_image.as_ptr(),
-                &mut fourcc,
-                &mut num_planes,
-                &mut modifier
-            ) {
-                panic!("DMABUF image query failed")
-            }
+        let (fourcc, modifier, planes) = exported?;
+        let fourcc = DrmFourcc::try_from(fourcc)
+            .map_err(|_| format!("DMA-BUF of unknown format {:#x} exported", fourcc))?;
+        Ok(Self::new(texture, fourcc, modifier.into(), planes))
+    }
+
+    // Import a DMA-BUF allocated elsewhere (e.g. by GBM) as a texture
+    pub fn import(
+        display: &Display<WindowSurface>,
+        width: u32,
+        height: u32,
+        fourcc: DrmFourcc,
+        modifier: DrmModifier,
+        planes: Vec<DmabufPlane>,
+    ) -> Result<Self, String> {
+        let egl = khronos_egl::Instance::new(khronos_egl::Static);
 
-            let mut fd = 0;
-            if !export_dmabuf_image(
-                display.as_ptr(),
-                egl_image.as_ptr(),
-                &mut fd,
-                &mut stride,
-                &mut offset,
-            ) {
-                panic!("DMABUF export failed")
+        let mut attribs = vec![
+            WIDTH as Attrib,
+            width as Attrib,
+            HEIGHT as Attrib,
+            height as Attrib,
+            EGL_LINUX_DRM_FOURCC_EXT,
+            fourcc as u32 as Attrib,
+        ];
+        for (plane, names) in planes.iter().zip(EGL_DMA_BUF_PLANE_ATTRIBS) {
+            attribs.extend([
+                names[0],
+                plane.fd.as_raw_fd() as Attrib,
+                names[1],
+                plane.offset as Attrib,
+                names[2],
+                plane.stride as Attrib,
+            ]);
+            // Layout is chosen by the driver without modifiers
+            if modifier != DrmModifier::Invalid {
+                let modifier: u64 = modifier.into();
+                attribs.extend([
+                    names[3],
+                    (modifier & 0xFFFFFFFF) as Attrib,
+                    names[4],
+                    (modifier >> 32) as Attrib,
+                ]);
             }
+        }
+        attribs.push(ATTRIB_NONE);
 
-            OwnedFd::from_raw_fd(fd)
+        let id = unsafe {
+            display.get_context().exec_in_context(|| {
+                let egl_display = egl
+                    .get_current_display()
+                    .ok_or_else(|| "no current EGL display".to_owned())?;
+                let egl_image = egl
+                    .create_image(
+                        egl_display,
+                        Context::from_ptr(NO_CONTEXT),
+                        EGL_LINUX_DMA_BUF_EXT,
+                        ClientBuffer::from_ptr(std::ptr::null_mut()),
+                        &attribs,
+                    )
+                    .map_err(|err| format!("DMA-BUF import failed ({})", err))?;
+                let id = texture_from_image(&egl, egl_image.as_ptr());
+                // The texture keeps the memory after the image is destroyed
+                let _ = egl.destroy_image(egl_display, egl_image);
+                id
+            })?
         };
+        let texture = unsafe {
+            Texture2d::from_id(
+                display,
+                UncompressedFloatFormat::U8U8U8U8,
+                id,
+                true,
+                MipmapsOption::NoMipmap,
+                Dimensions::Texture2d { width, height },
+            )
+        };
+
+        Ok(Self::new(texture, fourcc, modifier, planes))
+    }
 
+    fn new(
+        texture: Texture2d,
+        fourcc: DrmFourcc,
+        modifier: DrmModifier,
+        planes: Vec<DmabufPlane>,
+    ) -> Self {
         Self {
             texture: Arc::new(texture),
-            fd,
-            fourcc: (fourcc as u32).try_into().unwrap(),
-            num_planes: num_planes as u32,
-            modifier: modifier.into(),
-            offset: offset as u32,
-            stride: stride as u32,
+            fourcc,
+            modifier,
+            planes,
         }
     }
 
@@ -101,27 +200,221 @@ impl DmabufTexture {
         Arc::clone(&self.texture)
     }
 
-    pub fn fd(&self) -> BorrowedFd<'_> {
-        self.fd.as_fd()
-    }
-
     pub fn fourcc(&self) -> DrmFourcc {
         self.fourcc
     }
 
-    pub fn num_planes(&self) -> u32 {
-        self.num_planes
-    }
-
     pub fn modifier(&self) -> DrmModifier {
         self.modifier
     }
 
-    pub fn offset(&self) -> u32 {
-        self.offset
+    pub fn planes(&self) -> &[DmabufPlane] {
+        &self.planes
     }
+}
+
+// Planes of an EGL image as DMA-BUFs, with the fourcc and the modifier (EGL_MESA_image_dma_buf_export)
+unsafe fn export_image(
+    egl: &khronos_egl::Instance<khronos_egl::Static>,
+    display: EGLDisplay,
+    egl_image: EGLImage,
+) -> Result<(u32, u64, Vec<DmabufPlane>), String> {
+    let proc = |name: &str| {
+        egl.get_proc_address(name)
+            .ok_or_else(|| format!("{} is not available", name))
+    };
+    let export_dmabuf_image_query: EglExportDmabufImageQueryMesaFunc =
+        std::mem::transmute(proc("eglExportDMABUFImageQueryMESA")?);
+    let export_dmabuf_image: EglExportDmabufImageMesaFunc =
+        std::mem::transmute(proc("eglExportDMABUFImageMESA")?);
+
+    let mut fourcc: i32 = 0;
+    let mut num_planes: i32 = 0;
+    let mut modifier: u64 = 0;
+    let mut fds = [-1; MAX_PLANES];
+    let mut offsets = [0; MAX_PLANES];
+    let mut strides = [0; MAX_PLANES];
 
-    pub fn stride(&self) -> u32 {
-        self.stride
+    if !export_dmabuf_image_query(
+        display,
+        egl_image,
+        &mut fourcc,
+        &mut num_planes,
+        &mut modifier,
+    ) {
+        return Err("DMA-BUF image query failed".to_owned());
+    }
+    let num_planes = num_planes as usize;
+    if num_planes == 0 || num_planes > MAX_PLANES {
+        return Err(format!("DMA-BUF with {} planes", num_planes));
+    }
+
+    // One entry is written for each plane
+    if !export_dmabuf_image(
+        display,
+        egl_image,
+        fds.as_mut_ptr(),
+        strides.as_mut_ptr(),
+        offsets.as_mut_ptr(),
+    ) {
+        return Err("DMA-BUF export failed".to_owned());
+    }
+
+    // Planes in the same buffer as the first one may have no fd of their own
+    let mut fds: Vec<_> = fds[..num_planes]
+        .iter()
+        .map(|&fd| (fd >= 0).then(|| OwnedFd::from_raw_fd(fd)))
+        .collect();
+    let first = fds[0]
+        .take()
+        .ok_or_else(|| "DMA-BUF exported without fd".to_owned())?;
+    let planes = (0..num_planes)
+        .map(|i| {
+            let fd = match fds[i].take() {
+                Some(fd) => fd,
+                None => first
+                    .try_clone()
+                    .map_err(|err| format!("cannot duplicate DMA-BUF fd ({})", err))?,
+            };
+            Ok(DmabufPlane {
+                fd,
+                offset: offsets[i] as u32,
+                stride: strides[i] as u32,
+            })
+        })
+        .collect::<Result<_, String>>()?;
+    Ok((fourcc as u32, modifier, planes))
+}
+
+// Create a GL texture backed by an EGL image. The texture binding glium knows is kept.
+unsafe fn texture_from_image(
+    egl: &khronos_egl::Instance<khronos_egl::Static>,
+    egl_image: EGLImage,
+) -> Result<u32, String> {
+    let proc = |name: &str| {
+        egl.get_proc_address(name)
+            .ok_or_else(|| format!("{} is not available", name))
+    };
+    let get_integerv: GlGetIntegervFunc = std::mem::transmute(proc("glGetIntegerv")?);
+    let gen_textures: GlGenTexturesFunc = std::mem::transmute(proc("glGenTextures")?);
+    let delete_textures: GlDeleteTexturesFunc = std::mem::transmute(proc("glDeleteTextures")?);
+    let bind_texture: GlBindTextureFunc = std::mem::transmute(proc("glBindTexture")?);
+    let get_error: GlGetErrorFunc = std::mem::transmute(proc("glGetError")?);
+    let image_target_texture: GlEglImageTargetTexture2dOesFunc =
+        std::mem::transmute(proc("glEGLImageTargetTexture2DOES")?);
+
+    let mut previous = 0;
+    get_integerv(GL_TEXTURE_BINDING_2D, &mut previous);
+    let mut id = 0;
+    gen_textures(1, &mut id);
+    bind_texture(GL_TEXTURE_2D as u32, id);
+    image_target_texture(GL_TEXTURE_2D as u32, egl_image);
+    let error = get_error();
+    bind_texture(GL_TEXTURE_2D as u32, previous as u32);
+
+    if error != GL_NO_ERROR {
+        delete_textures(1, &id);
+        return Err(format!(
+            "DMA-BUF cannot be used as texture (GL error {:#x})",
+            error
+        ));
+    }
+    Ok(id)
+}
+
+// Modifiers with which EGL can import `fourcc` as a 2D texture. None if the format cannot be imported.
+// Empty if only the implicit layout of the driver can be imported.
+pub fn importable_modifiers(
+    display: &Display<WindowSurface>,
+    fourcc: DrmFourcc,
+) -> Option<Vec<DrmModifier>> {
+    let egl = khronos_egl::Instance::new(khronos_egl::Static);
+
+    unsafe {
+        display.get_context().exec_in_context(|| {
+            let egl_display = egl.get_current_display().unwrap().as_ptr();
+            let (Some(query_formats), Some(query_modifiers)) = (
+                egl.get_proc_address("eglQueryDmaBufFormatsEXT"),
+                egl.get_proc_address("eglQueryDmaBufModifiersEXT"),
+            ) else {
+                // Without EGL_EXT_image_dma_buf_import_modifiers
+                return Some(Vec::new());
+            };
+            let query_formats: EglQueryDmabufFormatsExtFunc = std::mem::transmute(query_formats);
+            let query_modifiers: EglQueryDmabufModifiersExtFunc =
+                std::mem::transmute(query_modifiers);
+
+            let mut count = 0;
+            if query_formats(egl_display, 0, std::ptr::null_mut(), &mut count) == FALSE {
+                return None;
+            }
+            let mut formats = vec![0; count as usize];
+            query_formats(egl_display, count, formats.as_mut_ptr(), &mut count);
+            if !formats.contains(&(fourcc as u32 as Int)) {
+                return None;
+            }
+
+            let format = fourcc as u32 as Int;
+            let (null_modifiers, null_external) = (std::ptr::null_mut(), std::ptr::null_mut());
+            if query_modifiers(
+                egl_display,
+                format,
+                0,
+                null_modifiers,
+                null_external,
+                &mut count,
+            ) == FALSE
+            {
+                return None;
+            }
+            let mut modifiers = vec![0; count as usize];
+            let mut external_only = vec![FALSE; count as usize];
+            query_modifiers(
+                egl_display,
+                format,
+                count,
+                modifiers.as_mut_ptr(),
+                external_only.as_mut_ptr(),
+                &mut count,
+            );
+            // External-only ones need GL_TEXTURE_EXTERNAL_OES
+            Some(
+                modifiers
+                    .into_iter()
+                    .zip(external_only)
+                    .filter(|&(_, external_only)| external_only == FALSE)
+                    .map(|(modifier, _)| modifier.into())
+                    .collect(),
+            )
+        })
+    }
+}
+
+// DRM device of the GPU EGL renders with, preferably its render node
+pub fn render_node(display: &Display<WindowSurface>) -> Option<PathBuf> {
+    let egl = khronos_egl::Instance::new(khronos_egl::Static);
+
+    unsafe {
+        display.get_context().exec_in_context(|| {
+            let egl_display = egl.get_current_display().unwrap().as_ptr();
+            let query_display_attrib: EglQueryDisplayAttribExtFunc =
+                std::mem::transmute(egl.get_proc_address("eglQueryDisplayAttribEXT")?);
+            let query_device_string: EglQueryDeviceStringExtFunc =
+                std::mem::transmute(egl.get_proc_address("eglQueryDeviceStringEXT")?);
+
+            let mut device: Attrib = 0;
+            if query_display_attrib(egl_display, EGL_DEVICE_EXT, &mut device) == FALSE {
+                return None;
+            }
+            [EGL_DRM_RENDER_NODE_FILE_EXT, EGL_DRM_DEVICE_FILE_EXT]
+                .into_iter()
+                .find_map(|name| {
+                    let path = query_device_string(device as *mut c_void, name);
+                    if path.is_null() {
+                        return None;
+                    }
+                    Some(PathBuf::from(CStr::from_ptr(path).to_str().ok()?))
+                })
+        })
     }
 }
@@ -2,220 +2,1055 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use drm_fourcc::DrmFourcc;
+use cursor::CursorState;
+use dmabuf::{choose_allocation, choose_format, BufferFormat, DmabufFormats};
+use drm_fourcc::{DrmFourcc, DrmModifier};
 use glium::{glutin::surface::WindowSurface, Display, Texture2d};
-use std::{cell::RefCell, collections::HashMap, sync::Arc};
-use texture::DmabufTexture;
+use session::SessionState;
+use std::{
+    collections::HashMap,
+    os::fd::AsFd,
+    path::PathBuf,
+    sync::{
+        mpsc::{self, RecvTimeoutError, TryRecvError},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+use texture::{importable_modifiers, render_node, DmabufPlane, DmabufTexture};
+use toplevel::{ToplevelFilter, ToplevelInfo};
 use wayland_client::{
+    backend::WaylandError,
     protocol::{
         wl_buffer::{self, WlBuffer},
-        wl_output::{self, WlOutput},
+        wl_callback::{self, WlCallback},
+        wl_output::WlOutput,
+        wl_pointer::WlPointer,
         wl_registry::{self, WlRegistry},
+        wl_seat::WlSeat,
+    },
+    Connection, Dispatch, DispatchError, EventQueue, Proxy, QueueHandle, WEnum,
+};
+use wayland_protocols::ext::{
+    foreign_toplevel_list::v1::client::{
+        ext_foreign_toplevel_handle_v1::ExtForeignToplevelHandleV1,
+        ext_foreign_toplevel_list_v1::ExtForeignToplevelListV1,
+    },
+    image_capture_source::v1::client::{
+        ext_foreign_toplevel_image_capture_source_manager_v1::ExtForeignToplevelImageCaptureSourceManagerV1,
+        ext_output_image_capture_source_manager_v1::ExtOutputImageCaptureSourceManagerV1,
+    },
+    image_copy_capture::v1::client::{
+        ext_image_copy_capture_cursor_session_v1::ExtImageCopyCaptureCursorSessionV1,
+        ext_image_copy_capture_frame_v1::FailureReason,
+        ext_image_copy_capture_manager_v1::ExtImageCopyCaptureManagerV1,
+        ext_image_copy_capture_session_v1::ExtImageCopyCaptureSessionV1,
     },
-    Connection, Dispatch, EventQueue, Proxy,
 };
 use wayland_protocols::wp::linux_dmabuf::zv1::client::{
     zwp_linux_buffer_params_v1::{self, ZwpLinuxBufferParamsV1},
     zwp_linux_dmabuf_v1::ZwpLinuxDmabufV1,
 };
+use wayland_protocols::xdg::xdg_output::zv1::client::zxdg_output_manager_v1::ZxdgOutputManagerV1;
+use wayland_protocols_wlr::output_management::v1::client::{
+    zwlr_output_head_v1::ZwlrOutputHeadV1, zwlr_output_manager_v1::ZwlrOutputManagerV1,
+};
 use wayland_protocols_wlr::screencopy::v1::client::{
     zwlr_screencopy_frame_v1::{self, ZwlrScreencopyFrameV1},
     zwlr_screencopy_manager_v1::ZwlrScreencopyManagerV1,
 };
 
-use super::{texture, Capturer};
+use super::{
+    gbm::GbmDevice, limiter::FrameLimiter, monotonic_now, texture, CaptureError, CapturedFrame,
+    Capturer, DamageRect, FrameTiming,
+};
+use crate::{
+    config::{Allocator, OutputMatch, Region},
+    output_match::OutputProperties,
+};
+use output::{find_output, OutputInfo};
 
+pub mod cursor;
+mod dmabuf;
+mod output;
+mod session;
+mod toplevel;
+
+pub use output::list_outputs;
+
+// One buffer is shown by the renderer, one is being written by the compositor, and one is spare
+const NUM_BUFFERS: usize = 3;
+
+// Wait after a failed capture, doubled for each consecutive failure up to about a second
+const FAILURE_DELAY: Duration = Duration::from_millis(30);
+const MAX_FAILURE_DELAY_EXPONENT: u32 = 5;
+
+// Used when EGL does not tell its device
+const DEFAULT_RENDER_NODE: &str = "/dev/dri/renderD128";
+
+// Capture runs on its own thread with its own event queue, so that waiting for the compositor
+// never blocks rendering. Buffers are allocated on the render thread (because they are GL textures)
+// and rotated between the two threads by index.
 pub struct WaylandCapturer {
-    queue: EventQueue<State>,
-    state: State,
     glium_display: Arc<Display<WindowSurface>>,
-    texture: Option<DmabufTexture>,
-    wl_buffer: Option<WlBuffer>,
+    allocator: BufferAllocator,
+    textures: Vec<DmabufTexture>,
+    current: Option<usize>, // Index of the buffer the renderer is showing
+    current_texture: Option<Arc<Texture2d>>,
+    current_timing: Option<FrameTiming>,
+    conn: Connection,
+    thread_queue: QueueHandle<State>,
+    command_sender: mpsc::Sender<Command>,
+    notification_receiver: mpsc::Receiver<Notification>,
+    join_handle: Option<JoinHandle<()>>,
+    source_lost: bool,
+    missing_output: Option<String>, // Output the capture thread is waiting for
+    error: Option<CaptureError>,    // Why the capture thread has stopped
+}
+
+enum BufferAllocator {
+    // Falls back to `Export` if `fallback` and no format both the compositor and EGL support is found
+    Gbm { device: GbmDevice, fallback: bool },
+    Export,
+}
+
+// Messages from the renderer to the capture thread
+enum Command {
+    SetBuffers(Vec<BufferDesc>),
+    Release(usize),
+    Stop,
 }
 
-#[derive(Clone, Debug)]
-struct OutputInfo {
-    output: WlOutput,
-    name: Option<String>,
-    width: Option<i32>,
-    height: Option<i32>,
+// Messages from the capture thread to the renderer
+enum Notification {
+    Allocate {
+        width: u32,
+        height: u32,
+        formats: Vec<BufferFormat>, // Formats the compositor can write, in order of preference
+    },
+    Frame {
+        index: usize,
+        damage: Vec<DamageRect>,
+        timing: FrameTiming,
+    },
+    SourceLost,
+    OutputNotFound(String), // The capture thread waits for the output to appear
+    Failed(String),         // The capture thread retries
+    Error(CaptureError),    // The capture thread stops
+}
+
+// DMA-BUF exported from a texture on the render thread
+struct BufferDesc {
+    planes: Vec<DmabufPlane>,
+    format: DrmFourcc, // Format written by the compositor
+    modifier: DrmModifier,
+    width: u32,
+    height: u32,
+}
+
+// What the capture thread captures
+enum Source {
+    Output {
+        target: OutputTarget,
+        region: Option<Region>,
+        overlay_cursor: bool,
+    },
+    Toplevel(ToplevelFilter),
+    Cursor(OutputTarget),
+}
+
+// Output selected by its properties (or any output if None).
+// It may be plugged and unplugged while capturing.
+struct OutputTarget {
+    selector: Option<OutputMatch>,
+    output: Option<WlOutput>, // None while the output does not exist
+}
+
+struct CaptureThread {
+    queue: EventQueue<State>,
+    state: State,
+    source: Source,
+    session: Option<ExtImageCopyCaptureSessionV1>, // Only for `Source::Toplevel` and `Source::Cursor`
+    cursor_session: Option<(ExtImageCopyCaptureCursorSessionV1, WlPointer)>, // Only for `Source::Cursor`
+    command_receiver: mpsc::Receiver<Command>,
+    notification_sender: mpsc::Sender<Notification>,
+    buffers: Vec<WlBuffer>,
+    buffer_spec: (u32, u32, Vec<BufferFormat>), // Width, height and formats of the current buffers
+    lent: Vec<bool>,                            // Whether each buffer is held by the renderer
+    stale: Vec<Vec<DamageRect>>,                // Regions newer than the content of each buffer
+    failures: u32,                              // Number of consecutive failed captures
+    limiter: FrameLimiter,
 }
 
 #[derive(Default)]
 struct State {
-    all_outputs: HashMap<WlOutput, Arc<RefCell<OutputInfo>>>,
-    output: Option<Arc<RefCell<OutputInfo>>>,
+    all_outputs: HashMap<WlOutput, OutputInfo>,
+    xdg_output_manager: Option<ZxdgOutputManagerV1>,
+    heads_manager: Option<ZwlrOutputManagerV1>,
+    heads: HashMap<ZwlrOutputHeadV1, OutputProperties>, // Only for properties wl_output lacks
     manager: Option<ZwlrScreencopyManagerV1>,
     dmabuf_factory: Option<ZwpLinuxDmabufV1>,
+    dmabuf_formats: DmabufFormats,
+    toplevel_list: Option<ExtForeignToplevelListV1>,
+    toplevel_source_manager: Option<ExtForeignToplevelImageCaptureSourceManagerV1>,
+    image_copy_manager: Option<ExtImageCopyCaptureManagerV1>,
+    output_source_manager: Option<ExtOutputImageCaptureSourceManagerV1>,
+    seat: Option<WlSeat>,
+    cursor: Arc<Mutex<CursorState>>,
+    toplevels: HashMap<ExtForeignToplevelHandleV1, ToplevelInfo>,
+    session: SessionState,
     buf_width: u32,
     buf_height: u32,
     buf_format: u32,
+    damage: Vec<DamageRect>,
+    presented: Option<Duration>, // Presentation time of the frame being captured
     ready: bool,
-    released: bool,
+    failed: Option<WEnum<FailureReason>>, // Always `Unknown` for `zwlr_screencopy_frame_v1`
+    buffer_busy: Vec<bool>,               // Whether the compositor has not released each buffer yet
+    woken: bool,
 }
 
 impl WaylandCapturer {
-    pub fn new(glium_display: Arc<Display<WindowSurface>>, output_name: Option<&str>) -> Self {
-        let conn = Connection::connect_to_env().unwrap();
-        let display = conn.display();
+    // If `overlay_cursor` is false, the mouse cursor is not included in captured frames.
+    pub fn new(
+        glium_display: Arc<Display<WindowSurface>>,
+        output: Option<&OutputMatch>,
+        region: Option<Region>,
+        overlay_cursor: bool,
+        limiter: FrameLimiter,
+        allocator: Allocator,
+    ) -> Result<Self, CaptureError> {
+        let (conn, queue, state) = connect()?;
 
-        let mut queue = conn.new_event_queue();
+        if state.manager.is_none() {
+            return Err(CaptureError::Unsupported(
+                "the compositor does not support screen capture (wlr-screencopy)".to_owned(),
+            ));
+        }
 
-        let mut state = State::default();
+        if let Some(region) = region {
+            log::info!("Capturing region {:?}", region);
+        }
+        let source = Source::Output {
+            target: OutputTarget::new(output),
+            region,
+            overlay_cursor,
+        };
 
-        // (1) Retrieve global objects such as wl_display.
-        // It will fire `wl_registry::global` event.
-        let _registry = display.get_registry(&queue.handle(), ());
-        queue.roundtrip(&mut state).unwrap();
+        Self::spawn(
+            glium_display,
+            conn,
+            queue,
+            state,
+            source,
+            limiter,
+            allocator,
+        )
+    }
 
-        // Receive names (and other properties) of each output
-        queue.blocking_dispatch(&mut state).unwrap();
+    // Capture a single window. It waits until a window matching `app_id` and `title` (substring) appears.
+    pub fn new_window(
+        glium_display: Arc<Display<WindowSurface>>,
+        app_id: Option<&str>,
+        title: Option<&str>,
+        limiter: FrameLimiter,
+        allocator: Allocator,
+    ) -> Result<Self, CaptureError> {
+        let (conn, queue, state) = connect()?;
 
-        for output in state.all_outputs.values() {
-            log::debug!(
-                "Output: {}",
-                output.as_ref().borrow().name.as_ref().unwrap()
-            );
+        if state.toplevel_list.is_none()
+            || state.toplevel_source_manager.is_none()
+            || state.image_copy_manager.is_none()
+        {
+            return Err(CaptureError::Unsupported(
+                "the compositor does not support window capture (ext-image-copy-capture)"
+                    .to_owned(),
+            ));
         }
 
-        // (3) Select output.
-        let output = if let Some(output_name) = output_name {
-            let output = state
-                .all_outputs
-                .iter()
-                .find(|(_, output)| output.as_ref().borrow().name.as_ref().unwrap() == output_name)
-                .unwrap();
-            log::info!(
-                "Capturing {}",
-                output.1.as_ref().borrow().name.as_ref().unwrap()
-            );
-
-            output.1.clone()
-        } else {
-            state.all_outputs.values().next().unwrap().clone()
-        };
-        state.output = Some(output);
+        let source = Source::Toplevel(ToplevelFilter {
+            app_id: app_id.map(str::to_owned),
+            title: title.map(str::to_owned),
+        });
 
-        Self {
+        Self::spawn(
+            glium_display,
+            conn,
             queue,
             state,
+            source,
+            limiter,
+            allocator,
+        )
+    }
+
+    fn spawn(
+        glium_display: Arc<Display<WindowSurface>>,
+        conn: Connection,
+        queue: EventQueue<State>,
+        state: State,
+        source: Source,
+        limiter: FrameLimiter,
+        allocator: Allocator,
+    ) -> Result<Self, CaptureError> {
+        let allocator = buffer_allocator(&glium_display, allocator)?;
+        let thread_queue = queue.handle();
+        let (command_sender, command_receiver) = mpsc::channel();
+        let (notification_sender, notification_receiver) = mpsc::channel();
+
+        let join_handle = thread::spawn(move || {
+            CaptureThread {
+                queue,
+                state,
+                source,
+                session: None,
+                cursor_session: None,
+                command_receiver,
+                notification_sender,
+                buffers: Vec::new(),
+                buffer_spec: (0, 0, Vec::new()),
+                lent: Vec::new(),
+                stale: Vec::new(),
+                failures: 0,
+                limiter,
+            }
+            .run();
+        });
+
+        Ok(Self {
             glium_display,
-            texture: None,
-            wl_buffer: None,
-        }
+            allocator,
+            textures: Vec::new(),
+            current: None,
+            current_texture: None,
+            current_timing: None,
+            conn,
+            thread_queue,
+            command_sender,
+            notification_receiver,
+            join_handle: Some(join_handle),
+            source_lost: false,
+            missing_output: None,
+            error: None,
+        })
+    }
+
+    fn allocate(
+        &mut self,
+        width: u32,
+        height: u32,
+        formats: &[BufferFormat],
+    ) -> Result<(), CaptureError> {
+        log::debug!("Requested dma-buf: {:?} {} {}", formats, width, height);
+
+        // Buffers of the old size are dropped, except the one currently shown (kept alive by `current_texture`).
+        // The capture thread forgets the old buffers too, so the index need not be released.
+        self.current = None;
+
+        // (6) Create buffers on GPU.
+        let buffers = match self.allocator {
+            BufferAllocator::Gbm {
+                ref device,
+                fallback,
+            } => match self.allocate_gbm(device, width, height, formats) {
+                Err(CaptureError::Unsupported(reason)) if fallback => {
+                    log::info!("Exporting textures instead of GBM: {}", reason);
+                    self.allocator = BufferAllocator::Export;
+                    self.export_textures(width, height, formats)
+                }
+                result => result,
+            },
+            BufferAllocator::Export => self.export_textures(width, height, formats),
+        }?;
+
+        let descs = buffers
+            .iter()
+            .map(|(texture, format)| -> Result<_, CaptureError> {
+                log::debug!(
+                    "Created dma-buf: {:?} {:?} ({} planes) written as {:?}",
+                    texture.fourcc(),
+                    texture.modifier(),
+                    texture.planes().len(),
+                    format
+                );
+                let planes = texture
+                    .planes()
+                    .iter()
+                    .map(|plane| {
+                        Ok(DmabufPlane {
+                            fd: plane.fd.try_clone().map_err(|err| {
+                                CaptureError::Failed(format!(
+                                    "cannot duplicate DMA-BUF fd: {}",
+                                    err
+                                ))
+                            })?,
+                            offset: plane.offset,
+                            stride: plane.stride,
+                        })
+                    })
+                    .collect::<Result<_, CaptureError>>()?;
+                Ok(BufferDesc {
+                    planes,
+                    format: *format,
+                    modifier: texture.modifier(),
+                    width,
+                    height,
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        self.textures = buffers.into_iter().map(|(texture, _)| texture).collect();
+        // Fails only if the capture thread has stopped, which is noticed in `capture`
+        let _ = self.command_sender.send(Command::SetBuffers(descs));
+        Ok(())
+    }
+
+    // Buffers allocated by GBM in a format both the compositor and EGL support,
+    // with the format the compositor writes
+    fn allocate_gbm(
+        &self,
+        device: &GbmDevice,
+        width: u32,
+        height: u32,
+        formats: &[BufferFormat],
+    ) -> Result<Vec<(DmabufTexture, DrmFourcc)>, CaptureError> {
+        let display = self.glium_display.as_ref();
+        let allocation =
+            choose_allocation(formats, |fourcc| importable_modifiers(display, fourcc))?;
+        log::debug!("Allocating with GBM: {:?}", allocation);
+        (0..NUM_BUFFERS)
+            .map(|_| {
+                let buffer = device
+                    .allocate(width, height, allocation.buffer, &allocation.modifiers)
+                    .map_err(CaptureError::Unsupported)?;
+                let texture = DmabufTexture::import(
+                    display,
+                    width,
+                    height,
+                    allocation.texture,
+                    buffer.modifier,
+                    buffer.planes,
+                )
+                .map_err(CaptureError::Unsupported)?;
+                Ok((texture, allocation.buffer))
+            })
+            .collect()
+    }
+
+    // GL textures exported as DMA-BUFs, with the format the compositor writes
+    fn export_textures(
+        &self,
+        width: u32,
+        height: u32,
+        formats: &[BufferFormat],
+    ) -> Result<Vec<(DmabufTexture, DrmFourcc)>, CaptureError> {
+        (0..NUM_BUFFERS)
+            .map(|_| {
+                let texture = Texture2d::empty(self.glium_display.as_ref(), width, height)
+                    .map_err(|err| {
+                        CaptureError::Failed(format!("cannot create texture: {}", err))
+                    })?;
+                let texture = DmabufTexture::export(texture).map_err(CaptureError::Unsupported)?;
+                let format = choose_format(texture.fourcc(), texture.modifier(), formats)?;
+                Ok((texture, format))
+            })
+            .collect()
+    }
+
+    // Give a buffer back to the capture thread
+    fn release(&self, index: usize) {
+        // Fails only if the capture thread has stopped
+        let _ = self.command_sender.send(Command::Release(index));
     }
 }
 
 impl Capturer for WaylandCapturer {
-    fn capture(&mut self) -> Arc<Texture2d> {
-        self.state.ready = false;
-        self.state.released = false;
+    fn capture(&mut self) -> Result<CapturedFrame, CaptureError> {
+        if let Some(ref error) = self.error {
+            return Err(error.clone());
+        }
+
+        let mut damage = Vec::new();
+        let mut new_frame = false;
+        let mut failure = None;
+
+        // Take the most recently completed frame and give older ones back to the capture thread
+        loop {
+            let notification = match self.notification_receiver.try_recv() {
+                Ok(notification) => notification,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    let error = CaptureError::Disconnected("capture thread stopped".to_owned());
+                    self.error = Some(error.clone());
+                    return Err(error);
+                }
+            };
+
+            match notification {
+                Notification::Allocate {
+                    width,
+                    height,
+                    formats,
+                } => {
+                    if let Err(error) = self.allocate(width, height, &formats) {
+                        // The capturer has to be recreated, even after errors which are usually transient
+                        let error = match error {
+                            error if error.is_transient() => {
+                                CaptureError::Disconnected(error.to_string())
+                            }
+                            error => error,
+                        };
+                        self.error = Some(error.clone());
+                        return Err(error);
+                    }
+                }
+                Notification::Frame {
+                    index,
+                    damage: frame_damage,
+                    timing,
+                } => {
+                    if let Some(previous) = self.current.replace(index) {
+                        self.release(previous);
+                    }
+                    self.current_texture = Some(self.textures[index].texture());
+                    self.current_timing = Some(timing);
+                    self.source_lost = false;
+                    self.missing_output = None;
+                    damage.extend(frame_damage);
+                    new_frame = true;
+                }
+                Notification::SourceLost => {
+                    if let Some(previous) = self.current.take() {
+                        self.release(previous);
+                    }
+                    self.current_texture = None;
+                    self.source_lost = true;
+                }
+                Notification::OutputNotFound(name) => self.missing_output = Some(name),
+                Notification::Failed(reason) => failure = Some(CaptureError::Failed(reason)),
+                Notification::Error(error) => {
+                    self.error = Some(error.clone());
+                    return Err(error);
+                }
+            }
+        }
+
+        // The renderer keeps showing the last frame while capture is failing.
+        // A new frame has to be returned anyway, because the previous buffer has been released.
+        if let Some(failure) = failure.filter(|_| !new_frame) {
+            return Err(failure);
+        }
+        // Capture is paused, keeping the last frame, until the output is plugged again
+        if let Some(ref name) = self.missing_output {
+            return Err(CaptureError::OutputNotFound(name.clone()));
+        }
+
+        match self.current_texture {
+            Some(ref texture) => Ok(CapturedFrame {
+                texture: Arc::clone(texture),
+                damage,
+                timing: self.current_timing,
+            }),
+            None if self.source_lost => Err(CaptureError::SourceLost),
+            None => Err(CaptureError::NotReady),
+        }
+    }
+}
+
+impl Drop for WaylandCapturer {
+    fn drop(&mut self) {
+        let _ = self.command_sender.send(Command::Stop);
+        // The capture thread may be waiting for Wayland events, so generate one to wake it up.
+        self.conn.display().sync(&self.thread_queue, ());
+        let _ = self.conn.flush();
+
+        if let Some(join_handle) = self.join_handle.take() {
+            let _ = join_handle.join();
+        }
+    }
+}
+
+impl CaptureThread {
+    fn run(mut self) {
+        loop {
+            match self.capture_frame() {
+                Ok(true) => (),
+                Ok(false) => break,
+                Err(err) => {
+                    // Fails only if the capturer is being dropped
+                    let _ = self.notification_sender.send(Notification::Error(err));
+                    break;
+                }
+            }
+        }
+
+        if let Some(session) = self.session.take() {
+            session.destroy();
+        }
+        if let Some((cursor_session, pointer)) = self.cursor_session.take() {
+            cursor_session.destroy();
+            pointer.release();
+        }
+        for buffer in &self.buffers {
+            buffer.destroy();
+        }
+        let _ = self.queue.flush();
+    }
+
+    // Capture one frame into a free buffer. Returns false when stop is requested.
+    fn capture_frame(&mut self) -> Result<bool, CaptureError> {
+        match self.source {
+            Source::Output { .. } => self.capture_output_frame(),
+            Source::Toplevel(_) => self.capture_toplevel_frame(),
+            Source::Cursor(_) => self.capture_cursor_frame(),
+        }
+    }
+
+    fn capture_output_frame(&mut self) -> Result<bool, CaptureError> {
+        // Keep the capture frame rate limit
+        if !self.pause(self.limiter.remaining()) {
+            return Ok(false);
+        }
+
+        let Some(output) = self.wait_for_output()? else {
+            return Ok(false);
+        };
+        let Source::Output {
+            region,
+            overlay_cursor,
+            ..
+        } = self.source
+        else {
+            unreachable!()
+        };
 
         // (4) Request the compositor to capture the screen.
         // It will fire several events such as `zwlr_screencopy_frame_v1::buffer_done`.
-        let frame = self.state.manager.as_ref().unwrap().capture_output(
-            1, // include mouse cursor
-            &self.state.output.as_ref().unwrap().as_ref().borrow().output,
-            &self.queue.handle(),
-            (),
-        );
-        self.queue.roundtrip(&mut self.state).unwrap();
-
-        if self.texture.is_none() {
-            let width = self.state.buf_width;
-            let height = self.state.buf_height;
-
-            // (5) Query size and format of the buffer.
-            let dmabuf_params = self
-                .state
-                .dmabuf_factory
-                .as_ref()
-                .unwrap()
-                .create_params(&self.queue.handle(), ());
-            self.queue.roundtrip(&mut self.state).unwrap();
-
-            let buf_format = DrmFourcc::try_from(self.state.buf_format).unwrap();
-            log::debug!("Requested dma-buf: {:?} {} {}", buf_format, width, height);
-            if buf_format != DrmFourcc::Xrgb8888 {
-                panic!("Unsupported buffer format requested");
+        let manager = self.state.manager.as_ref().unwrap();
+        let frame = if let Some(region) = region {
+            manager.capture_output_region(
+                overlay_cursor as i32,
+                &output,
+                region.x,
+                region.y,
+                region.width,
+                region.height,
+                &self.queue.handle(),
+                (),
+            )
+        } else {
+            manager.capture_output(overlay_cursor as i32, &output, &self.queue.handle(), ())
+        };
+        self.state.failed = None;
+        self.queue.roundtrip(&mut self.state)?;
+
+        // The compositor refuses to capture e.g. a disabled output
+        if self.state.failed.take().is_some() {
+            frame.destroy();
+            return self.retry_output("Screen capture failed");
+        }
+
+        // (5) Query size and format of the buffer.
+        // They change when the output mode is changed while capturing.
+        let format = self.state.buffer_format(self.state.buf_format)?;
+        let spec = (self.state.buf_width, self.state.buf_height, vec![format]);
+        if !self.prepare_buffers(spec)? {
+            frame.destroy();
+            return Ok(false);
+        }
+
+        let Some(index) = self.wait_for_buffer()? else {
+            frame.destroy();
+            return Ok(false);
+        };
+
+        // (8) Copy the captured frame into the buffer, but only after something changed.
+        // Changed regions are reported by `zwlr_screencopy_frame_v1::damage` before `ready`.
+        frame.copy_with_damage(&self.buffers[index]);
+        self.state.buffer_busy[index] = true;
+        let finished = self.wait_for_frame()?;
+        frame.destroy();
+        self.queue.flush()?;
+
+        if !finished {
+            return Ok(false);
+        }
+        if self.state.failed.take().is_some() {
+            // Nothing has been written into the buffer
+            self.state.buffer_busy[index] = false;
+            return self.retry_output("Screen capture failed");
+        }
+
+        Ok(self.send_frame(index))
+    }
+
+    // Retry after a failed capture of an output.
+    // If the output has been removed, the next capture waits for it instead.
+    fn retry_output(&mut self, reason: &str) -> Result<bool, CaptureError> {
+        if !self.output_exists() {
+            return Ok(true);
+        }
+
+        Ok(self.retry(reason))
+    }
+
+    fn output_exists(&self) -> bool {
+        let (Source::Output { ref target, .. } | Source::Cursor(ref target)) = self.source else {
+            unreachable!()
+        };
+        target
+            .output
+            .as_ref()
+            .is_some_and(|output| self.state.all_outputs.contains_key(output))
+    }
+
+    // Returns the captured output, waiting until it is plugged if it does not exist.
+    // Returns None when stop is requested.
+    fn wait_for_output(&mut self) -> Result<Option<WlOutput>, CaptureError> {
+        let mut waiting_notified = false;
+        loop {
+            let (Source::Output { ref mut target, .. } | Source::Cursor(ref mut target)) =
+                self.source
+            else {
+                unreachable!()
+            };
+            if let Some(ref output) = target.output {
+                if self.state.all_outputs.contains_key(output) {
+                    return Ok(Some(output.clone()));
+                }
+                log::info!("Captured output has been removed");
+                target.output = None;
+                self.state.damage.clear();
             }
 
-            // (6) Create a buffer on GPU.
-            self.texture = Some(DmabufTexture::new(
-                Texture2d::empty(
-                    self.glium_display.as_ref(),
-                    self.state.buf_width,
-                    self.state.buf_height,
-                )
-                .unwrap(),
-            ));
+            if let Some(output) = find_output(&self.state, target.selector.as_ref()) {
+                let properties = self
+                    .state
+                    .output_properties(&self.state.all_outputs[&output]);
+                log::info!(
+                    "Capturing {} ({})",
+                    properties.name.as_deref().unwrap_or_default(),
+                    properties.description.as_deref().unwrap_or_default()
+                );
+                target.output = Some(output.clone());
+                return Ok(Some(output));
+            }
 
-            // (7) Create Wayland buffer from the buffer.
-            let texture = self.texture.as_ref().unwrap();
-            log::debug!(
-                "Created dma-buf: {:?} {:?}",
-                texture.fourcc(),
-                texture.modifier()
-            );
-            if texture.fourcc() != DrmFourcc::Abgr8888 {
-                panic!("Unsupported DMA-BUF format")
+            if !waiting_notified {
+                let selector = match target.selector {
+                    Some(ref selector) => selector.to_string(),
+                    None => "(any)".to_owned(),
+                };
+                log::info!("Waiting for output {}", selector);
+                if self
+                    .notification_sender
+                    .send(Notification::OutputNotFound(selector))
+                    .is_err()
+                {
+                    return Ok(None);
+                }
+                waiting_notified = true;
+            }
+
+            // Outputs are added and removed by `wl_registry` events
+            self.queue.blocking_dispatch(&mut self.state)?;
+            if self.state.woken && !self.handle_commands() {
+                return Ok(None);
+            }
+        }
+    }
+
+    // Tell the renderer about a failed capture, and wait a little before trying again.
+    // Returns false when stop is requested.
+    fn retry(&mut self, reason: &str) -> bool {
+        if self.failures == 0 {
+            log::warn!("{}. Retrying", reason);
+        }
+        self.state.damage.clear();
+        self.failures += 1;
+
+        if self
+            .notification_sender
+            .send(Notification::Failed(reason.to_owned()))
+            .is_err()
+        {
+            return false;
+        }
+
+        let exponent = (self.failures - 1).min(MAX_FAILURE_DELAY_EXPONENT);
+        self.pause(FAILURE_DELAY * 2u32.pow(exponent))
+    }
+
+    // Sleep while handling commands. Returns false when stop is requested.
+    fn pause(&mut self, duration: Duration) -> bool {
+        let deadline = Instant::now() + duration;
+        loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            match self.command_receiver.recv_timeout(timeout) {
+                Ok(command) => {
+                    if !self.handle_command(command) {
+                        return false;
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => return true,
+                Err(RecvTimeoutError::Disconnected) => return false,
+            }
+        }
+    }
+
+    // (Re)create buffers if the size or formats requested by the compositor have changed.
+    // Returns false when stop is requested.
+    fn prepare_buffers(
+        &mut self,
+        spec: (u32, u32, Vec<BufferFormat>),
+    ) -> Result<bool, CaptureError> {
+        if !self.buffers.is_empty() && spec == self.buffer_spec {
+            return Ok(true);
+        }
+
+        if !self.buffers.is_empty() {
+            log::info!("Capture size changed to {}x{}", spec.0, spec.1);
+        }
+        for buffer in self.buffers.drain(..) {
+            buffer.destroy();
+        }
+        self.buffer_spec = spec.clone();
+
+        // Ask the render thread to create buffers of the requested size, in one of the formats.
+        let allocate = Notification::Allocate {
+            width: spec.0,
+            height: spec.1,
+            formats: spec.2,
+        };
+        if self.notification_sender.send(allocate).is_err() {
+            return Ok(false);
+        }
+        while self.buffers.is_empty() {
+            let Ok(command) = self.command_receiver.recv() else {
+                return Ok(false);
+            };
+            if !self.handle_command(command) {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    // Wait until the compositor finishes (or fails) copying. Returns false when stop is requested.
+    fn wait_for_frame(&mut self) -> Result<bool, CaptureError> {
+        self.state.ready = false;
+        self.state.failed = None;
+        self.state.presented = None;
+        self.queue.flush()?;
+        while !self.state.ready && self.state.failed.is_none() {
+            self.queue.blocking_dispatch(&mut self.state)?;
+            if self.state.woken && !self.handle_commands() {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    // Hand over a completed frame to the renderer
+    fn send_frame(&mut self, index: usize) -> bool {
+        if self.failures > 0 {
+            log::info!("Capture recovered");
+            self.failures = 0;
+        }
+        self.limiter.frame_captured();
+        self.lent[index] = true;
+
+        let ready = monotonic_now();
+        let timing = FrameTiming {
+            presented: self.state.presented.take().unwrap_or(ready),
+            ready,
+        };
+        self.notification_sender
+            .send(Notification::Frame {
+                index,
+                damage: std::mem::take(&mut self.state.damage),
+                timing,
+            })
+            .is_ok()
+    }
+
+    // Find a buffer which neither the renderer nor the compositor is using
+    fn wait_for_buffer(&mut self) -> Result<Option<usize>, CaptureError> {
+        loop {
+            if !self.handle_commands() {
+                return Ok(None);
+            }
+
+            let available = (0..self.buffers.len())
+                .find(|&index| !self.lent[index] && !self.state.buffer_busy[index]);
+            if available.is_some() {
+                return Ok(available);
+            }
+
+            if self.lent.iter().all(|&lent| lent) {
+                // Wait for the renderer
+                let Ok(command) = self.command_receiver.recv() else {
+                    return Ok(None);
+                };
+                if !self.handle_command(command) {
+                    return Ok(None);
+                }
+            } else {
+                // Wait for the compositor
+                self.queue.blocking_dispatch(&mut self.state)?;
             }
-            let modifier: u64 = texture.modifier().into();
+        }
+    }
+
+    // Handle commands that have already arrived. Returns false when stop is requested.
+    fn handle_commands(&mut self) -> bool {
+        self.state.woken = false;
+        loop {
+            match self.command_receiver.try_recv() {
+                Ok(command) => {
+                    if !self.handle_command(command) {
+                        return false;
+                    }
+                }
+                Err(TryRecvError::Empty) => return true,
+                Err(TryRecvError::Disconnected) => return false,
+            }
+        }
+    }
+
+    fn handle_command(&mut self, command: Command) -> bool {
+        match command {
+            Command::SetBuffers(descs) => {
+                for buffer in self.buffers.drain(..) {
+                    buffer.destroy();
+                }
+                self.buffers = descs
+                    .into_iter()
+                    .enumerate()
+                    .map(|(index, desc)| self.create_buffer(index, desc))
+                    .collect();
+                self.lent = vec![false; self.buffers.len()];
+                let full = DamageRect::full(self.buffer_spec.0, self.buffer_spec.1);
+                self.stale = vec![vec![full]; self.buffers.len()];
+                self.state.buffer_busy = vec![false; self.buffers.len()];
+            }
+            Command::Release(index) => {
+                self.lent[index] = false;
+            }
+            Command::Stop => return false,
+        }
+        true
+    }
+
+    // (7) Create Wayland buffer from the DMA-BUF.
+    fn create_buffer(&self, index: usize, desc: BufferDesc) -> WlBuffer {
+        let dmabuf_params = self
+            .state
+            .dmabuf_factory
+            .as_ref()
+            .unwrap()
+            .create_params(&self.queue.handle(), ());
+        let modifier: u64 = desc.modifier.into();
+        for (plane_idx, plane) in desc.planes.iter().enumerate() {
             dmabuf_params.add(
-                texture.fd(),
-                0,
-                texture.offset(),
-                texture.stride(),
+                plane.fd.as_fd(),
+                plane_idx as u32,
+                plane.offset,
+                plane.stride,
                 (modifier >> 32) as u32,
                 (modifier & 0xFFFFFFFF) as u32,
             );
-            self.wl_buffer = Some(dmabuf_params.create_immed(
-                width as i32,
-                height as i32,
-                DrmFourcc::Xrgb8888 as u32, // Writing XRGB8888 into ABGR8888, but correctable in shader.
-                zwp_linux_buffer_params_v1::Flags::empty(),
-                &self.queue.handle(),
-                (),
-            ));
-            self.queue.roundtrip(&mut self.state).unwrap();
-            log::debug!("Buffer {:?}", self.wl_buffer);
         }
+        let buffer = dmabuf_params.create_immed(
+            desc.width as i32,
+            desc.height as i32,
+            desc.format as u32, // Red and blue are swapped from the texture, but correctable in shader.
+            zwp_linux_buffer_params_v1::Flags::empty(),
+            &self.queue.handle(),
+            index,
+        );
+        log::debug!("Buffer {:?}", buffer);
+        buffer
+    }
+}
 
-        // (8) Copy the captured frame into the buffer.
-        frame.copy(self.wl_buffer.as_ref().unwrap());
-        self.queue.flush().unwrap();
-        while !self.state.ready {
-            self.queue.blocking_dispatch(&mut self.state).unwrap();
+impl OutputTarget {
+    fn new(selector: Option<&OutputMatch>) -> Self {
+        Self {
+            selector: selector.cloned(),
+            output: None,
         }
+    }
+}
 
-        frame.destroy();
-        self.queue.flush().unwrap();
-        while !self.state.released {
-            self.queue.blocking_dispatch(&mut self.state).unwrap();
-        }
-
-        self.texture.as_ref().unwrap().texture()
-    }
-
-    fn resolution(&self) -> (u32, u32) {
-        (
-            self.state
-                .output
-                .as_ref()
-                .unwrap()
-                .as_ref()
-                .borrow()
-                .width
-                .unwrap() as u32,
-            self.state
-                .output
-                .as_ref()
-                .unwrap()
-                .as_ref()
-                .borrow()
-                .height
-                .unwrap() as u32,
-        )
+fn buffer_allocator(
+    display: &Display<WindowSurface>,
+    allocator: Allocator,
+) -> Result<BufferAllocator, CaptureError> {
+    if allocator == Allocator::Export {
+        return Ok(BufferAllocator::Export);
+    }
+
+    let node = render_node(display).unwrap_or_else(|| PathBuf::from(DEFAULT_RENDER_NODE));
+    match GbmDevice::open(&node) {
+        Ok(device) => {
+            log::debug!("Allocating buffers with GBM on {}", node.display());
+            Ok(BufferAllocator::Gbm {
+                device,
+                fallback: allocator == Allocator::Auto,
+            })
+        }
+        Err(reason) if allocator == Allocator::Auto => {
+            log::debug!("GBM is not available ({}), exporting textures", reason);
+            Ok(BufferAllocator::Export)
+        }
+        Err(reason) => Err(CaptureError::Unsupported(format!("GBM ({})", reason))),
+    }
+}
+
+// Connect to the compositor and retrieve global objects
+fn connect() -> Result<(Connection, EventQueue<State>, State), CaptureError> {
+    let conn =
+        Connection::connect_to_env().map_err(|err| CaptureError::Disconnected(err.to_string()))?;
+    let display = conn.display();
+
+    let mut queue = conn.new_event_queue();
+
+    let mut state = State::default();
+
+    // (1) Retrieve global objects such as wl_display.
+    // It will fire `wl_registry::global` event.
+    let _registry = display.get_registry(&queue.handle(), ());
+    queue.roundtrip(&mut state)?;
+
+    // Receive names (and other properties) of each output
+    queue.roundtrip(&mut state)?;
+
+    // All capture methods write into DMA-BUFs
+    if state.dmabuf_factory.is_none() {
+        return Err(CaptureError::Unsupported(
+            "the compositor does not support linux-dmabuf".to_owned(),
+        ));
+    }
+
+    Ok((conn, queue, state))
+}
+
+// Timestamp of CLOCK_MONOTONIC split into protocol arguments
+fn timestamp(tv_sec_hi: u32, tv_sec_lo: u32, tv_nsec: u32) -> Duration {
+    Duration::new(((tv_sec_hi as u64) << 32) | tv_sec_lo as u64, tv_nsec)
+}
+
+impl From<DispatchError> for CaptureError {
+    fn from(err: DispatchError) -> Self {
+        CaptureError::Disconnected(err.to_string())
+    }
+}
+
+impl From<WaylandError> for CaptureError {
+    fn from(err: WaylandError) -> Self {
+        CaptureError::Disconnected(err.to_string())
     }
 }
 
@@ -237,42 +1072,47 @@ impl Dispatch<WlRegistry, (), Self> for State {
             } => {
                 if interface == WlOutput::interface().name {
                     let output: WlOutput = proxy.bind(name, version, qhandle, ());
-                    state.all_outputs.insert(
-                        output.clone(),
-                        Arc::new(RefCell::new(OutputInfo {
-                            output,
-                            name: None,
-                            width: None,
-                            height: None,
-                        })),
-                    );
+                    state.add_output(output, name, qhandle);
+                } else if interface == ZxdgOutputManagerV1::interface().name {
+                    let manager = proxy.bind(name, version.min(3), qhandle, ());
+                    state.set_xdg_output_manager(manager, qhandle);
+                } else if interface == ZwlrOutputManagerV1::interface().name {
+                    state.heads_manager = Some(proxy.bind(name, version.min(4), qhandle, ()));
                 } else if interface == ZwlrScreencopyManagerV1::interface().name {
                     state.manager = Some(proxy.bind(name, version, qhandle, ()));
                 } else if interface == ZwpLinuxDmabufV1::interface().name {
-                    state.dmabuf_factory = Some(proxy.bind(name, version, qhandle, ()));
+                    // Modifiers come from the default feedback since version 4
+                    let factory: ZwpLinuxDmabufV1 = proxy.bind(name, version.min(4), qhandle, ());
+                    if factory.version() >= 4 {
+                        factory.get_default_feedback(qhandle, ());
+                    }
+                    state.dmabuf_factory = Some(factory);
+                } else if interface == ExtForeignToplevelListV1::interface().name {
+                    state.toplevel_list = Some(proxy.bind(name, version, qhandle, ()));
+                } else if interface
+                    == ExtForeignToplevelImageCaptureSourceManagerV1::interface().name
+                {
+                    state.toplevel_source_manager = Some(proxy.bind(name, version, qhandle, ()));
+                } else if interface == ExtImageCopyCaptureManagerV1::interface().name {
+                    state.image_copy_manager = Some(proxy.bind(name, version, qhandle, ()));
+                } else if interface == ExtOutputImageCaptureSourceManagerV1::interface().name {
+                    state.output_source_manager = Some(proxy.bind(name, version, qhandle, ()));
+                } else if interface == WlSeat::interface().name && state.seat.is_none() {
+                    state.seat = Some(proxy.bind(name, version, qhandle, ()));
                 }
             }
-            _ => (),
-        }
-    }
-}
-
-impl Dispatch<WlOutput, (), Self> for State {
-    fn event(
-        state: &mut Self,
-        proxy: &WlOutput,
-        event: <WlOutput as wayland_client::Proxy>::Event,
-        _data: &(),
-        _conn: &Connection,
-        _qhandle: &wayland_client::QueueHandle<Self>,
-    ) {
-        match event {
-            wl_output::Event::Name { name } => {
-                state.all_outputs[proxy].borrow_mut().name = Some(name);
-            }
-            wl_output::Event::Mode { width, height, .. } => {
-                state.all_outputs[proxy].borrow_mut().width = Some(width);
-                state.all_outputs[proxy].borrow_mut().height = Some(height);
+            wl_registry::Event::GlobalRemove { name } => {
+                // An output has been unplugged (or disabled)
+                state.all_outputs.retain(|output, info| {
+                    if info.global == name {
+                        if output.version() >= 3 {
+                            output.release();
+                        }
+                        false
+                    } else {
+                        true
+                    }
+                });
             }
             _ => (),
         }
@@ -312,9 +1152,28 @@ impl Dispatch<ZwlrScreencopyFrameV1, (), Self> for State {
                 state.buf_height = height;
             }
             zwlr_screencopy_frame_v1::Event::Failed => {
-                panic!("Capture failed");
+                // wlr-screencopy does not tell the reason
+                state.failed = Some(WEnum::Value(FailureReason::Unknown));
+            }
+            zwlr_screencopy_frame_v1::Event::Damage {
+                x,
+                y,
+                width,
+                height,
+            } => {
+                state.damage.push(DamageRect {
+                    x,
+                    y,
+                    width,
+                    height,
+                });
             }
-            zwlr_screencopy_frame_v1::Event::Ready { .. } => {
+            zwlr_screencopy_frame_v1::Event::Ready {
+                tv_sec_hi,
+                tv_sec_lo,
+                tv_nsec,
+            } => {
+                state.presented = Some(timestamp(tv_sec_hi, tv_sec_lo, tv_nsec));
                 state.ready = true;
             }
             _ => (),
@@ -322,11 +1181,11 @@ impl Dispatch<ZwlrScreencopyFrameV1, (), Self> for State {
     }
 }
 
-impl Dispatch<ZwpLinuxDmabufV1, ()> for State {
+impl Dispatch<ZwpLinuxBufferParamsV1, ()> for State {
     fn event(
         _state: &mut Self,
-        _proxy: &ZwpLinuxDmabufV1,
-        _event: <ZwpLinuxDmabufV1 as Proxy>::Event,
+        _proxy: &ZwpLinuxBufferParamsV1,
+        _event: <ZwpLinuxBufferParamsV1 as Proxy>::Event,
         _data: &(),
         _conn: &Connection,
         _qhandle: &wayland_client::QueueHandle<Self>,
@@ -334,32 +1193,32 @@ impl Dispatch<ZwpLinuxDmabufV1, ()> for State {
     }
 }
 
-impl Dispatch<ZwpLinuxBufferParamsV1, ()> for State {
+impl Dispatch<WlBuffer, usize> for State {
     fn event(
-        _state: &mut Self,
-        _proxy: &ZwpLinuxBufferParamsV1,
-        _event: <ZwpLinuxBufferParamsV1 as Proxy>::Event,
-        _data: &(),
+        state: &mut Self,
+        _proxy: &WlBuffer,
+        event: <WlBuffer as Proxy>::Event,
+        index: &usize,
         _conn: &Connection,
         _qhandle: &wayland_client::QueueHandle<Self>,
     ) {
+        if let wl_buffer::Event::Release = event {
+            state.buffer_busy[*index] = false;
+        }
     }
 }
 
-impl Dispatch<WlBuffer, ()> for State {
+impl Dispatch<WlCallback, ()> for State {
     fn event(
         state: &mut Self,
-        _proxy: &WlBuffer,
-        event: <WlBuffer as Proxy>::Event,
+        _proxy: &WlCallback,
+        event: <WlCallback as Proxy>::Event,
         _data: &(),
         _conn: &Connection,
         _qhandle: &wayland_client::QueueHandle<Self>,
     ) {
-        match event {
-            wl_buffer::Event::Release => {
-                state.released = true;
-            }
-            _ => (),
+        if let wl_callback::Event::Done { .. } = event {
+            state.woken = true;
         }
     }
 }
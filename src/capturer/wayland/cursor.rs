@@ -0,0 +1,200 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Capture of the mouse cursor image and position, using cursor sessions of ext-image-copy-capture
+
+use std::sync::{Arc, Mutex};
+
+use drm_fourcc::DrmFourcc;
+use glium::{glutin::surface::WindowSurface, Display};
+use wayland_client::{
+    protocol::{wl_pointer::WlPointer, wl_seat::WlSeat},
+    Connection, Dispatch, Proxy,
+};
+use wayland_protocols::ext::{
+    image_capture_source::v1::client::ext_output_image_capture_source_manager_v1::ExtOutputImageCaptureSourceManagerV1,
+    image_copy_capture::v1::client::ext_image_copy_capture_cursor_session_v1::{
+        self, ExtImageCopyCaptureCursorSessionV1,
+    },
+};
+
+use super::{connect, CaptureThread, OutputTarget, Source, State, WaylandCapturer};
+use crate::{
+    capturer::{limiter::FrameLimiter, CaptureError, CapturedFrame, Capturer},
+    config::{Allocator, OutputMatch},
+};
+
+// Cursor state shared between the capture thread and the renderer
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CursorState {
+    inside: bool, // Whether the cursor is on the captured output
+    position: (i32, i32),
+    hotspot: (i32, i32),
+}
+
+pub struct CursorCapturer {
+    capturer: WaylandCapturer,
+    state: Arc<Mutex<CursorState>>,
+}
+
+pub struct CursorFrame {
+    pub image: CapturedFrame,
+    pub position: (i32, i32), // Position of the hotspot in buffer coordinates of the output
+    pub hotspot: (i32, i32),  // Hotspot in the cursor image
+}
+
+impl CursorCapturer {
+    pub fn new(
+        glium_display: Arc<Display<WindowSurface>>,
+        output: Option<&OutputMatch>,
+        allocator: Allocator,
+    ) -> Result<Self, CaptureError> {
+        let (conn, queue, state) = connect()?;
+
+        if state.seat.is_none()
+            || state.output_source_manager.is_none()
+            || state.image_copy_manager.is_none()
+        {
+            return Err(CaptureError::Unsupported(
+                "the compositor does not support cursor capture (ext-image-copy-capture)"
+                    .to_owned(),
+            ));
+        }
+
+        let cursor_state = Arc::clone(&state.cursor);
+        // Cursor images are small, and changes of its position do not need new frames
+        let capturer = WaylandCapturer::spawn(
+            glium_display,
+            conn,
+            queue,
+            state,
+            Source::Cursor(OutputTarget::new(output)),
+            FrameLimiter::new(None),
+            allocator,
+        )?;
+
+        Ok(Self {
+            capturer,
+            state: cursor_state,
+        })
+    }
+
+    // Returns None if the cursor is not on the output
+    pub fn capture(&mut self) -> Result<Option<CursorFrame>, CaptureError> {
+        let image = self.capturer.capture()?;
+        let state = *self.state.lock().unwrap();
+        if !state.inside {
+            return Ok(None);
+        }
+
+        Ok(Some(CursorFrame {
+            image,
+            position: state.position,
+            hotspot: state.hotspot,
+        }))
+    }
+}
+
+impl CaptureThread {
+    pub(super) fn capture_cursor_frame(&mut self) -> Result<bool, CaptureError> {
+        if self.session.is_none() {
+            let Some(output) = self.wait_for_output()? else {
+                return Ok(false);
+            };
+
+            let qhandle = self.queue.handle();
+            let source = self
+                .state
+                .output_source_manager
+                .as_ref()
+                .unwrap()
+                .create_source(&output, &qhandle, ());
+            let pointer = self.state.seat.as_ref().unwrap().get_pointer(&qhandle, ());
+            let cursor_session = self
+                .state
+                .image_copy_manager
+                .as_ref()
+                .unwrap()
+                .create_pointer_cursor_session(&source, &pointer, &qhandle, ());
+            source.destroy();
+
+            self.state.session = Default::default();
+            self.session = Some(cursor_session.get_capture_session(&qhandle, ()));
+            if let Some(old) = self.cursor_session.replace((cursor_session, pointer)) {
+                old.0.destroy();
+                old.1.release();
+            }
+        }
+
+        // Cursor images have alpha channel
+        self.capture_session_frame(
+            &[DrmFourcc::Argb8888, DrmFourcc::Abgr8888],
+            "Cursor capture stopped",
+        )
+    }
+}
+
+impl Dispatch<ExtImageCopyCaptureCursorSessionV1, ()> for State {
+    fn event(
+        state: &mut Self,
+        _proxy: &ExtImageCopyCaptureCursorSessionV1,
+        event: <ExtImageCopyCaptureCursorSessionV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &wayland_client::QueueHandle<Self>,
+    ) {
+        let mut cursor = state.cursor.lock().unwrap();
+        match event {
+            ext_image_copy_capture_cursor_session_v1::Event::Enter => {
+                cursor.inside = true;
+            }
+            ext_image_copy_capture_cursor_session_v1::Event::Leave => {
+                cursor.inside = false;
+            }
+            ext_image_copy_capture_cursor_session_v1::Event::Position { x, y } => {
+                cursor.position = (x, y);
+            }
+            ext_image_copy_capture_cursor_session_v1::Event::Hotspot { x, y } => {
+                cursor.hotspot = (x, y);
+            }
+            _ => (),
+        }
+    }
+}
+
+impl Dispatch<ExtOutputImageCaptureSourceManagerV1, ()> for State {
+    fn event(
+        _state: &mut Self,
+        _proxy: &ExtOutputImageCaptureSourceManagerV1,
+        _event: <ExtOutputImageCaptureSourceManagerV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &wayland_client::QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl Dispatch<WlSeat, ()> for State {
+    fn event(
+        _state: &mut Self,
+        _proxy: &WlSeat,
+        _event: <WlSeat as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &wayland_client::QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl Dispatch<WlPointer, ()> for State {
+    fn event(
+        _state: &mut Self,
+        _proxy: &WlPointer,
+        _event: <WlPointer as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &wayland_client::QueueHandle<Self>,
+    ) {
+    }
+}
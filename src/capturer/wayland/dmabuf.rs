@@ -0,0 +1,258 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Negotiation of DMA-BUF formats and modifiers between the compositor and the GPU of the renderer
+
+use std::{
+    collections::HashMap,
+    fmt,
+    os::fd::{AsRawFd, OwnedFd},
+};
+
+use drm_fourcc::{DrmFourcc, DrmModifier};
+use wayland_client::{Connection, Dispatch, Proxy, QueueHandle};
+use wayland_protocols::wp::linux_dmabuf::zv1::client::{
+    zwp_linux_dmabuf_feedback_v1::{self, ZwpLinuxDmabufFeedbackV1},
+    zwp_linux_dmabuf_v1::{self, ZwpLinuxDmabufV1},
+};
+
+use super::{CaptureError, State};
+
+// Buffer format the compositor can write. `modifiers` is None if the compositor does not tell them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BufferFormat {
+    pub fourcc: DrmFourcc,
+    pub modifiers: Option<Vec<DrmModifier>>,
+}
+
+// Buffer to allocate with GBM
+#[derive(Debug)]
+pub struct Allocation {
+    pub buffer: DrmFourcc,  // Format written by the compositor
+    pub texture: DrmFourcc, // Format imported into EGL
+    pub modifiers: Vec<DrmModifier>,
+}
+
+// Modifiers the compositor can import for each format, from linux-dmabuf
+#[derive(Debug, Default)]
+pub struct DmabufFormats {
+    modifiers: HashMap<u32, Vec<DrmModifier>>,
+    pending: HashMap<u32, Vec<DrmModifier>>, // Until `zwp_linux_dmabuf_feedback_v1::done`
+    table: Vec<(u32, u64)>,                  // Format table of the feedback
+}
+
+impl BufferFormat {
+    fn accepts(&self, modifier: DrmModifier) -> bool {
+        self.modifiers
+            .as_ref()
+            .is_none_or(|modifiers| modifiers.contains(&modifier))
+    }
+}
+
+impl fmt::Display for BufferFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.fourcc)?;
+        if let Some(ref modifiers) = self.modifiers {
+            write!(f, " {:?}", modifiers)?;
+        }
+        Ok(())
+    }
+}
+
+impl State {
+    // Buffer format requested by wlr-screencopy, with modifiers from linux-dmabuf
+    pub(super) fn buffer_format(&self, format: u32) -> Result<BufferFormat, CaptureError> {
+        let fourcc = DrmFourcc::try_from(format).map_err(|_| {
+            CaptureError::Unsupported(format!("buffer format {:#x} requested", format))
+        })?;
+        Ok(BufferFormat {
+            fourcc,
+            modifiers: self.dmabuf_formats.modifiers.get(&format).cloned(),
+        })
+    }
+}
+
+// Choose a format the compositor writes into a texture exported as `fourcc` with `modifier`.
+// Textures are always sampled with red and blue swapped (see shader.frag), so the compositor has
+// to write blue where the texture has red, e.g. XRGB8888 into ABGR8888.
+pub(super) fn choose_format(
+    fourcc: DrmFourcc,
+    modifier: DrmModifier,
+    formats: &[BufferFormat],
+) -> Result<DrmFourcc, CaptureError> {
+    formats
+        .iter()
+        .find(|format| swaps_red_blue(fourcc, format.fourcc) && format.accepts(modifier))
+        .map(|format| format.fourcc)
+        .ok_or_else(|| {
+            let offered: Vec<String> = formats.iter().map(BufferFormat::to_string).collect();
+            CaptureError::Unsupported(format!(
+                "DMA-BUF format {:?} with modifier {:?} of the GPU (the compositor offers {})",
+                fourcc,
+                modifier,
+                offered.join(", ")
+            ))
+        })
+}
+
+// Choose a format and modifiers to allocate, which the compositor writes and EGL imports as the format
+// with red and blue swapped (for the same reason as `choose_format`). `importable` returns modifiers
+// EGL supports for a format, as `texture::importable_modifiers`.
+pub(super) fn choose_allocation(
+    formats: &[BufferFormat],
+    importable: impl Fn(DrmFourcc) -> Option<Vec<DrmModifier>>,
+) -> Result<Allocation, CaptureError> {
+    for format in formats {
+        let Some(texture) = red_blue_swapped(format.fourcc) else {
+            continue;
+        };
+        let Some(mut modifiers) = importable(texture) else {
+            continue;
+        };
+        // The implicit layout of the driver can always be imported, but explicit ones are preferred
+        modifiers.push(DrmModifier::Invalid);
+        modifiers.retain(|&modifier| format.accepts(modifier));
+        if modifiers.len() > 1 {
+            modifiers.retain(|&modifier| modifier != DrmModifier::Invalid);
+        }
+        if !modifiers.is_empty() {
+            return Ok(Allocation {
+                buffer: format.fourcc,
+                texture,
+                modifiers,
+            });
+        }
+    }
+
+    let offered: Vec<String> = formats.iter().map(BufferFormat::to_string).collect();
+    Err(CaptureError::Unsupported(format!(
+        "no buffer format EGL can import among ones the compositor offers ({})",
+        offered.join(", ")
+    )))
+}
+
+fn red_blue_swapped(format: DrmFourcc) -> Option<DrmFourcc> {
+    use DrmFourcc::*;
+    match format {
+        Xrgb8888 => Some(Xbgr8888),
+        Argb8888 => Some(Abgr8888),
+        Xbgr8888 => Some(Xrgb8888),
+        Abgr8888 => Some(Argb8888),
+        _ => None,
+    }
+}
+
+fn swaps_red_blue(texture: DrmFourcc, buffer: DrmFourcc) -> bool {
+    use DrmFourcc::*;
+    match texture {
+        Abgr8888 | Xbgr8888 => matches!(buffer, Argb8888 | Xrgb8888),
+        Argb8888 | Xrgb8888 => matches!(buffer, Abgr8888 | Xbgr8888),
+        _ => false,
+    }
+}
+
+// Array of native-endian u64 in protocol events
+pub(super) fn modifiers_from_array(array: &[u8]) -> Vec<DrmModifier> {
+    array
+        .chunks_exact(8)
+        .map(|bytes| u64::from_ne_bytes(bytes.try_into().unwrap()).into())
+        .collect()
+}
+
+// Pairs of format and modifier (16 bytes each) in the shared memory of the feedback
+fn read_format_table(fd: &OwnedFd, size: u32) -> Vec<(u32, u64)> {
+    let size = size as usize;
+    let ptr = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            size,
+            libc::PROT_READ,
+            libc::MAP_PRIVATE,
+            fd.as_raw_fd(),
+            0,
+        )
+    };
+    if ptr == libc::MAP_FAILED {
+        log::warn!("Cannot map DMA-BUF format table");
+        return Vec::new();
+    }
+    let bytes = unsafe { std::slice::from_raw_parts(ptr as *const u8, size) };
+    let table = bytes
+        .chunks_exact(16)
+        .map(|entry| {
+            (
+                u32::from_ne_bytes(entry[0..4].try_into().unwrap()),
+                u64::from_ne_bytes(entry[8..16].try_into().unwrap()),
+            )
+        })
+        .collect();
+    unsafe { libc::munmap(ptr, size) };
+    table
+}
+
+impl Dispatch<ZwpLinuxDmabufV1, ()> for State {
+    fn event(
+        state: &mut Self,
+        _proxy: &ZwpLinuxDmabufV1,
+        event: <ZwpLinuxDmabufV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        // Only sent before version 4
+        if let zwp_linux_dmabuf_v1::Event::Modifier {
+            format,
+            modifier_hi,
+            modifier_lo,
+        } = event
+        {
+            let modifier = ((modifier_hi as u64) << 32) | modifier_lo as u64;
+            state
+                .dmabuf_formats
+                .modifiers
+                .entry(format)
+                .or_default()
+                .push(modifier.into());
+        }
+    }
+}
+
+impl Dispatch<ZwpLinuxDmabufFeedbackV1, ()> for State {
+    fn event(
+        state: &mut Self,
+        _proxy: &ZwpLinuxDmabufFeedbackV1,
+        event: <ZwpLinuxDmabufFeedbackV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        let formats = &mut state.dmabuf_formats;
+        match event {
+            zwp_linux_dmabuf_feedback_v1::Event::FormatTable { fd, size } => {
+                formats.table = read_format_table(&fd, size);
+            }
+            zwp_linux_dmabuf_feedback_v1::Event::TrancheFormats { indices } => {
+                // Tranches are in order of preference, but any of them can be imported
+                for index in indices.chunks_exact(2) {
+                    let index = u16::from_ne_bytes([index[0], index[1]]) as usize;
+                    if let Some(&(format, modifier)) = formats.table.get(index) {
+                        formats
+                            .pending
+                            .entry(format)
+                            .or_default()
+                            .push(modifier.into());
+                    }
+                }
+            }
+            zwp_linux_dmabuf_feedback_v1::Event::Done => {
+                formats.modifiers = std::mem::take(&mut formats.pending);
+                log::debug!(
+                    "DMA-BUF modifiers of the compositor: {:?}",
+                    formats.modifiers
+                );
+            }
+            _ => (),
+        }
+    }
+}
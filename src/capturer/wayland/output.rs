@@ -0,0 +1,233 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Properties of outputs, collected from wl_output, xdg-output and wlr-output-management (for serial numbers)
+
+use wayland_client::{
+    event_created_child,
+    protocol::wl_output::{self, WlOutput},
+    Connection, Dispatch, Proxy, QueueHandle,
+};
+use wayland_protocols::xdg::xdg_output::zv1::client::{
+    zxdg_output_manager_v1::ZxdgOutputManagerV1,
+    zxdg_output_v1::{self, ZxdgOutputV1},
+};
+use wayland_protocols_wlr::output_management::v1::client::{
+    zwlr_output_head_v1::{self, ZwlrOutputHeadV1},
+    zwlr_output_manager_v1::{self, ZwlrOutputManagerV1},
+    zwlr_output_mode_v1::ZwlrOutputModeV1,
+};
+
+use super::{connect, CaptureError, State};
+use crate::{config::OutputMatch, output_match::OutputProperties};
+
+#[derive(Clone, Debug)]
+pub struct OutputInfo {
+    pub properties: OutputProperties,
+    pub global: u32, // Name of the global in the registry
+}
+
+impl State {
+    // Bind a new wl_output global
+    pub(super) fn add_output(
+        &mut self,
+        output: WlOutput,
+        global: u32,
+        qhandle: &QueueHandle<Self>,
+    ) {
+        if let Some(ref manager) = self.xdg_output_manager {
+            manager.get_xdg_output(&output, qhandle, output.clone());
+        }
+        self.all_outputs.insert(
+            output,
+            OutputInfo {
+                properties: OutputProperties::default(),
+                global,
+            },
+        );
+    }
+
+    // xdg-output may be announced after outputs
+    pub(super) fn set_xdg_output_manager(
+        &mut self,
+        manager: ZxdgOutputManagerV1,
+        qhandle: &QueueHandle<Self>,
+    ) {
+        for output in self.all_outputs.keys() {
+            manager.get_xdg_output(output, qhandle, output.clone());
+        }
+        self.xdg_output_manager = Some(manager);
+    }
+
+    // Properties of an output. Those wl_output lacks are filled from wlr-output-management.
+    pub(super) fn output_properties(&self, info: &OutputInfo) -> OutputProperties {
+        let mut properties = info.properties.clone();
+        let head = self
+            .heads
+            .values()
+            .find(|head| head.name.is_some() && head.name == properties.name);
+        if let Some(head) = head {
+            properties.description = properties.description.or(head.description.clone());
+            properties.make = properties.make.or(head.make.clone());
+            properties.model = properties.model.or(head.model.clone());
+            properties.serial = properties.serial.or(head.serial.clone());
+        }
+        properties
+    }
+}
+
+// (3) Select output.
+pub(super) fn find_output(state: &State, selector: Option<&OutputMatch>) -> Option<WlOutput> {
+    state
+        .all_outputs
+        .iter()
+        .find(|(_, info)| {
+            selector.is_none_or(|selector| selector.matches(&state.output_properties(info)))
+        })
+        .map(|(output, _)| output.clone())
+}
+
+// Properties of all outputs, e.g. to find the glasses before opening a window
+pub fn list_outputs() -> Result<Vec<OutputProperties>, CaptureError> {
+    let (_conn, _queue, state) = connect()?;
+    Ok(state
+        .all_outputs
+        .values()
+        .map(|info| state.output_properties(info))
+        .collect())
+}
+
+impl Dispatch<WlOutput, ()> for State {
+    fn event(
+        state: &mut Self,
+        proxy: &WlOutput,
+        event: <WlOutput as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        let Some(info) = state.all_outputs.get_mut(proxy) else {
+            return;
+        };
+        let properties = &mut info.properties;
+        match event {
+            wl_output::Event::Name { name } => properties.name = Some(name),
+            wl_output::Event::Description { description } => {
+                properties.description = Some(description)
+            }
+            wl_output::Event::Geometry { make, model, .. } => {
+                properties.make = Some(make);
+                properties.model = Some(model);
+            }
+            _ => (),
+        }
+    }
+}
+
+impl Dispatch<ZxdgOutputManagerV1, ()> for State {
+    fn event(
+        _state: &mut Self,
+        _proxy: &ZxdgOutputManagerV1,
+        _event: <ZxdgOutputManagerV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+// Name and description for compositors with old wl_output
+impl Dispatch<ZxdgOutputV1, WlOutput> for State {
+    fn event(
+        state: &mut Self,
+        _proxy: &ZxdgOutputV1,
+        event: <ZxdgOutputV1 as Proxy>::Event,
+        output: &WlOutput,
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        let Some(info) = state.all_outputs.get_mut(output) else {
+            return;
+        };
+        let properties = &mut info.properties;
+        match event {
+            zxdg_output_v1::Event::Name { name } => {
+                properties.name.get_or_insert(name);
+            }
+            zxdg_output_v1::Event::Description { description } => {
+                properties.description.get_or_insert(description);
+            }
+            _ => (),
+        }
+    }
+}
+
+impl Dispatch<ZwlrOutputManagerV1, ()> for State {
+    fn event(
+        state: &mut Self,
+        _proxy: &ZwlrOutputManagerV1,
+        event: <ZwlrOutputManagerV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        if let zwlr_output_manager_v1::Event::Head { head } = event {
+            state.heads.insert(head, OutputProperties::default());
+        }
+    }
+
+    event_created_child!(State, ZwlrOutputManagerV1, [
+        zwlr_output_manager_v1::EVT_HEAD_OPCODE => (ZwlrOutputHeadV1, ()),
+    ]);
+}
+
+impl Dispatch<ZwlrOutputHeadV1, ()> for State {
+    fn event(
+        state: &mut Self,
+        proxy: &ZwlrOutputHeadV1,
+        event: <ZwlrOutputHeadV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        let Some(head) = state.heads.get_mut(proxy) else {
+            return;
+        };
+        match event {
+            zwlr_output_head_v1::Event::Name { name } => head.name = Some(name),
+            zwlr_output_head_v1::Event::Description { description } => {
+                head.description = Some(description)
+            }
+            zwlr_output_head_v1::Event::Make { make } => head.make = Some(make),
+            zwlr_output_head_v1::Event::Model { model } => head.model = Some(model),
+            zwlr_output_head_v1::Event::SerialNumber { serial_number } => {
+                head.serial = Some(serial_number)
+            }
+            zwlr_output_head_v1::Event::Finished => {
+                state.heads.remove(proxy);
+                if proxy.version() >= 3 {
+                    proxy.release();
+                }
+            }
+            _ => (),
+        }
+    }
+
+    event_created_child!(State, ZwlrOutputHeadV1, [
+        zwlr_output_head_v1::EVT_MODE_OPCODE => (ZwlrOutputModeV1, ()),
+    ]);
+}
+
+// Modes are not used, but have to be handled
+impl Dispatch<ZwlrOutputModeV1, ()> for State {
+    fn event(
+        _state: &mut Self,
+        _proxy: &ZwlrOutputModeV1,
+        _event: <ZwlrOutputModeV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+    }
+}
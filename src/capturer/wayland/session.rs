@@ -0,0 +1,285 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Capture using ext-image-copy-capture, common to all kinds of capture sources
+
+use drm_fourcc::{DrmFourcc, DrmModifier};
+use wayland_client::{Connection, Dispatch, Proxy, WEnum};
+use wayland_protocols::ext::{
+    image_capture_source::v1::client::ext_image_capture_source_v1::ExtImageCaptureSourceV1,
+    image_copy_capture::v1::client::{
+        ext_image_copy_capture_frame_v1::{self, ExtImageCopyCaptureFrameV1, FailureReason},
+        ext_image_copy_capture_manager_v1::{ExtImageCopyCaptureManagerV1, Options},
+        ext_image_copy_capture_session_v1::{self, ExtImageCopyCaptureSessionV1},
+    },
+};
+
+use super::{
+    dmabuf::{modifiers_from_array, BufferFormat},
+    timestamp, CaptureError, CaptureThread, DamageRect, Notification, State,
+};
+
+// Damaged regions of a buffer are merged into one rectangle beyond this
+const MAX_STALE_RECTS: usize = 16;
+
+// Buffer constraints of the current capture session
+#[derive(Clone, Debug, Default)]
+pub struct SessionState {
+    width: u32,
+    height: u32,
+    formats: Vec<(u32, Vec<DrmModifier>)>,
+    pending_formats: Vec<(u32, Vec<DrmModifier>)>,
+    done: bool,
+    stopped: bool,
+    started: bool, // Whether the buffers have been marked stale for this session
+}
+
+impl CaptureThread {
+    pub(super) fn start_session(&mut self, source: &ExtImageCaptureSourceV1, options: Options) {
+        self.state.session = SessionState::default();
+        self.session = Some(
+            self.state
+                .image_copy_manager
+                .as_ref()
+                .unwrap()
+                .create_session(source, options, &self.queue.handle(), ()),
+        );
+    }
+
+    // Capture one frame of the current session into a free buffer, using one of `formats` the compositor
+    // supports. Returns false when stop is requested.
+    pub(super) fn capture_session_frame(
+        &mut self,
+        formats: &[DrmFourcc],
+        lost_message: &str,
+    ) -> Result<bool, CaptureError> {
+        // Wait for buffer constraints
+        while !self.state.session.done && !self.state.session.stopped {
+            self.queue.blocking_dispatch(&mut self.state)?;
+            if self.state.woken && !self.handle_commands() {
+                return Ok(false);
+            }
+        }
+        if self.state.session.stopped {
+            // A new session is started in the next call
+            log::info!("{}", lost_message);
+            self.session.take().unwrap().destroy();
+            return Ok(self
+                .notification_sender
+                .send(Notification::SourceLost)
+                .is_ok());
+        }
+
+        // Modifiers are sent for each format, and are negotiated with the texture of the renderer
+        let offered: Vec<BufferFormat> = self
+            .state
+            .session
+            .formats
+            .iter()
+            .filter_map(|(format, modifiers)| {
+                Some(BufferFormat {
+                    fourcc: DrmFourcc::try_from(*format)
+                        .ok()
+                        .filter(|fourcc| formats.contains(fourcc))?,
+                    modifiers: (!modifiers.is_empty()).then(|| modifiers.clone()),
+                })
+            })
+            .collect();
+        if offered.is_empty() {
+            let offered: Vec<String> = self
+                .state
+                .session
+                .formats
+                .iter()
+                .map(|(format, _)| format!("{:#x}", format))
+                .collect();
+            return Err(CaptureError::Unsupported(format!(
+                "buffer formats {} offered by the compositor",
+                offered.join(", ")
+            )));
+        }
+        let (width, height) = (self.state.session.width, self.state.session.height);
+        if !self.prepare_buffers((width, height, offered))? {
+            return Ok(false);
+        }
+        // Damage reported by a new session is not relative to what the buffers contain
+        if !self.state.session.started {
+            self.state.session.started = true;
+            self.stale.fill(vec![DamageRect::full(width, height)]);
+        }
+
+        // Keep the capture frame rate limit
+        if !self.pause(self.limiter.remaining()) {
+            return Ok(false);
+        }
+        let Some(index) = self.wait_for_buffer()? else {
+            return Ok(false);
+        };
+
+        let frame = self
+            .session
+            .as_ref()
+            .unwrap()
+            .create_frame(&self.queue.handle(), ());
+        frame.attach_buffer(&self.buffers[index]);
+        // Buffers are rotated, so the compositor cannot know what each buffer already contains
+        for rect in &self.stale[index] {
+            frame.damage_buffer(
+                rect.x as i32,
+                rect.y as i32,
+                rect.width as i32,
+                rect.height as i32,
+            );
+        }
+        frame.capture();
+        let finished = self.wait_for_frame()?;
+        frame.destroy();
+        self.queue.flush()?;
+
+        if !finished {
+            return Ok(false);
+        }
+
+        let failed = self.state.failed.take();
+        if failed.is_some() {
+            // Contents of the buffer are undefined after a failure
+            self.stale[index] = vec![DamageRect::full(width, height)];
+        }
+        match failed {
+            None => {
+                self.mark_filled(index);
+                Ok(self.send_frame(index))
+            }
+            Some(WEnum::Value(FailureReason::BufferConstraints)) => {
+                // New constraints arrive before the failure, so just try again
+                self.state.damage.clear();
+                Ok(true)
+            }
+            Some(WEnum::Value(FailureReason::Stopped)) => {
+                self.state.session.stopped = true;
+                self.state.damage.clear();
+                Ok(true)
+            }
+            Some(reason) => {
+                // Recreate the session in the next call
+                self.session.take().unwrap().destroy();
+                Ok(self.retry(&format!("Capture failed ({:?})", reason)))
+            }
+        }
+    }
+
+    // Buffer `index` now has the latest frame, and the other buffers miss its damage
+    fn mark_filled(&mut self, index: usize) {
+        let damage = &self.state.damage;
+        for (i, stale) in self.stale.iter_mut().enumerate() {
+            if i == index {
+                stale.clear();
+                continue;
+            }
+            stale.extend_from_slice(damage);
+            if stale.len() > MAX_STALE_RECTS {
+                let bounds = stale.iter().copied().reduce(DamageRect::union).unwrap();
+                *stale = vec![bounds];
+            }
+        }
+    }
+}
+
+impl Dispatch<ExtImageCaptureSourceV1, ()> for State {
+    fn event(
+        _state: &mut Self,
+        _proxy: &ExtImageCaptureSourceV1,
+        _event: <ExtImageCaptureSourceV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &wayland_client::QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl Dispatch<ExtImageCopyCaptureManagerV1, ()> for State {
+    fn event(
+        _state: &mut Self,
+        _proxy: &ExtImageCopyCaptureManagerV1,
+        _event: <ExtImageCopyCaptureManagerV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &wayland_client::QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl Dispatch<ExtImageCopyCaptureSessionV1, ()> for State {
+    fn event(
+        state: &mut Self,
+        _proxy: &ExtImageCopyCaptureSessionV1,
+        event: <ExtImageCopyCaptureSessionV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &wayland_client::QueueHandle<Self>,
+    ) {
+        let session = &mut state.session;
+        match event {
+            ext_image_copy_capture_session_v1::Event::BufferSize { width, height } => {
+                session.width = width;
+                session.height = height;
+            }
+            ext_image_copy_capture_session_v1::Event::DmabufFormat { format, modifiers } => {
+                session
+                    .pending_formats
+                    .push((format, modifiers_from_array(&modifiers)));
+            }
+            ext_image_copy_capture_session_v1::Event::Done => {
+                // Constraints are always sent as a whole batch
+                session.formats = std::mem::take(&mut session.pending_formats);
+                session.done = true;
+            }
+            ext_image_copy_capture_session_v1::Event::Stopped => {
+                session.stopped = true;
+            }
+            _ => (),
+        }
+    }
+}
+
+impl Dispatch<ExtImageCopyCaptureFrameV1, ()> for State {
+    fn event(
+        state: &mut Self,
+        _proxy: &ExtImageCopyCaptureFrameV1,
+        event: <ExtImageCopyCaptureFrameV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &wayland_client::QueueHandle<Self>,
+    ) {
+        match event {
+            ext_image_copy_capture_frame_v1::Event::Damage {
+                x,
+                y,
+                width,
+                height,
+            } => {
+                state.damage.push(DamageRect {
+                    x: x as u32,
+                    y: y as u32,
+                    width: width as u32,
+                    height: height as u32,
+                });
+            }
+            ext_image_copy_capture_frame_v1::Event::PresentationTime {
+                tv_sec_hi,
+                tv_sec_lo,
+                tv_nsec,
+            } => {
+                state.presented = Some(timestamp(tv_sec_hi, tv_sec_lo, tv_nsec));
+            }
+            ext_image_copy_capture_frame_v1::Event::Ready => {
+                state.ready = true;
+            }
+            ext_image_copy_capture_frame_v1::Event::Failed { reason } => {
+                state.failed = Some(reason);
+            }
+            _ => (),
+        }
+    }
+}
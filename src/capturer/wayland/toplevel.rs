@@ -0,0 +1,177 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Window capture using ext-foreign-toplevel-list and ext-foreign-toplevel-image-capture-source
+
+use drm_fourcc::DrmFourcc;
+use wayland_client::{event_created_child, Connection, Dispatch, Proxy};
+use wayland_protocols::ext::{
+    foreign_toplevel_list::v1::client::{
+        ext_foreign_toplevel_handle_v1::{self, ExtForeignToplevelHandleV1},
+        ext_foreign_toplevel_list_v1::{self, ExtForeignToplevelListV1},
+    },
+    image_capture_source::v1::client::ext_foreign_toplevel_image_capture_source_manager_v1::ExtForeignToplevelImageCaptureSourceManagerV1,
+    image_copy_capture::v1::client::ext_image_copy_capture_manager_v1::Options,
+};
+
+use super::{CaptureError, CaptureThread, Source, State};
+
+// Which window to capture
+pub struct ToplevelFilter {
+    pub app_id: Option<String>,
+    pub title: Option<String>, // Substring of the title
+}
+
+impl ToplevelFilter {
+    fn matches(&self, info: &ToplevelInfo) -> bool {
+        let app_id_matches = match self.app_id {
+            Some(ref app_id) => info.app_id.as_ref() == Some(app_id),
+            None => true,
+        };
+        let title_matches = match self.title {
+            Some(ref title) => info
+                .title
+                .as_ref()
+                .is_some_and(|info_title| info_title.contains(title.as_str())),
+            None => true,
+        };
+        app_id_matches && title_matches
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ToplevelInfo {
+    app_id: Option<String>,
+    title: Option<String>,
+    done: bool, // Whether the initial properties have been received
+}
+
+impl CaptureThread {
+    pub(super) fn capture_toplevel_frame(&mut self) -> Result<bool, CaptureError> {
+        if self.session.is_none() {
+            let Some(handle) = self.wait_for_toplevel()? else {
+                return Ok(false);
+            };
+
+            let source = self
+                .state
+                .toplevel_source_manager
+                .as_ref()
+                .unwrap()
+                .create_source(&handle, &self.queue.handle(), ());
+            self.start_session(&source, Options::PaintCursors);
+            source.destroy();
+        }
+
+        self.capture_session_frame(
+            &[
+                DrmFourcc::Xrgb8888,
+                DrmFourcc::Argb8888,
+                DrmFourcc::Xbgr8888,
+                DrmFourcc::Abgr8888,
+            ],
+            "Captured window has gone",
+        )
+    }
+
+    // Wait until a window matching the filter exists. Returns None when stop is requested.
+    fn wait_for_toplevel(&mut self) -> Result<Option<ExtForeignToplevelHandleV1>, CaptureError> {
+        let mut waiting_logged = false;
+        loop {
+            let Source::Toplevel(ref filter) = self.source else {
+                unreachable!()
+            };
+
+            let found = self
+                .state
+                .toplevels
+                .iter()
+                .find(|(_, info)| info.done && filter.matches(info));
+            if let Some((handle, info)) = found {
+                log::info!(
+                    "Capturing window {} ({})",
+                    info.title.as_deref().unwrap_or_default(),
+                    info.app_id.as_deref().unwrap_or_default()
+                );
+                return Ok(Some(handle.clone()));
+            }
+
+            if !waiting_logged {
+                log::info!(
+                    "Waiting for a window (app_id: {:?}, title: {:?})",
+                    filter.app_id,
+                    filter.title
+                );
+                waiting_logged = true;
+            }
+
+            self.queue.blocking_dispatch(&mut self.state)?;
+            if self.state.woken && !self.handle_commands() {
+                return Ok(None);
+            }
+        }
+    }
+}
+
+impl Dispatch<ExtForeignToplevelListV1, ()> for State {
+    fn event(
+        state: &mut Self,
+        _proxy: &ExtForeignToplevelListV1,
+        event: <ExtForeignToplevelListV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &wayland_client::QueueHandle<Self>,
+    ) {
+        if let ext_foreign_toplevel_list_v1::Event::Toplevel { toplevel } = event {
+            state.toplevels.insert(toplevel, ToplevelInfo::default());
+        }
+    }
+
+    event_created_child!(State, ExtForeignToplevelListV1, [
+        ext_foreign_toplevel_list_v1::EVT_TOPLEVEL_OPCODE => (ExtForeignToplevelHandleV1, ()),
+    ]);
+}
+
+impl Dispatch<ExtForeignToplevelHandleV1, ()> for State {
+    fn event(
+        state: &mut Self,
+        proxy: &ExtForeignToplevelHandleV1,
+        event: <ExtForeignToplevelHandleV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &wayland_client::QueueHandle<Self>,
+    ) {
+        let Some(info) = state.toplevels.get_mut(proxy) else {
+            return;
+        };
+        match event {
+            ext_foreign_toplevel_handle_v1::Event::AppId { app_id } => {
+                info.app_id = Some(app_id);
+            }
+            ext_foreign_toplevel_handle_v1::Event::Title { title } => {
+                info.title = Some(title);
+            }
+            ext_foreign_toplevel_handle_v1::Event::Done => {
+                info.done = true;
+            }
+            ext_foreign_toplevel_handle_v1::Event::Closed => {
+                state.toplevels.remove(proxy);
+                proxy.destroy();
+            }
+            _ => (),
+        }
+    }
+}
+
+impl Dispatch<ExtForeignToplevelImageCaptureSourceManagerV1, ()> for State {
+    fn event(
+        _state: &mut Self,
+        _proxy: &ExtForeignToplevelImageCaptureSourceManagerV1,
+        _event: <ExtForeignToplevelImageCaptureSourceManagerV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &wayland_client::QueueHandle<Self>,
+    ) {
+    }
+}
@@ -0,0 +1,390 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Capture of an X11 screen using MIT-SHM.
+// The Damage extension is used to capture only when the screen has changed.
+
+use std::{
+    fs::File,
+    os::unix::fs::FileExt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use glium::backend::Facade;
+use x11rb::{
+    connection::Connection,
+    errors::{ConnectionError, ReplyError, ReplyOrIdError},
+    protocol::{
+        damage::{self, ConnectionExt as _},
+        randr::ConnectionExt as _,
+        shm::{self, ConnectionExt as _},
+        xproto::{
+            AtomEnum, ClientMessageEvent, ConnectionExt as _, CreateWindowAux, EventMask,
+            ImageFormat, Window, WindowClass,
+        },
+        Event,
+    },
+    rust_connection::RustConnection,
+    COPY_DEPTH_FROM_PARENT, NONE,
+};
+
+use super::{
+    limiter::FrameLimiter,
+    memory::{MemoryFrame, MemoryTexture},
+    monotonic_now, CaptureError, CapturedFrame, Capturer, FrameTiming,
+};
+use crate::{
+    config::{OutputMatch, Region},
+    output_match::OutputProperties,
+};
+
+pub struct X11Capturer {
+    texture: MemoryTexture,
+    frame_receiver: mpsc::Receiver<MemoryFrame>,
+    error_receiver: mpsc::Receiver<CaptureError>, // Why the capture thread has stopped
+    error: Option<CaptureError>,
+    conn: Arc<RustConnection>,
+    wake_window: Window, // Receives an event to wake the capture thread
+    stop: Arc<AtomicBool>,
+    join_handle: Option<JoinHandle<()>>,
+}
+
+// Captured rectangle in root window coordinates
+#[derive(Clone, Copy, Debug)]
+struct Area {
+    x: i16,
+    y: i16,
+    width: u16,
+    height: u16,
+}
+
+struct CaptureThread {
+    conn: Arc<RustConnection>,
+    root: Window,
+    area: Area,
+    segment: shm::Seg,
+    memory: File, // Shared memory segment
+    frame_sender: mpsc::Sender<MemoryFrame>,
+    error_sender: mpsc::Sender<CaptureError>,
+    stop: Arc<AtomicBool>,
+    limiter: FrameLimiter,
+}
+
+impl X11Capturer {
+    // `monitor` selects a RandR monitor by its name (usually the output name), as other properties
+    // are unknown on X11. The whole screen is captured if it is None.
+    // `region` is relative to the monitor.
+    pub fn new(
+        facade: &impl Facade,
+        monitor: Option<&OutputMatch>,
+        region: Option<Region>,
+        limiter: FrameLimiter,
+    ) -> Result<Self, CaptureError> {
+        let (conn, screen_num) = x11rb::connect(None).map_err(|err| {
+            CaptureError::Disconnected(format!("cannot connect to the X server: {}", err))
+        })?;
+        let conn = Arc::new(conn);
+        let screen = &conn.setup().roots[screen_num];
+        let root = screen.root;
+        // Depth 24 and 32 use 4 bytes per pixel (XRGB8888 in little endian)
+        if screen.root_depth != 24 && screen.root_depth != 32 {
+            return Err(CaptureError::Unsupported(format!(
+                "X11 screen depth {}",
+                screen.root_depth
+            )));
+        }
+
+        // (1) Check extensions
+        conn.shm_query_version()
+            .map_err(|_| CaptureError::Unsupported("MIT-SHM of the X server".to_owned()))?
+            .reply()?;
+        conn.damage_query_version(1, 1)
+            .map_err(|_| CaptureError::Unsupported("Damage of the X server".to_owned()))?
+            .reply()?;
+
+        // (2) Determine the captured area
+        let screen_area = Area {
+            x: 0,
+            y: 0,
+            width: screen.width_in_pixels,
+            height: screen.height_in_pixels,
+        };
+        let mut area = match monitor {
+            Some(monitor) => find_monitor(&conn, root, monitor)?,
+            None => screen_area,
+        };
+        if let Some(region) = region {
+            log::info!("Capturing region {:?}", region);
+            area = area.region(region).ok_or_else(|| {
+                CaptureError::Unsupported(format!("region {:?} outside of {:?}", region, area))
+            })?;
+        }
+        log::info!("Capturing X11 area {:?}", area);
+
+        // (3) Create a shared memory segment (allocated by the server)
+        let size = 4 * area.width as u32 * area.height as u32;
+        let segment = conn.generate_id()?;
+        let reply = conn.shm_create_segment(segment, size, false)?.reply()?;
+        let memory = File::from(reply.shm_fd);
+
+        // (4) Report damage of the root window
+        let damage = conn.generate_id()?;
+        conn.damage_create(damage, root, damage::ReportLevel::NON_EMPTY)?;
+
+        let wake_window = conn.generate_id()?;
+        conn.create_window(
+            COPY_DEPTH_FROM_PARENT,
+            wake_window,
+            root,
+            0,
+            0,
+            1,
+            1,
+            0,
+            WindowClass::INPUT_ONLY,
+            0,
+            &CreateWindowAux::new(),
+        )?;
+        conn.flush()?;
+
+        let (frame_sender, frame_receiver) = mpsc::channel();
+        let (error_sender, error_receiver) = mpsc::channel();
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let join_handle = thread::spawn({
+            let conn = Arc::clone(&conn);
+            let stop = Arc::clone(&stop);
+            move || {
+                CaptureThread {
+                    conn,
+                    root,
+                    area,
+                    segment,
+                    memory,
+                    frame_sender,
+                    error_sender,
+                    stop,
+                    limiter,
+                }
+                .run(damage);
+            }
+        });
+
+        Ok(Self {
+            texture: MemoryTexture::new(facade),
+            frame_receiver,
+            error_receiver,
+            error: None,
+            conn,
+            wake_window,
+            stop,
+            join_handle: Some(join_handle),
+        })
+    }
+}
+
+impl Capturer for X11Capturer {
+    fn capture(&mut self) -> Result<CapturedFrame, CaptureError> {
+        if let Ok(error) = self.error_receiver.try_recv() {
+            self.error = Some(error);
+        }
+        if let Some(ref error) = self.error {
+            return Err(error.clone());
+        }
+        self.texture.capture(&self.frame_receiver)
+    }
+}
+
+impl Drop for X11Capturer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+
+        // Wake the capture thread waiting for damage
+        // Fails only if the connection is lost, and then the capture thread has stopped too
+        let event = ClientMessageEvent::new(32, self.wake_window, AtomEnum::NONE, [0u32; 5]);
+        let _ = self
+            .conn
+            .send_event(false, self.wake_window, EventMask::NO_EVENT, event);
+        let _ = self.conn.flush();
+
+        if let Some(join_handle) = self.join_handle.take() {
+            let _ = join_handle.join();
+        }
+
+        let _ = self.conn.destroy_window(self.wake_window);
+        let _ = self.conn.flush();
+    }
+}
+
+impl CaptureThread {
+    fn run(&mut self, damage: damage::Damage) {
+        if let Err(err) = self.capture_frames(damage) {
+            log::error!("X11 capture stopped: {}", err);
+            // The capturer has to be recreated, even after errors which are usually transient
+            let err = match err {
+                err if err.is_transient() => CaptureError::Disconnected(err.to_string()),
+                err => err,
+            };
+            // Fails only if the capturer is being dropped
+            let _ = self.error_sender.send(err);
+        }
+
+        let _ = self.conn.damage_destroy(damage);
+        let _ = self.conn.shm_detach(self.segment);
+        let _ = self.conn.flush();
+    }
+
+    // Capture whenever the screen is damaged, until stop is requested
+    fn capture_frames(&mut self, damage: damage::Damage) -> Result<(), CaptureError> {
+        // The first frame is captured without waiting for damage
+        self.capture_frame(monotonic_now())?;
+
+        loop {
+            let event = self.conn.wait_for_event()?;
+            if self.stop.load(Ordering::Relaxed) {
+                return Ok(());
+            }
+
+            // With ReportLevel::NON_EMPTY, next damage is not reported until it is subtracted
+            if let Event::DamageNotify(_) = event {
+                // X11 does not tell when the screen is presented, so damage time is used instead
+                let damaged = monotonic_now();
+
+                // Keep the capture frame rate limit. Damage during the wait is included in this frame.
+                thread::sleep(self.limiter.remaining());
+                if self.stop.load(Ordering::Relaxed) {
+                    return Ok(());
+                }
+
+                self.conn.damage_subtract(damage, NONE, NONE)?;
+                self.capture_frame(damaged)?;
+            }
+        }
+    }
+
+    fn capture_frame(&mut self, presented: Duration) -> Result<(), CaptureError> {
+        let Area {
+            x,
+            y,
+            width,
+            height,
+        } = self.area;
+
+        let reply = self
+            .conn
+            .shm_get_image(
+                self.root,
+                x,
+                y,
+                width,
+                height,
+                !0,
+                ImageFormat::Z_PIXMAP.into(),
+                self.segment,
+                0,
+            )?
+            .reply()?;
+        // Depth 24 and 32 use 4 bytes per pixel (XRGB8888 in little endian)
+        if reply.depth != 24 && reply.depth != 32 {
+            return Err(CaptureError::Unsupported(format!(
+                "X11 screen depth {}",
+                reply.depth
+            )));
+        }
+
+        let mut data = vec![0; reply.size as usize];
+        self.memory
+            .read_exact_at(&mut data, 0)
+            .map_err(|err| CaptureError::Failed(format!("cannot read shared memory: {}", err)))?;
+        self.limiter.frame_captured();
+
+        // Fails only if the capturer is being dropped
+        let _ = self.frame_sender.send(MemoryFrame {
+            data,
+            width: width as u32,
+            height: height as u32,
+            timing: FrameTiming {
+                presented,
+                ready: monotonic_now(),
+            },
+        });
+        Ok(())
+    }
+}
+
+impl Area {
+    // Intersection with `region` relative to this area, or None if they do not overlap
+    fn region(&self, region: Region) -> Option<Self> {
+        let left = (self.x as i32 + region.x).max(self.x as i32);
+        let top = (self.y as i32 + region.y).max(self.y as i32);
+        let right = (self.x as i32 + region.x.saturating_add(region.width))
+            .min(self.x as i32 + self.width as i32);
+        let bottom = (self.y as i32 + region.y.saturating_add(region.height))
+            .min(self.y as i32 + self.height as i32);
+        (right > left && bottom > top).then(|| Self {
+            // Within this area, so they fit in the types of X11
+            x: left as i16,
+            y: top as i16,
+            width: (right - left) as u16,
+            height: (bottom - top) as u16,
+        })
+    }
+}
+
+fn find_monitor(
+    conn: &RustConnection,
+    root: Window,
+    selector: &OutputMatch,
+) -> Result<Area, CaptureError> {
+    let monitors = conn
+        .randr_get_monitors(root, true)
+        .map_err(|_| CaptureError::Unsupported("RandR of the X server".to_owned()))?
+        .reply()?;
+
+    for monitor in monitors.monitors {
+        let name = conn.get_atom_name(monitor.name)?.reply()?;
+        let properties = OutputProperties {
+            name: Some(String::from_utf8_lossy(&name.name).into_owned()),
+            ..Default::default()
+        };
+        log::debug!("X11 monitor: {:?}", properties);
+
+        if selector.matches(&properties) {
+            return Ok(Area {
+                x: monitor.x,
+                y: monitor.y,
+                width: monitor.width,
+                height: monitor.height,
+            });
+        }
+    }
+
+    Err(CaptureError::OutputNotFound(selector.to_string()))
+}
+
+impl From<ConnectionError> for CaptureError {
+    fn from(err: ConnectionError) -> Self {
+        CaptureError::Disconnected(format!("X11: {}", err))
+    }
+}
+
+impl From<ReplyError> for CaptureError {
+    fn from(err: ReplyError) -> Self {
+        match err {
+            ReplyError::ConnectionError(err) => err.into(),
+            ReplyError::X11Error(err) => CaptureError::Failed(format!("X11: {:?}", err)),
+        }
+    }
+}
+
+impl From<ReplyOrIdError> for CaptureError {
+    fn from(err: ReplyOrIdError) -> Self {
+        CaptureError::Disconnected(format!("X11: {}", err))
+    }
+}
@@ -2,6 +2,8 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use std::path::PathBuf;
+
 use serde::{Deserialize, Serialize};
 
 #[derive(Clone, Default, Debug, Serialize, Deserialize)]
@@ -12,37 +14,308 @@ pub struct Config {
     pub glasses: Glasses,
     #[serde(default)]
     pub virtual_screen: VirtualScreen,
+    #[serde(default)]
+    pub windows: Vec<Window>,
+    // Output created at startup to be captured
+    #[serde(default)]
+    pub headless_output: Option<HeadlessOutput>,
+    #[serde(default)]
+    pub snapshot: Snapshot,
+    // Render into files at poses from an IMU trace, instead of showing on the glasses
+    #[serde(default)]
+    pub offscreen: Option<Offscreen>,
 }
 
-#[derive(Clone, Default, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Capture {
+    #[serde(default)]
+    pub backend: Backend,
+    // Name of the output, or a glob pattern of it
     #[serde(default)]
     pub output_name: Option<String>,
+    // Output selected by other properties. Overrides `output_name`.
+    #[serde(default)]
+    pub output: Option<OutputMatch>,
+    // Capture only a part of the output
+    #[serde(default)]
+    pub region: Option<Region>,
+    #[serde(default)]
+    pub cursor: CursorMode,
+    // Size of the overlaid cursor relative to its size on the desktop
+    #[serde(default = "default_cursor_scale")]
+    pub cursor_scale: f32,
+    // Distance of the overlaid cursor in front of the screen (in meters)
+    #[serde(default)]
+    pub cursor_depth: f32,
+    // PipeWire node captured directly, without the ScreenCast portal (mainly for testing)
+    #[serde(default)]
+    pub pipewire_node: Option<u32>,
+    // Images shown by the `file` backend
+    #[serde(default)]
+    pub file: Option<FileSource>,
+    // Frames captured per second at most. The screen is captured whenever it changes if None.
+    #[serde(default)]
+    pub max_frame_rate: Option<f32>,
+    // Mode of the captured output set through wlr-output-management while running
+    #[serde(default)]
+    pub output_mode: Option<OutputMode>,
+    #[serde(default)]
+    pub allocator: Allocator,
+}
+
+impl Default for Capture {
+    fn default() -> Self {
+        Self {
+            backend: Backend::default(),
+            output_name: None,
+            output: None,
+            region: None,
+            cursor: CursorMode::default(),
+            cursor_scale: default_cursor_scale(),
+            cursor_depth: 0.0,
+            pipewire_node: None,
+            file: None,
+            max_frame_rate: None,
+            output_mode: None,
+            allocator: Allocator::default(),
+        }
+    }
+}
+
+impl Capture {
+    pub fn output_match(&self) -> Option<OutputMatch> {
+        self.output
+            .clone()
+            .or_else(|| self.output_name.as_deref().map(OutputMatch::by_name))
+    }
+}
+
+// How the screen is captured
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Backend {
+    // wlroots screencopy or ext-image-copy-capture
+    #[default]
+    Wayland,
+    // ScreenCast portal and PipeWire (requires the `pipewire` feature)
+    Pipewire,
+    // MIT-SHM of X11
+    X11,
+    // Image files (for demos and testing)
+    File,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FileSource {
+    // Image file, or directory of images shown in the order of file names
+    pub path: PathBuf,
+    // Images per second. Only the first image is shown if not specified.
+    #[serde(default)]
+    pub frame_rate: Option<f32>,
+    // Width and height of raw XRGB8888 frames (files with extension `raw` or `bin`)
+    #[serde(default)]
+    pub raw_size: Option<(u32, u32)>,
+}
+
+// Glob patterns (`*` and `?`) of properties of an output. Unset ones match any output.
+// Make, model and serial come from EDID. Description is usually made of them.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct OutputMatch {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub make: Option<String>,
+    #[serde(default)]
+    pub model: Option<String>,
+    #[serde(default)]
+    pub serial: Option<String>,
+}
+
+// Unset ones are not changed
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct OutputMode {
+    // Width and height in pixels
+    #[serde(default)]
+    pub size: Option<(u32, u32)>,
+    // Refresh rate in Hz
+    #[serde(default)]
+    pub refresh_rate: Option<f32>,
+    #[serde(default)]
+    pub scale: Option<f32>,
+}
+
+// How the mouse cursor is shown
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CursorMode {
+    // Drawn into captured frames by the compositor
+    #[default]
+    Embedded,
+    // Captured separately and drawn as a layer over the screen
+    Overlay,
+}
+
+// How buffers shared with the compositor are allocated (Wayland backend)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Allocator {
+    // GBM if it is available, otherwise exported GL textures
+    #[default]
+    Auto,
+    // GBM on the render node of the GPU, imported into GL
+    Gbm,
+    // GL textures exported as DMA-BUFs (only on Mesa)
+    Export,
+}
+
+// Rectangle in the logical coordinates of an output (origin is top left)
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct Region {
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Glasses {
+    // Output of the glasses, detected from its make and model if neither this nor `monitor` is set
     #[serde(default)]
     pub monitor_name: Option<String>,
+    // Output selected by other properties. Overrides `monitor_name`.
+    #[serde(default)]
+    pub monitor: Option<OutputMatch>,
     #[serde(default)]
     pub window_mode: bool,
+    // Move the glasses away from other outputs in the desktop layout while running,
+    // so that the pointer and windows do not go there
+    #[serde(default)]
+    pub isolate: bool,
+    // CSV file to record IMU samples into, for `[offscreen]`
+    #[serde(default)]
+    pub record_imu: Option<PathBuf>,
+    // Command of each button of the glasses, in the order of key IDs (0, 1, ...)
+    #[serde(default)]
+    pub buttons: Vec<ScreenCommand>,
 }
 
 impl Default for Glasses {
     fn default() -> Self {
         Self {
             monitor_name: None,
+            monitor: None,
             window_mode: false,
+            isolate: false,
+            record_imu: None,
+            buttons: Vec::new(),
+        }
+    }
+}
+
+impl Glasses {
+    pub fn monitor_match(&self) -> Option<OutputMatch> {
+        self.monitor
+            .clone()
+            .or_else(|| self.monitor_name.as_deref().map(OutputMatch::by_name))
+    }
+}
+
+// Window shown as its own virtual screen.
+// A window matches when both `app_id` (exact) and `title` (substring) match. Unset ones match any window.
+#[derive(Clone, Default, Debug, Serialize, Deserialize)]
+pub struct Window {
+    #[serde(default)]
+    pub app_id: Option<String>,
+    #[serde(default)]
+    pub title: Option<String>,
+    #[serde(default)]
+    pub virtual_screen: VirtualScreen,
+}
+
+// Headless (virtual) output created through the IPC of the compositor, and removed on exit
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HeadlessOutput {
+    #[serde(default = "default_headless_width")]
+    pub width: u32,
+    #[serde(default = "default_headless_height")]
+    pub height: u32,
+    // Refresh rate in Hz (compositor default if None)
+    #[serde(default)]
+    pub refresh_rate: Option<f32>,
+    #[serde(default)]
+    pub scale: Option<f32>,
+}
+
+impl Default for HeadlessOutput {
+    fn default() -> Self {
+        Self {
+            width: default_headless_width(),
+            height: default_headless_height(),
+            refresh_rate: None,
+            scale: None,
+        }
+    }
+}
+
+// Snapshots saved with F12 or SIGUSR1
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Snapshot {
+    #[serde(default = "default_snapshot_directory")]
+    pub directory: PathBuf,
+}
+
+impl Default for Snapshot {
+    fn default() -> Self {
+        Self {
+            directory: default_snapshot_directory(),
         }
     }
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Offscreen {
+    // Recorded with `record_imu` of [glasses]
+    pub imu_trace: PathBuf,
+    // Directory of PNG files, Y4M file (with extension `y4m`), or `-` for Y4M to stdout
+    pub output: PathBuf,
+    // Size of the side-by-side frame
+    #[serde(default = "default_offscreen_size")]
+    pub size: (u32, u32),
+    #[serde(default = "default_offscreen_frame_rate")]
+    pub frame_rate: f32,
+    // Field of view in degrees (same as XREAL Air if None)
+    #[serde(default)]
+    pub fov: Option<f32>,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct VirtualScreen {
     #[serde(default = "default_height")]
     pub height: f32,
     #[serde(default = "default_distance")]
     pub distance: f32,
+    // Direction of the screen seen from the viewer, in degrees (positive to the right and up).
+    // The screen faces the viewer. Screens without `yaw` are placed next to each other.
+    #[serde(default)]
+    pub yaw: Option<f32>,
+    #[serde(default)]
+    pub pitch: f32,
+    // Rotation of the screen around its center, in degrees (positive clockwise)
+    #[serde(default)]
+    pub roll: f32,
+    // Shift of the screen along itself, in meters (positive to the right and up)
+    #[serde(default)]
+    pub offset: (f32, f32),
+    #[serde(default)]
+    pub shape: ScreenShape,
+    // Radius of curvature in meters. Same as `distance` if None, so that every point is equally far.
+    #[serde(default)]
+    pub radius: Option<f32>,
+    // Horizontal angle covered by a curved screen, in degrees. Overrides `radius`.
+    #[serde(default)]
+    pub arc: Option<f32>,
 }
 
 impl Default for VirtualScreen {
@@ -50,10 +323,71 @@ impl Default for VirtualScreen {
         Self {
             height: default_height(),
             distance: default_distance(),
+            yaw: None,
+            pitch: 0.0,
+            roll: 0.0,
+            offset: (0.0, 0.0),
+            shape: ScreenShape::default(),
+            radius: None,
+            arc: None,
         }
     }
 }
 
+// Change of the placement of virtual screens at runtime
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScreenCommand {
+    Closer,
+    Farther,
+    Larger,
+    Smaller,
+    Left,
+    Right,
+    Up,
+    Down,
+    // Back to the placement at startup
+    Reset,
+    // Write the current placement into the config file
+    Save,
+}
+
+// Shape of a virtual screen. Curved ones bend toward the viewer.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScreenShape {
+    #[default]
+    Flat,
+    // Curved horizontally, like curved monitors
+    Cylinder,
+    // Curved horizontally and vertically
+    Sphere,
+}
+
+fn default_cursor_scale() -> f32 {
+    1.0
+}
+
+fn default_headless_width() -> u32 {
+    1920
+}
+
+fn default_headless_height() -> u32 {
+    1080
+}
+
+fn default_offscreen_size() -> (u32, u32) {
+    (3840, 1080)
+}
+
+fn default_offscreen_frame_rate() -> f32 {
+    60.0
+}
+
+fn default_snapshot_directory() -> PathBuf {
+    PathBuf::from(".")
+}
+
 fn default_height() -> f32 {
     1.0
 }
@@ -0,0 +1,205 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Runtime control of the placement of screens, by keys in the window and through a Unix socket
+// accepting one command per line (e.g. `echo closer | socat - UNIX-CONNECT:$XDG_RUNTIME_DIR/okulekrano.sock`)
+
+use std::{
+    env, fs,
+    io::{self, BufRead, BufReader, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::PathBuf,
+    str::FromStr,
+    sync::mpsc,
+    thread,
+};
+
+use serde::{
+    de::{value::StrDeserializer, IntoDeserializer},
+    Deserialize,
+};
+use winit::keyboard::{Key, ModifiersState, NamedKey};
+
+use crate::config::ScreenCommand;
+
+const SOCKET_NAME: &str = "okulekrano.sock";
+
+pub struct ControlSocket {
+    path: PathBuf,
+    receiver: mpsc::Receiver<ScreenCommand>,
+}
+
+// Same names as in the config file
+impl FromStr for ScreenCommand {
+    type Err = serde::de::value::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let deserializer: StrDeserializer<Self::Err> = s.into_deserializer();
+        Self::deserialize(deserializer)
+    }
+}
+
+impl ControlSocket {
+    pub fn bind() -> io::Result<Self> {
+        let directory = env::var_os("XDG_RUNTIME_DIR")
+            .ok_or_else(|| io::Error::other("XDG_RUNTIME_DIR is not set"))?;
+        let path = PathBuf::from(directory).join(SOCKET_NAME);
+
+        // Remove the socket left by a crashed instance, but not one of a running instance
+        if path.exists() && UnixStream::connect(&path).is_err() {
+            fs::remove_file(&path)?;
+        }
+        let listener = UnixListener::bind(&path)?;
+        log::info!("Listening to commands on {}", path.display());
+
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let sender = sender.clone();
+                        thread::spawn(move || serve(stream, sender));
+                    }
+                    Err(err) => log::warn!("Cannot accept control connection: {}", err),
+                }
+            }
+        });
+
+        Ok(Self { path, receiver })
+    }
+
+    // Commands received since the last call
+    pub fn commands(&self) -> mpsc::TryIter<'_, ScreenCommand> {
+        self.receiver.try_iter()
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+// Reply `ok` or an error to each line
+fn serve(stream: UnixStream, sender: mpsc::Sender<ScreenCommand>) {
+    let Ok(mut writer) = stream.try_clone() else {
+        return;
+    };
+    for line in BufReader::new(stream).lines() {
+        let Ok(line) = line else {
+            break;
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let reply = match line.parse::<ScreenCommand>() {
+            Ok(command) => {
+                if sender.send(command).is_err() {
+                    break;
+                }
+                "ok".to_owned()
+            }
+            Err(err) => format!("error: {}", err),
+        };
+        if writeln!(writer, "{}", reply).is_err() {
+            break;
+        }
+    }
+}
+
+// Arrow keys move, +/- resize, Page Up/Down change the distance, Home resets and Ctrl+S saves
+pub fn key_command(key: &Key, modifiers: ModifiersState) -> Option<ScreenCommand> {
+    match key.as_ref() {
+        Key::Named(NamedKey::ArrowLeft) => Some(ScreenCommand::Left),
+        Key::Named(NamedKey::ArrowRight) => Some(ScreenCommand::Right),
+        Key::Named(NamedKey::ArrowUp) => Some(ScreenCommand::Up),
+        Key::Named(NamedKey::ArrowDown) => Some(ScreenCommand::Down),
+        Key::Named(NamedKey::PageUp) => Some(ScreenCommand::Closer),
+        Key::Named(NamedKey::PageDown) => Some(ScreenCommand::Farther),
+        Key::Named(NamedKey::Home) => Some(ScreenCommand::Reset),
+        Key::Character("+" | "=") => Some(ScreenCommand::Larger),
+        Key::Character("-") => Some(ScreenCommand::Smaller),
+        Key::Character("s" | "S") if modifiers.control_key() => Some(ScreenCommand::Save),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn character(text: &str) -> Key {
+        Key::Character(text.into())
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            "closer".parse::<ScreenCommand>().unwrap(),
+            ScreenCommand::Closer
+        );
+        assert_eq!(
+            "reset".parse::<ScreenCommand>().unwrap(),
+            ScreenCommand::Reset
+        );
+        assert_eq!(
+            "save".parse::<ScreenCommand>().unwrap(),
+            ScreenCommand::Save
+        );
+        for invalid in ["", "Closer", "closer ", "jump"] {
+            assert!(invalid.parse::<ScreenCommand>().is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn keys() {
+        let none = ModifiersState::empty();
+        let named = |key| key_command(&Key::Named(key), none);
+        assert_eq!(named(NamedKey::ArrowLeft), Some(ScreenCommand::Left));
+        assert_eq!(named(NamedKey::ArrowRight), Some(ScreenCommand::Right));
+        assert_eq!(named(NamedKey::ArrowUp), Some(ScreenCommand::Up));
+        assert_eq!(named(NamedKey::ArrowDown), Some(ScreenCommand::Down));
+        assert_eq!(named(NamedKey::PageUp), Some(ScreenCommand::Closer));
+        assert_eq!(named(NamedKey::PageDown), Some(ScreenCommand::Farther));
+        assert_eq!(named(NamedKey::Home), Some(ScreenCommand::Reset));
+        assert_eq!(named(NamedKey::Escape), None);
+
+        // `+` is on the same key as `=` on many layouts
+        for text in ["+", "="] {
+            assert_eq!(
+                key_command(&character(text), none),
+                Some(ScreenCommand::Larger)
+            );
+        }
+        assert_eq!(
+            key_command(&character("-"), none),
+            Some(ScreenCommand::Smaller)
+        );
+        assert_eq!(key_command(&character("s"), none), None);
+        for text in ["s", "S"] {
+            let command = key_command(&character(text), ModifiersState::CONTROL);
+            assert_eq!(command, Some(ScreenCommand::Save));
+        }
+    }
+
+    #[test]
+    fn protocol() {
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let (sender, receiver) = mpsc::channel();
+        let server = thread::spawn(move || serve(server, sender));
+
+        client.write_all(b"closer\n\n  left  \njump\n").unwrap();
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+        server.join().unwrap();
+
+        let replies: Vec<_> = BufReader::new(client).lines().map(Result::unwrap).collect();
+        assert_eq!(replies.len(), 3);
+        assert_eq!(replies[..2], ["ok", "ok"]);
+        assert!(replies[2].starts_with("error: "), "{}", replies[2]);
+        let commands: Vec<_> = receiver.try_iter().collect();
+        assert_eq!(commands, [ScreenCommand::Closer, ScreenCommand::Left]);
+    }
+}
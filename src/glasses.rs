@@ -5,17 +5,28 @@
 use ahrs::Ahrs;
 use ar_drivers::{ARGlasses, GlassesEvent, Side};
 use core::f32;
-use na::{Matrix4, Perspective3, Rotation3, UnitQuaternion, Vector3};
+use na::{Matrix4, Perspective3, Rotation3, Translation3, UnitQuaternion, Vector3};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
 use std::sync::mpsc;
 use std::thread::{self, JoinHandle};
 
 const FUSION_PERIOD_US: u64 = 10000;
 const IPD: f32 = 0.07;
 
+// Head pose and projection of each eye, used by the renderer
+pub trait Viewer {
+    fn pose(&self) -> UnitQuaternion<f32>;
+    fn fov(&self) -> f32;
+    fn camera_mat(&self, side: Side, aspect: f32) -> Matrix4<f32>;
+}
+
 pub struct GlassesController {
-    #[allow(dead_code)]
-    join_handle: JoinHandle<()>,
+    join_handle: Option<JoinHandle<()>>,
     quat_receiver: mpsc::Receiver<UnitQuaternion<f32>>,
+    button_receiver: mpsc::Receiver<u8>,
     stop_sender: mpsc::Sender<()>,
     pose: Rotation3<f32>,
     fov: f32,
@@ -23,8 +34,31 @@ pub struct GlassesController {
     imu_to_display_r: Matrix4<f32>,
 }
 
+// Reading of the accelerometer and gyroscope, as recorded in IMU traces
+#[derive(Clone, Copy, Debug)]
+struct ImuSample {
+    timestamp: u64, // Microseconds
+    gyroscope: Vector3<f32>,
+    accelerometer: Vector3<f32>,
+}
+
+// Sensor fusion of IMU samples into the head pose, same for the glasses and recorded traces
+struct Fusion {
+    filter: ahrs::Madgwick<f32>,
+    last_timestamp: u64,
+}
+
+// Poses from a recorded IMU trace, for rendering without glasses
+pub struct TracePlayback {
+    samples: Vec<ImuSample>,
+    next: usize, // First sample not fused yet
+    fusion: Fusion,
+    pose: UnitQuaternion<f32>,
+}
+
 impl GlassesController {
-    pub fn new() -> Self {
+    // IMU samples are also written to `record_imu` if it is set
+    pub fn new(record_imu: Option<&Path>) -> Self {
         let mut glasses = ar_drivers::any_glasses()
             .expect("AR glasses not found. Maybe permission issues of hidraw device.");
 
@@ -50,16 +84,27 @@ impl GlassesController {
         )
         .cast();
 
+        let recorder = record_imu.map(|path| {
+            let mut file = File::create(path)
+                .map(BufWriter::new)
+                .unwrap_or_else(|err| panic!("Cannot create {}: {}", path.display(), err));
+            writeln!(file, "{}", ImuSample::CSV_HEADER).unwrap();
+            log::info!("Recording IMU samples to {}", path.display());
+            file
+        });
+
         let (quat_sender, quat_receiver) = mpsc::sync_channel(0);
+        let (button_sender, button_receiver) = mpsc::channel();
         let (stop_sender, stop_receiver) = mpsc::channel();
 
         let join_handle = thread::spawn(move || {
-            process_events(glasses, quat_sender, stop_receiver);
+            process_events(glasses, quat_sender, button_sender, stop_receiver, recorder);
         });
 
         Self {
-            join_handle,
+            join_handle: Some(join_handle),
             quat_receiver,
+            button_receiver,
             stop_sender,
             pose: Rotation3::identity(),
             fov,
@@ -75,61 +120,250 @@ impl GlassesController {
         self.pose = quat.to_rotation_matrix();
     }
 
-    pub fn camera_mat(&self, side: Side, aspect: f32) -> Matrix4<f32> {
-        let proj = Perspective3::new(aspect, self.fov / aspect, 0.1, 10.0)
-            .as_projective()
-            .clone();
+    // Key IDs of buttons pressed since the last call
+    pub fn pressed_buttons(&self) -> Vec<u8> {
+        self.button_receiver.try_iter().collect()
+    }
+}
+
+impl Viewer for GlassesController {
+    fn pose(&self) -> UnitQuaternion<f32> {
+        UnitQuaternion::from_rotation_matrix(&self.pose)
+    }
+
+    fn fov(&self) -> f32 {
+        self.fov
+    }
+
+    fn camera_mat(&self, side: Side, aspect: f32) -> Matrix4<f32> {
         let imu_to_display = match side {
             Side::Left => self.imu_to_display_l,
             Side::Right => self.imu_to_display_r,
         };
-        proj.to_homogeneous() * imu_to_display * self.pose.inverse().to_homogeneous()
+        camera_mat(self.fov, aspect, &imu_to_display, &self.pose)
     }
 }
 
+// Viewer with a pose given from outside, for rendering without glasses (e.g. tests).
+// Eyes are IPD apart without divergence.
+#[derive(Clone, Copy, Debug)]
+pub struct FixedViewer {
+    pub pose: UnitQuaternion<f32>,
+    pub fov: f32,
+}
+
+impl FixedViewer {
+    pub fn new(pose: UnitQuaternion<f32>) -> Self {
+        Self {
+            pose,
+            fov: 24f32.to_radians(), // Same as XREAL Air
+        }
+    }
+}
+
+impl Viewer for FixedViewer {
+    fn pose(&self) -> UnitQuaternion<f32> {
+        self.pose
+    }
+
+    fn fov(&self) -> f32 {
+        self.fov
+    }
+
+    fn camera_mat(&self, side: Side, aspect: f32) -> Matrix4<f32> {
+        let offset = match side {
+            Side::Left => -IPD / 2.0,
+            Side::Right => IPD / 2.0,
+        };
+        let imu_to_display = Translation3::new(offset, 0.0, 0.0).to_homogeneous();
+        camera_mat(
+            self.fov,
+            aspect,
+            &imu_to_display,
+            &self.pose.to_rotation_matrix(),
+        )
+    }
+}
+
+fn camera_mat(
+    fov: f32,
+    aspect: f32,
+    imu_to_display: &Matrix4<f32>,
+    pose: &Rotation3<f32>,
+) -> Matrix4<f32> {
+    let proj = Perspective3::new(aspect, fov / aspect, 0.1, 10.0)
+        .as_projective()
+        .clone();
+    proj.to_homogeneous() * imu_to_display * pose.inverse().to_homogeneous()
+}
+
 impl Drop for GlassesController {
     fn drop(&mut self) {
-        self.stop_sender.send(()).unwrap();
+        // Fails only if the thread has already finished
+        let _ = self.stop_sender.send(());
+        // Waits for the recorded IMU trace to be written
+        if let Some(join_handle) = self.join_handle.take() {
+            if join_handle.join().is_err() {
+                log::error!("Glasses thread has panicked");
+            }
+        }
+    }
+}
+
+impl ImuSample {
+    const CSV_HEADER: &str = "# timestamp_us,gyro_x,gyro_y,gyro_z,accel_x,accel_y,accel_z";
+
+    fn parse(line: &str) -> Option<Self> {
+        let mut fields = line.split(',').map(str::trim);
+        let timestamp = fields.next()?.parse().ok()?;
+        let values: Vec<f32> = fields
+            .map(|field| field.parse().ok())
+            .collect::<Option<_>>()?;
+        let [gx, gy, gz, ax, ay, az] = values[..] else {
+            return None;
+        };
+        Some(Self {
+            timestamp,
+            gyroscope: Vector3::new(gx, gy, gz),
+            accelerometer: Vector3::new(ax, ay, az),
+        })
+    }
+}
+
+// One line of CSV
+impl fmt::Display for ImuSample {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (gyro, accel) = (&self.gyroscope, &self.accelerometer);
+        write!(
+            f,
+            "{},{},{},{},{},{},{}",
+            self.timestamp, gyro.x, gyro.y, gyro.z, accel.x, accel.y, accel.z
+        )
+    }
+}
+
+impl Fusion {
+    fn new() -> Self {
+        Self {
+            filter: ahrs::Madgwick::new(FUSION_PERIOD_US as f32 / 1e6, 0.1),
+            last_timestamp: 0,
+        }
+    }
+
+    // Returns the updated pose, or None if the sample is skipped because it is too soon
+    fn update(&mut self, sample: &ImuSample) -> Option<UnitQuaternion<f32>> {
+        if sample.timestamp.saturating_sub(self.last_timestamp) < FUSION_PERIOD_US {
+            return None;
+        }
+        self.last_timestamp = sample.timestamp;
+
+        let quat = self
+            .filter
+            .update_imu(&sample.gyroscope, &sample.accelerometer)
+            .unwrap();
+
+        // Conversion from `ahrs` (z-down) to `ar-drivers` convention (y-up)
+        let filter_to_glasses =
+            UnitQuaternion::from_axis_angle(&Vector3::x_axis(), -f32::consts::FRAC_PI_2);
+        Some(filter_to_glasses * quat)
+    }
+}
+
+impl TracePlayback {
+    // CSV file recorded with `record_imu` of the glasses config
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let samples = fs::read_to_string(path)?
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty() && !line.starts_with('#'))
+            .map(|(index, line)| {
+                ImuSample::parse(line).ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("invalid IMU sample at line {}", index + 1),
+                    )
+                })
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        if samples.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "no IMU sample in the trace",
+            ));
+        }
+
+        Ok(Self {
+            samples,
+            next: 0,
+            fusion: Fusion::new(),
+            pose: UnitQuaternion::identity(),
+        })
+    }
+
+    // Timestamps of the first and last samples in microseconds
+    pub fn time_range(&self) -> (u64, u64) {
+        (
+            self.samples[0].timestamp,
+            self.samples[self.samples.len() - 1].timestamp,
+        )
+    }
+
+    // Pose after fusing all samples until `timestamp`. It must not go back.
+    pub fn pose_at(&mut self, timestamp: u64) -> UnitQuaternion<f32> {
+        while let Some(sample) = self.samples.get(self.next) {
+            if sample.timestamp > timestamp {
+                break;
+            }
+            if let Some(pose) = self.fusion.update(sample) {
+                self.pose = pose;
+            }
+            self.next += 1;
+        }
+        self.pose
     }
 }
 
 fn process_events(
     mut glasses: Box<dyn ARGlasses>,
     quat_sender: mpsc::SyncSender<UnitQuaternion<f32>>,
+    button_sender: mpsc::Sender<u8>,
     stop_receiver: mpsc::Receiver<()>,
+    mut recorder: Option<BufWriter<File>>,
 ) {
-    let mut filter = ahrs::Madgwick::new(FUSION_PERIOD_US as f32 / 1e6, 0.1);
-
-    let mut last_timestamp = 0;
+    let mut fusion = Fusion::new();
 
     // Terminate when something is received from stop_receiver
-    while stop_receiver.try_recv().is_err() {
+    while let Err(mpsc::TryRecvError::Empty) = stop_receiver.try_recv() {
         match glasses.read_event().unwrap() {
             GlassesEvent::AccGyro {
                 accelerometer,
                 gyroscope,
                 timestamp,
-            } if (timestamp - last_timestamp) >= FUSION_PERIOD_US => {
+            } => {
                 // Because ahrs and ar_drivers use incompatible versions of nalgebra, conversions are needed.
-                let quat = filter
-                    .update_imu(
-                        &Vector3::new(gyroscope.x, gyroscope.y, gyroscope.z),
-                        &Vector3::new(accelerometer.x, accelerometer.y, accelerometer.z),
-                    )
-                    .unwrap();
+                let sample = ImuSample {
+                    timestamp,
+                    gyroscope: Vector3::new(gyroscope.x, gyroscope.y, gyroscope.z),
+                    accelerometer: Vector3::new(accelerometer.x, accelerometer.y, accelerometer.z),
+                };
 
-                // Conversion from `ahrs` (z-down) to `ar-drivers` convention (y-up)
-                let filter_to_glasses =
-                    UnitQuaternion::from_axis_angle(&Vector3::x_axis(), -f32::consts::FRAC_PI_2);
+                if let Some(ref mut file) = recorder {
+                    if let Err(err) = writeln!(file, "{}", sample) {
+                        log::error!("Cannot record IMU samples: {}", err);
+                        recorder = None;
+                    }
+                }
 
+                let Some(quat) = fusion.update(&sample) else {
+                    continue;
+                };
                 // Skip sending if the receiver is not received the old pose yet
-                match quat_sender.try_send(filter_to_glasses * quat) {
+                match quat_sender.try_send(quat) {
                     Ok(_) => (),
                     Err(mpsc::TrySendError::Full(_)) => (),
-                    Err(error) => panic!("{}", error),
+                    // The controller is being dropped
+                    Err(mpsc::TrySendError::Disconnected(_)) => break,
                 }
-
-                last_timestamp = timestamp;
             }
             GlassesEvent::Magnetometer {
                 magnetometer: _magnetometer,
@@ -137,12 +371,23 @@ fn process_events(
             } => {
                 log::debug!("mag {}", timestamp);
             }
+            GlassesEvent::KeyPress(key) => {
+                // IDs differ between models, so they are logged for configuring `buttons`
+                log::debug!("Button {} pressed", key);
+                let _ = button_sender.send(key);
+            }
             _ => (),
         }
     }
 
+    if let Some(mut file) = recorder {
+        if let Err(err) = file.flush() {
+            log::error!("Cannot record IMU samples: {}", err);
+        }
+    }
+
     // Reset the glasses to 2D mode
-    glasses
-        .set_display_mode(ar_drivers::DisplayMode::SameOnBoth)
-        .unwrap();
+    if let Err(err) = glasses.set_display_mode(ar_drivers::DisplayMode::SameOnBoth) {
+        log::warn!("Cannot reset the glasses to 2D mode: {}", err);
+    }
 }
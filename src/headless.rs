@@ -0,0 +1,291 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Automatic creation of the headless output to capture, through the IPC of Sway.
+// labwc cannot run actions from other programs, so `VirtualOutputAdd` has to be bound to a key or run in autostart.
+
+use std::{
+    collections::HashMap,
+    env,
+    io::{self, Read, Write},
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
+};
+
+use tinyjson::JsonValue;
+
+use crate::config;
+
+// Message types of Sway IPC (same as i3)
+const RUN_COMMAND: u32 = 0;
+const GET_OUTPUTS: u32 = 3;
+
+const MAGIC: &[u8; 6] = b"i3-ipc";
+
+// Headless output which is removed when dropped
+pub struct HeadlessOutput {
+    name: String,
+    ipc: SwayIpc,
+}
+
+struct SwayIpc {
+    stream: UnixStream,
+}
+
+// Socket of Sway IPC of the current session
+pub fn sway_socket() -> io::Result<PathBuf> {
+    match env::var_os("SWAYSOCK") {
+        Some(path) => Ok(path.into()),
+        None if env::var_os("LABWC_PID").is_some() => Err(io::Error::other(
+            "labwc cannot create outputs for other programs. Run `VirtualOutputAdd` in autostart and set `output_name` instead",
+        )),
+        None => Err(io::Error::other(
+            "only Sway can create outputs (SWAYSOCK is not set)",
+        )),
+    }
+}
+
+impl HeadlessOutput {
+    pub fn create(socket_path: &Path, config: &config::HeadlessOutput) -> io::Result<Self> {
+        let mut ipc = SwayIpc {
+            stream: UnixStream::connect(socket_path).map_err(|err| {
+                io::Error::new(err.kind(), format!("cannot connect to Sway IPC: {}", err))
+            })?,
+        };
+
+        // (1) Create an output. Its name (HEADLESS-n) is found by comparing the outputs before and after.
+        let existing = ipc.output_names()?;
+        ipc.run_command("create_output")?;
+        let name = ipc
+            .output_names()?
+            .into_iter()
+            .find(|name| !existing.contains(name))
+            .ok_or_else(|| io::Error::other("created output is not found"))?;
+        log::info!("Created headless output {}", name);
+
+        // From here, the output is removed even if configuring fails
+        let mut output = Self { name, ipc };
+
+        // (2) Configure the mode
+        let mut mode = format!("{}x{}", config.width, config.height);
+        if let Some(refresh_rate) = config.refresh_rate {
+            mode += &format!("@{}Hz", refresh_rate);
+        }
+        output
+            .ipc
+            .run_command(&format!("output {} mode --custom {}", output.name, mode))?;
+        if let Some(scale) = config.scale {
+            output
+                .ipc
+                .run_command(&format!("output {} scale {}", output.name, scale))?;
+        }
+
+        Ok(output)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl Drop for HeadlessOutput {
+    fn drop(&mut self) {
+        let command = format!("output {} unplug", self.name);
+        match self.ipc.run_command(&command) {
+            Ok(()) => log::info!("Removed headless output {}", self.name),
+            Err(err) => log::warn!("Failed to remove headless output {}: {}", self.name, err),
+        }
+    }
+}
+
+impl SwayIpc {
+    // Send a message and receive its reply
+    fn request(&mut self, message_type: u32, payload: &str) -> io::Result<JsonValue> {
+        // Header is the magic string, payload length and message type (in native byte order)
+        let mut message = MAGIC.to_vec();
+        message.extend((payload.len() as u32).to_ne_bytes());
+        message.extend(message_type.to_ne_bytes());
+        message.extend(payload.as_bytes());
+        self.stream.write_all(&message)?;
+
+        let mut header = [0; 14];
+        self.stream.read_exact(&mut header)?;
+        if &header[..6] != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Invalid reply from Sway IPC",
+            ));
+        }
+        let length = u32::from_ne_bytes(header[6..10].try_into().unwrap());
+        let mut reply = vec![0; length as usize];
+        self.stream.read_exact(&mut reply)?;
+
+        String::from_utf8_lossy(&reply)
+            .parse()
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    fn run_command(&mut self, command: &str) -> io::Result<()> {
+        log::debug!("Sway command: {}", command);
+        let reply = self.request(RUN_COMMAND, command)?;
+
+        // Reply is an array of results of each command
+        let results: &Vec<_> = reply.get().ok_or_else(invalid_reply)?;
+        for result in results {
+            let result: &HashMap<_, _> = result.get().ok_or_else(invalid_reply)?;
+            if result.get("success") != Some(&JsonValue::Boolean(true)) {
+                let error = match result.get("error") {
+                    Some(JsonValue::String(error)) => error.as_str(),
+                    _ => "unknown error",
+                };
+                return Err(io::Error::other(format!(
+                    "Sway command `{}` failed: {}",
+                    command, error
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn output_names(&mut self) -> io::Result<Vec<String>> {
+        let reply = self.request(GET_OUTPUTS, "")?;
+        let outputs: &Vec<_> = reply.get().ok_or_else(invalid_reply)?;
+        Ok(outputs
+            .iter()
+            .filter_map(|output| match output {
+                JsonValue::Object(output) => match output.get("name") {
+                    Some(JsonValue::String(name)) => Some(name.clone()),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect())
+    }
+}
+
+fn invalid_reply() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "Unexpected reply from Sway IPC")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        os::unix::net::UnixListener,
+        process,
+        sync::atomic::{AtomicU32, Ordering},
+        thread::{self, JoinHandle},
+    };
+
+    use super::*;
+
+    static SOCKET_COUNT: AtomicU32 = AtomicU32::new(0);
+
+    // Stand-in of Sway with the output HDMI-A-1, which creates HEADLESS-1.
+    // Commands containing `fail_on` fail. Returns the received messages when the client disconnects.
+    fn stand_in(fail_on: &'static str) -> (PathBuf, JoinHandle<Vec<(u32, String)>>) {
+        let path = env::temp_dir().join(format!(
+            "okulekrano-test-{}-{}.sock",
+            process::id(),
+            SOCKET_COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let listener = UnixListener::bind(&path).unwrap();
+
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut messages = Vec::new();
+            let mut created = false;
+            let mut header = [0; 14];
+            while stream.read_exact(&mut header).is_ok() {
+                assert_eq!(&header[..6], MAGIC);
+                let length = u32::from_ne_bytes(header[6..10].try_into().unwrap());
+                let message_type = u32::from_ne_bytes(header[10..14].try_into().unwrap());
+                let mut payload = vec![0; length as usize];
+                stream.read_exact(&mut payload).unwrap();
+                let payload = String::from_utf8(payload).unwrap();
+
+                let reply = match message_type {
+                    GET_OUTPUTS if created => r#"[{"name":"HDMI-A-1"},{"name":"HEADLESS-1"}]"#,
+                    GET_OUTPUTS => r#"[{"name":"HDMI-A-1"}]"#,
+                    _ if payload.contains(fail_on) => r#"[{"success":false,"error":"refused"}]"#,
+                    _ => {
+                        created |= payload == "create_output";
+                        r#"[{"success":true}]"#
+                    }
+                };
+                let mut message = MAGIC.to_vec();
+                message.extend((reply.len() as u32).to_ne_bytes());
+                message.extend(message_type.to_ne_bytes());
+                message.extend(reply.as_bytes());
+                stream.write_all(&message).unwrap();
+
+                messages.push((message_type, payload));
+            }
+            messages
+        });
+        (path, server)
+    }
+
+    fn config() -> config::HeadlessOutput {
+        config::HeadlessOutput {
+            width: 1920,
+            height: 1080,
+            refresh_rate: Some(60.0),
+            scale: Some(1.5),
+        }
+    }
+
+    fn commands(messages: &[(u32, String)]) -> Vec<&str> {
+        messages
+            .iter()
+            .filter(|(message_type, _)| *message_type == RUN_COMMAND)
+            .map(|(_, payload)| payload.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn create_and_remove() {
+        let (path, server) = stand_in("never");
+        let output = HeadlessOutput::create(&path, &config()).unwrap();
+        assert_eq!(output.name(), "HEADLESS-1");
+        drop(output);
+
+        let messages = server.join().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            commands(&messages),
+            [
+                "create_output",
+                "output HEADLESS-1 mode --custom 1920x1080@60Hz",
+                "output HEADLESS-1 scale 1.5",
+                "output HEADLESS-1 unplug",
+            ]
+        );
+        // Outputs are listed before and after creating one
+        assert_eq!(messages[0], (GET_OUTPUTS, String::new()));
+        assert_eq!(messages[2], (GET_OUTPUTS, String::new()));
+    }
+
+    #[test]
+    fn failed_command() {
+        let (path, server) = stand_in("scale");
+        let err = HeadlessOutput::create(&path, &config()).err().unwrap();
+        assert!(err.to_string().contains("refused"), "{}", err);
+
+        // The created output is removed
+        let messages = server.join().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            commands(&messages).last(),
+            Some(&"output HEADLESS-1 unplug")
+        );
+    }
+
+    #[test]
+    fn missing_socket() {
+        let path = env::temp_dir().join("okulekrano-test-missing.sock");
+        assert!(HeadlessOutput::create(&path, &config()).is_err());
+    }
+}
@@ -0,0 +1,157 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Statistics of latency between the compositor presenting a frame and okulekrano rendering it.
+// Reported every few seconds in debug log (`RUST_LOG=okulekrano::latency=debug`).
+
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+use crate::capturer::FrameTiming;
+
+const REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+pub struct LatencyStats {
+    capture: Vec<Duration>, // From presentation to the end of copying
+    age: Vec<Duration>,     // From presentation to the end of rendering
+    period_start: Instant,
+    last_report: Option<LatencyReport>,
+}
+
+// Statistics of one reporting period
+#[derive(Clone, Copy, Debug)]
+pub struct LatencyReport {
+    pub frames: usize,
+    pub capture: Distribution,
+    pub age: Distribution,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Distribution {
+    pub median: Duration,
+    pub p95: Duration,
+    pub max: Duration,
+}
+
+impl LatencyStats {
+    pub fn new() -> Self {
+        Self {
+            capture: Vec::new(),
+            age: Vec::new(),
+            period_start: Instant::now(),
+            last_report: None,
+        }
+    }
+
+    // Record a captured frame which has been rendered for the first time at `rendered` (CLOCK_MONOTONIC)
+    pub fn record(&mut self, timing: FrameTiming, rendered: Duration) {
+        self.capture
+            .push(timing.ready.saturating_sub(timing.presented));
+        self.age.push(rendered.saturating_sub(timing.presented));
+
+        if self.period_start.elapsed() >= REPORT_INTERVAL {
+            let report = LatencyReport {
+                frames: self.age.len(),
+                capture: Distribution::of(&mut self.capture),
+                age: Distribution::of(&mut self.age),
+            };
+            log::debug!("{}", report);
+
+            self.last_report = Some(report);
+            self.capture.clear();
+            self.age.clear();
+            self.period_start = Instant::now();
+        }
+    }
+
+    // Statistics of the latest complete period
+    pub fn last_report(&self) -> Option<LatencyReport> {
+        self.last_report
+    }
+}
+
+impl Distribution {
+    fn of(samples: &mut [Duration]) -> Self {
+        samples.sort_unstable();
+        let percentile = |p: usize| samples[(samples.len() - 1) * p / 100];
+        Self {
+            median: percentile(50),
+            p95: percentile(95),
+            max: percentile(100),
+        }
+    }
+}
+
+impl fmt::Display for LatencyReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Latency of {} frames (median/95%/max): capture {}, until rendered {}",
+            self.frames, self.capture, self.age
+        )
+    }
+}
+
+impl fmt::Display for Distribution {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:.1}/{:.1}/{:.1} ms",
+            self.median.as_secs_f64() * 1000.0,
+            self.p95.as_secs_f64() * 1000.0,
+            self.max.as_secs_f64() * 1000.0
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn percentiles() {
+        // 1 to 100 ms in a scrambled order
+        let mut samples: Vec<_> = (1..=100).map(|i| ms(i * 37 % 101)).collect();
+        let distribution = Distribution::of(&mut samples);
+        assert_eq!(distribution.median, ms(50));
+        assert_eq!(distribution.p95, ms(95));
+        assert_eq!(distribution.max, ms(100));
+    }
+
+    #[test]
+    fn single_sample() {
+        let distribution = Distribution::of(&mut [ms(7)]);
+        assert_eq!(distribution.median, ms(7));
+        assert_eq!(distribution.p95, ms(7));
+        assert_eq!(distribution.max, ms(7));
+        assert_eq!(distribution.to_string(), "7.0/7.0/7.0 ms");
+    }
+
+    #[test]
+    fn report_after_interval() {
+        let mut stats = LatencyStats::new();
+        let timing = |presented: u64| FrameTiming {
+            presented: ms(presented),
+            ready: ms(presented + 2),
+        };
+        stats.record(timing(100), ms(110));
+        assert!(stats.last_report().is_none());
+
+        stats.period_start = Instant::now() - REPORT_INTERVAL;
+        stats.record(timing(200), ms(220));
+        let report = stats.last_report().unwrap();
+        assert_eq!(report.frames, 2);
+        assert_eq!(report.capture.max, ms(2));
+        assert_eq!(report.age.median, ms(10));
+        assert_eq!(report.age.max, ms(20));
+        // A new period starts
+        assert!(stats.age.is_empty());
+    }
+}
@@ -0,0 +1,19 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+pub mod capturer;
+pub mod config;
+mod control;
+pub mod glasses;
+mod headless;
+mod latency;
+mod mode_refresh;
+pub mod offscreen;
+mod output_manager;
+mod output_match;
+pub mod renderer;
+mod snapshot;
+pub mod winit_app;
+
+extern crate nalgebra as na;
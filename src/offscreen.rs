@@ -0,0 +1,348 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Rendering without any window, using the surfaceless platform of Mesa.
+// Frames are drawn into textures, so it works without a display or GPU (e.g. llvmpipe in CI).
+// The offscreen mode renders poses from a recorded IMU trace into PNG files or a Y4M video.
+
+use std::{
+    cell::Cell,
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    os::raw::c_void,
+    path::{Path, PathBuf},
+    rc::Rc,
+    time::Duration,
+};
+
+use glium::{
+    backend::{Backend, Context},
+    debug::DebugCallbackBehavior,
+    framebuffer::SimpleFrameBuffer,
+    texture::RawImage2d,
+    SwapBuffersError, Texture2d,
+};
+use image::RgbaImage;
+use khronos_egl as egl;
+use na::UnitQuaternion;
+
+use crate::{
+    capturer::{
+        fake::{FakeCapturer, Pattern},
+        file::{FileCapturer, FileClock},
+        limiter::FrameLimiter,
+        Capturer,
+    },
+    config::{Backend as CaptureBackend, Config, Offscreen},
+    glasses::{FixedViewer, TracePlayback},
+    renderer::Renderer,
+};
+
+// EGL_MESA_platform_surfaceless
+const EGL_PLATFORM_SURFACELESS_MESA: egl::Enum = 0x31DD;
+
+struct OffscreenBackend {
+    egl: egl::Instance<egl::Static>,
+    display: egl::Display,
+    context: egl::Context,
+    dimensions: (u32, u32),
+}
+
+// `dimensions` is the size of the default framebuffer as seen by glium, which does not exist.
+// Draw into a `SimpleFrameBuffer` instead.
+pub fn create_context(dimensions: (u32, u32)) -> Result<Rc<Context>, String> {
+    let backend = OffscreenBackend::new(dimensions)?;
+    unsafe { Context::new(backend, true, DebugCallbackBehavior::Ignore) }
+        .map_err(|err| format!("incompatible OpenGL: {:?}", err))
+}
+
+// Destination of rendered frames
+enum FrameWriter {
+    Png { directory: PathBuf, count: u64 },
+    Y4m(Box<dyn Write>),
+}
+
+// Render frames at the poses from the IMU trace until its end
+pub fn run(config: &Config, offscreen: &Offscreen) {
+    let mut trace = TracePlayback::open(&offscreen.imu_trace).unwrap_or_else(|err| {
+        panic!(
+            "Cannot read IMU trace {}: {}",
+            offscreen.imu_trace.display(),
+            err
+        )
+    });
+    if !config.windows.is_empty() {
+        log::warn!("Windows are not captured in offscreen mode");
+    }
+
+    let (width, height) = offscreen.size;
+    let context = create_context((width, height))
+        .unwrap_or_else(|err| panic!("Cannot create offscreen context: {}", err));
+    // Images of files change with the time in the trace
+    let time = Rc::new(Cell::new(Duration::ZERO));
+    let capturer = capturer(&context, config, FileClock::Manual(Rc::clone(&time)));
+    let mut renderer = Renderer::with_capturers(&context, config, vec![capturer]);
+
+    let target = Texture2d::empty(&context, width, height).unwrap();
+    let mut framebuffer = SimpleFrameBuffer::new(&context, &target).unwrap();
+    let mut writer = FrameWriter::create(&offscreen.output, offscreen.size, offscreen.frame_rate)
+        .unwrap_or_else(|err| panic!("Cannot write to {}: {}", offscreen.output.display(), err));
+
+    let mut viewer = FixedViewer::new(UnitQuaternion::identity());
+    if let Some(fov) = offscreen.fov {
+        viewer.fov = fov.to_radians();
+    }
+
+    let (start, end) = trace.time_range();
+    let frame_interval = 1e6 / offscreen.frame_rate as f64; // Microseconds
+    let mut count = 0;
+    loop {
+        let timestamp = start + (count as f64 * frame_interval) as u64;
+        if timestamp > end {
+            break;
+        }
+        viewer.pose = trace.pose_at(timestamp);
+        time.set(Duration::from_micros(timestamp - start));
+        renderer.render_to(&mut framebuffer, &viewer);
+
+        let raw: RawImage2d<u8> = target.read();
+        let image = RgbaImage::from_raw(raw.width, raw.height, raw.data.into_owned()).unwrap();
+        // Rows of framebuffers start from the bottom
+        let image = image::imageops::flip_vertical(&image);
+        writer
+            .write(&image)
+            .unwrap_or_else(|err| panic!("Cannot write frame: {}", err));
+        count += 1;
+    }
+    writer.finish().unwrap();
+
+    log::info!("{} frames written to {}", count, offscreen.output.display());
+}
+
+// Only sources without a compositor are available
+fn capturer(context: &Rc<Context>, config: &Config, clock: FileClock) -> Box<dyn Capturer> {
+    let pattern = config
+        .capture
+        .output_name
+        .as_deref()
+        .and_then(Pattern::from_output_name);
+    let region = config.capture.region;
+    if let Some(pattern) = pattern {
+        // Animated once per rendered frame
+        Box::new(FakeCapturer::new(
+            context,
+            pattern,
+            region,
+            FrameLimiter::new(None),
+        ))
+    } else if config.capture.backend == CaptureBackend::File {
+        let source = config
+            .capture
+            .file
+            .as_ref()
+            .expect("[capture.file] is needed for the file backend");
+        Box::new(
+            FileCapturer::new(
+                context,
+                source,
+                region,
+                FrameLimiter::new(config.capture.max_frame_rate),
+                clock,
+            )
+            .unwrap_or_else(|err| panic!("Cannot show image files: {}", err)),
+        )
+    } else {
+        panic!("Offscreen mode can show only test patterns or image files");
+    }
+}
+
+impl FrameWriter {
+    fn create(path: &Path, size: (u32, u32), frame_rate: f32) -> io::Result<Self> {
+        let mut output: Box<dyn Write> = if path == Path::new("-") {
+            Box::new(BufWriter::new(io::stdout().lock()))
+        } else if path.extension().is_some_and(|extension| extension == "y4m") {
+            Box::new(BufWriter::new(File::create(path)?))
+        } else {
+            fs::create_dir_all(path)?;
+            return Ok(Self::Png {
+                directory: path.to_owned(),
+                count: 0,
+            });
+        };
+
+        // 4:2:0 is the most widely supported chroma subsampling
+        writeln!(
+            output,
+            "YUV4MPEG2 W{} H{} F{}:1000 Ip A1:1 C420jpeg",
+            size.0,
+            size.1,
+            (frame_rate * 1000.0).round() as u32
+        )?;
+        Ok(Self::Y4m(output))
+    }
+
+    fn write(&mut self, image: &RgbaImage) -> io::Result<()> {
+        match self {
+            Self::Png { directory, count } => {
+                let path = directory.join(format!("{:06}.png", count));
+                // Alpha is undefined
+                image::DynamicImage::ImageRgba8(image.clone())
+                    .into_rgb8()
+                    .save(path)
+                    .map_err(io::Error::other)?;
+                *count += 1;
+                Ok(())
+            }
+            Self::Y4m(output) => {
+                output.write_all(b"FRAME\n")?;
+                output.write_all(&yuv420(image))
+            }
+        }
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        match self {
+            Self::Png { .. } => Ok(()),
+            Self::Y4m(output) => output.flush(),
+        }
+    }
+}
+
+// Planar Y'CbCr 4:2:0 of BT.601 in limited range, with chroma averaged over 2x2 pixels
+fn yuv420(image: &RgbaImage) -> Vec<u8> {
+    let (width, height) = image.dimensions();
+    let (chroma_width, chroma_height) = (width.div_ceil(2), height.div_ceil(2));
+    let rgb = |x: u32, y: u32| {
+        let pixel = image.get_pixel(x.min(width - 1), y.min(height - 1)).0;
+        (pixel[0] as f32, pixel[1] as f32, pixel[2] as f32)
+    };
+
+    let mut data = Vec::with_capacity((width * height + 2 * chroma_width * chroma_height) as usize);
+    for y in 0..height {
+        for x in 0..width {
+            let (r, g, b) = rgb(x, y);
+            data.push((16.0 + 0.257 * r + 0.504 * g + 0.098 * b).round() as u8);
+        }
+    }
+
+    let mut cb = Vec::with_capacity((chroma_width * chroma_height) as usize);
+    let mut cr = Vec::with_capacity((chroma_width * chroma_height) as usize);
+    for y in 0..chroma_height {
+        for x in 0..chroma_width {
+            let (mut r, mut g, mut b) = (0.0, 0.0, 0.0);
+            for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                let pixel = rgb(x * 2 + dx, y * 2 + dy);
+                r += pixel.0 / 4.0;
+                g += pixel.1 / 4.0;
+                b += pixel.2 / 4.0;
+            }
+            cb.push((128.0 - 0.148 * r - 0.291 * g + 0.439 * b).round() as u8);
+            cr.push((128.0 + 0.439 * r - 0.368 * g - 0.071 * b).round() as u8);
+        }
+    }
+    data.extend(cb);
+    data.extend(cr);
+    data
+}
+
+impl OffscreenBackend {
+    fn new(dimensions: (u32, u32)) -> Result<Self, String> {
+        let egl = egl::Instance::new(egl::Static);
+        let display = unsafe {
+            egl.get_platform_display(
+                EGL_PLATFORM_SURFACELESS_MESA,
+                egl::DEFAULT_DISPLAY,
+                &[egl::ATTRIB_NONE],
+            )
+        }
+        .map_err(|err| format!("cannot get surfaceless EGL display: {}", err))?;
+        egl.initialize(display)
+            .map_err(|err| format!("cannot initialize EGL: {}", err))?;
+        egl.bind_api(egl::OPENGL_ES_API).unwrap();
+
+        // Same version as the window (see winit_app.rs)
+        let config = egl
+            .choose_first_config(
+                display,
+                &[
+                    egl::SURFACE_TYPE,
+                    egl::PBUFFER_BIT,
+                    egl::RENDERABLE_TYPE,
+                    egl::OPENGL_ES3_BIT,
+                    egl::RED_SIZE,
+                    8,
+                    egl::GREEN_SIZE,
+                    8,
+                    egl::BLUE_SIZE,
+                    8,
+                    egl::NONE,
+                ],
+            )
+            .map_err(|err| err.to_string())?
+            .ok_or("no EGL config for OpenGL ES 3")?;
+        let context = egl
+            .create_context(
+                display,
+                config,
+                None,
+                &[
+                    egl::CONTEXT_MAJOR_VERSION,
+                    3,
+                    egl::CONTEXT_MINOR_VERSION,
+                    1,
+                    egl::NONE,
+                ],
+            )
+            .map_err(|err| format!("cannot create EGL context: {}", err))?;
+
+        Ok(Self {
+            egl,
+            display,
+            context,
+            dimensions,
+        })
+    }
+}
+
+unsafe impl Backend for OffscreenBackend {
+    fn swap_buffers(&self) -> Result<(), SwapBuffersError> {
+        Ok(())
+    }
+
+    unsafe fn get_proc_address(&self, symbol: &str) -> *const c_void {
+        self.egl
+            .get_proc_address(symbol)
+            .map_or(std::ptr::null(), |function| function as *const c_void)
+    }
+
+    fn get_framebuffer_dimensions(&self) -> (u32, u32) {
+        self.dimensions
+    }
+
+    fn resize(&self, _new_size: (u32, u32)) {}
+
+    fn is_current(&self) -> bool {
+        self.egl.get_current_context() == Some(self.context)
+    }
+
+    unsafe fn make_current(&self) {
+        self.egl
+            .make_current(self.display, None, None, Some(self.context))
+            .unwrap();
+    }
+}
+
+impl Drop for OffscreenBackend {
+    fn drop(&mut self) {
+        // The display is shared by all contexts in the process, so it is not terminated
+        if self.is_current() {
+            self.egl
+                .make_current(self.display, None, None, None)
+                .unwrap();
+        }
+        self.egl
+            .destroy_context(self.display, self.context)
+            .unwrap();
+    }
+}
@@ -0,0 +1,602 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Configuration of outputs through wlr-output-management while okulekrano is running.
+// Changed properties are restored when dropped.
+
+use std::collections::HashMap;
+
+use wayland_client::{
+    event_created_child,
+    protocol::{
+        wl_output::Transform,
+        wl_registry::{self, WlRegistry},
+    },
+    Connection, Dispatch, DispatchError, EventQueue, Proxy, WEnum,
+};
+use wayland_protocols_wlr::output_management::v1::client::{
+    zwlr_output_configuration_head_v1::ZwlrOutputConfigurationHeadV1,
+    zwlr_output_configuration_v1::{self, ZwlrOutputConfigurationV1},
+    zwlr_output_head_v1::{self, ZwlrOutputHeadV1},
+    zwlr_output_manager_v1::{self, ZwlrOutputManagerV1},
+    zwlr_output_mode_v1::{self, ZwlrOutputModeV1},
+};
+
+use crate::{
+    config::{Config, OutputMatch},
+    output_match::OutputProperties,
+};
+
+// Horizontal gap between the glasses and other outputs when isolated (in logical pixels)
+const ISOLATION_GAP: i32 = 1000;
+
+// Configuration is retried when cancelled because outputs have changed meanwhile
+const MAX_ATTEMPTS: u32 = 3;
+
+pub struct OutputManager {
+    queue: EventQueue<State>,
+    state: State,
+    reverts: Vec<(ZwlrOutputHeadV1, HeadChange)>, // Original properties of changed outputs
+}
+
+#[derive(Default)]
+struct State {
+    manager: Option<ZwlrOutputManagerV1>,
+    heads: HashMap<ZwlrOutputHeadV1, HeadInfo>,
+    modes: HashMap<ZwlrOutputModeV1, ModeInfo>,
+    serial: Option<u32>, // Serial of the latest complete configuration
+    result: Option<ApplyResult>,
+}
+
+#[derive(Default)]
+struct HeadInfo {
+    properties: OutputProperties,
+    enabled: bool,
+    modes: Vec<ZwlrOutputModeV1>,
+    current_mode: Option<ZwlrOutputModeV1>,
+    position: (i32, i32),
+    transform: Option<Transform>,
+    scale: f64,
+}
+
+#[derive(Clone, Copy, Default)]
+struct ModeInfo {
+    width: i32,
+    height: i32,
+    refresh: i32, // In mHz
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ApplyResult {
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+// Properties of one output to be set. Unset ones are not changed.
+#[derive(Clone, Default)]
+struct HeadChange {
+    mode: Option<ModeSetting>,
+    position: Option<(i32, i32)>,
+    scale: Option<f64>,
+}
+
+#[derive(Clone)]
+enum ModeSetting {
+    Existing(ZwlrOutputModeV1),
+    Custom {
+        width: i32,
+        height: i32,
+        refresh: i32, // In mHz, or zero if unspecified
+    },
+}
+
+impl OutputManager {
+    // Returns None if nothing is configured or outputs cannot be configured
+    pub fn apply(config: &Config) -> Option<Self> {
+        let output_mode = config.capture.output_mode.as_ref();
+        let isolate = config.glasses.isolate && !config.glasses.window_mode;
+        if output_mode.is_none() && !isolate {
+            return None;
+        }
+
+        let conn = match Connection::connect_to_env() {
+            Ok(conn) => conn,
+            Err(err) => {
+                log::warn!("Cannot configure outputs: {}", err);
+                return None;
+            }
+        };
+        let mut queue = conn.new_event_queue();
+        let mut state = State::default();
+
+        // (1) Retrieve the output manager. Its heads (outputs) are sent after binding.
+        let _registry = conn.display().get_registry(&queue.handle(), ());
+        if let Err(err) = queue.roundtrip(&mut state) {
+            log::warn!("Cannot configure outputs: {}", err);
+            return None;
+        }
+        if state.manager.is_none() {
+            log::warn!(
+                "Cannot configure outputs: the compositor does not support wlr-output-management"
+            );
+            return None;
+        }
+        while state.serial.is_none() {
+            if let Err(err) = queue.blocking_dispatch(&mut state) {
+                log::warn!("Cannot configure outputs: {}", err);
+                return None;
+            }
+        }
+
+        let mut manager = Self {
+            queue,
+            state,
+            reverts: Vec::new(),
+        };
+
+        // (2) Decide new properties of the captured output and the glasses
+        let mut changes = Vec::new();
+        if let Some(output_mode) = output_mode {
+            match config.capture.output_match() {
+                Some(selector) => match manager.find_head(&selector) {
+                    Some(head) => {
+                        let info = &manager.state.heads[&head];
+                        let change = HeadChange {
+                            mode: manager.mode_setting(
+                                info,
+                                output_mode.size,
+                                output_mode.refresh_rate,
+                            ),
+                            position: None,
+                            scale: output_mode.scale.map(|scale| scale as f64),
+                        };
+                        changes.push((head, change));
+                    }
+                    None => log::warn!("Output {} to configure is not found", selector),
+                },
+                None => log::warn!("output_mode is ignored because the output is not set"),
+            }
+        }
+        if isolate {
+            match config.glasses.monitor_match() {
+                Some(selector) => match manager.find_head(&selector) {
+                    Some(head) => {
+                        let position = manager.isolated_position(&head, &changes);
+                        changes.push((
+                            head,
+                            HeadChange {
+                                position: Some(position),
+                                ..Default::default()
+                            },
+                        ));
+                    }
+                    None => log::warn!("Glasses output {} is not found", selector),
+                },
+                None => log::warn!("isolate is ignored because the glasses are not found"),
+            }
+        }
+        if changes.is_empty() {
+            return None;
+        }
+
+        // (3) Remember the original properties and apply the new ones
+        let reverts = changes
+            .iter()
+            .map(|(head, change)| (head.clone(), manager.original(head, change)))
+            .collect();
+        match manager.configure(&changes) {
+            Ok(true) => (),
+            Ok(false) => return None,
+            Err(err) => {
+                log::warn!("Cannot configure outputs: {}", err);
+                return None;
+            }
+        }
+        manager.reverts = reverts;
+        for (head, _) in &changes {
+            let info = &manager.state.heads[head];
+            log::info!(
+                "Configured output {}: {}x{} at {:?}, scale {}",
+                info.properties.name.as_deref().unwrap_or_default(),
+                info.current_mode
+                    .as_ref()
+                    .map_or(0, |mode| manager.state.modes[mode].width),
+                info.current_mode
+                    .as_ref()
+                    .map_or(0, |mode| manager.state.modes[mode].height),
+                info.position,
+                info.scale
+            );
+        }
+
+        Some(manager)
+    }
+
+    fn find_head(&self, selector: &OutputMatch) -> Option<ZwlrOutputHeadV1> {
+        self.state
+            .heads
+            .iter()
+            .find(|(_, info)| info.enabled && selector.matches(&info.properties))
+            .map(|(head, _)| head.clone())
+    }
+
+    // An advertised mode is preferred, because custom modes of physical outputs may not be displayed well
+    fn mode_setting(
+        &self,
+        info: &HeadInfo,
+        size: Option<(u32, u32)>,
+        refresh_rate: Option<f32>,
+    ) -> Option<ModeSetting> {
+        if size.is_none() && refresh_rate.is_none() {
+            return None;
+        }
+        let current = info
+            .current_mode
+            .as_ref()
+            .map(|mode| self.state.modes[mode])
+            .unwrap_or_default();
+        let (width, height) = size.map_or((current.width, current.height), |(width, height)| {
+            (width as i32, height as i32)
+        });
+        let refresh = refresh_rate.map_or(0, |rate| (rate * 1000.0).round() as i32);
+
+        let existing = info
+            .modes
+            .iter()
+            .filter(|&mode| {
+                let mode = &self.state.modes[mode];
+                mode.width == width
+                    && mode.height == height
+                    && (refresh == 0 || (mode.refresh - refresh).abs() < 500)
+            })
+            .max_by_key(|&mode| self.state.modes[mode].refresh);
+        Some(match existing {
+            Some(mode) => ModeSetting::Existing(mode.clone()),
+            None => ModeSetting::Custom {
+                width,
+                height,
+                refresh,
+            },
+        })
+    }
+
+    // Right of all other enabled outputs, with a gap so that the pointer cannot move there
+    fn isolated_position(
+        &self,
+        glasses: &ZwlrOutputHeadV1,
+        changes: &[(ZwlrOutputHeadV1, HeadChange)],
+    ) -> (i32, i32) {
+        let right = self
+            .state
+            .heads
+            .iter()
+            .filter(|(head, info)| *head != glasses && info.enabled)
+            .map(|(head, info)| {
+                let change = changes
+                    .iter()
+                    .find(|(changed, _)| changed == head)
+                    .map(|(_, change)| change);
+                let (width, _) = self.logical_size(info, change);
+                info.position.0 + width
+            })
+            .max()
+            .unwrap_or(0);
+        (right + ISOLATION_GAP, 0)
+    }
+
+    // Size in the layout, after `change` is applied
+    fn logical_size(&self, info: &HeadInfo, change: Option<&HeadChange>) -> (i32, i32) {
+        let (width, height) = match change.and_then(|change| change.mode.as_ref()) {
+            Some(ModeSetting::Existing(mode)) => {
+                let mode = &self.state.modes[mode];
+                (mode.width, mode.height)
+            }
+            Some(ModeSetting::Custom { width, height, .. }) => (*width, *height),
+            None => info.current_mode.as_ref().map_or((0, 0), |mode| {
+                let mode = &self.state.modes[mode];
+                (mode.width, mode.height)
+            }),
+        };
+        let scale = change.and_then(|change| change.scale).unwrap_or(info.scale);
+        let (width, height) = match info.transform {
+            Some(
+                Transform::_90 | Transform::_270 | Transform::Flipped90 | Transform::Flipped270,
+            ) => (height, width),
+            _ => (width, height),
+        };
+        (
+            (width as f64 / scale).ceil() as i32,
+            (height as f64 / scale).ceil() as i32,
+        )
+    }
+
+    // Current values of the properties which `change` sets
+    fn original(&self, head: &ZwlrOutputHeadV1, change: &HeadChange) -> HeadChange {
+        let info = &self.state.heads[head];
+        HeadChange {
+            mode: change
+                .mode
+                .as_ref()
+                .and(info.current_mode.as_ref())
+                .map(|mode| {
+                    // Virtual outputs report their custom mode as the only mode.
+                    // It cannot be set again as a mode, so it is set as a custom mode.
+                    if info.modes.len() > 1 {
+                        ModeSetting::Existing(mode.clone())
+                    } else {
+                        let mode = &self.state.modes[mode];
+                        ModeSetting::Custom {
+                            width: mode.width,
+                            height: mode.height,
+                            refresh: mode.refresh,
+                        }
+                    }
+                }),
+            position: change.position.map(|_| info.position),
+            scale: change.scale.map(|_| info.scale),
+        }
+    }
+
+    // Apply a configuration in which other outputs are unchanged. Returns whether it succeeded.
+    fn configure(
+        &mut self,
+        changes: &[(ZwlrOutputHeadV1, HeadChange)],
+    ) -> Result<bool, DispatchError> {
+        for _ in 0..MAX_ATTEMPTS {
+            // Configuration must be based on the latest state
+            self.queue.roundtrip(&mut self.state)?;
+            let qhandle = self.queue.handle();
+            let configuration = self.state.manager.as_ref().unwrap().create_configuration(
+                self.state.serial.unwrap(),
+                &qhandle,
+                (),
+            );
+
+            // Every output must be either enabled or disabled in a configuration
+            for (head, info) in &self.state.heads {
+                if !info.enabled {
+                    configuration.disable_head(head);
+                    continue;
+                }
+                let config_head = configuration.enable_head(head, &qhandle, ());
+                let Some((_, change)) = changes.iter().find(|(changed, _)| changed == head) else {
+                    continue;
+                };
+                match change.mode {
+                    Some(ModeSetting::Existing(ref mode)) => config_head.set_mode(mode),
+                    Some(ModeSetting::Custom {
+                        width,
+                        height,
+                        refresh,
+                    }) => config_head.set_custom_mode(width, height, refresh),
+                    None => (),
+                }
+                if let Some((x, y)) = change.position {
+                    config_head.set_position(x, y);
+                }
+                if let Some(scale) = change.scale {
+                    config_head.set_scale(scale);
+                }
+            }
+
+            configuration.apply();
+            self.state.result = None;
+            while self.state.result.is_none() {
+                self.queue.blocking_dispatch(&mut self.state)?;
+            }
+            configuration.destroy();
+
+            match self.state.result.unwrap() {
+                ApplyResult::Succeeded => {
+                    // Receive the new properties
+                    self.queue.roundtrip(&mut self.state)?;
+                    return Ok(true);
+                }
+                ApplyResult::Failed => {
+                    log::warn!("The compositor has rejected the output configuration");
+                    return Ok(false);
+                }
+                ApplyResult::Cancelled => {
+                    log::debug!("Output configuration has been cancelled. Retrying.")
+                }
+            }
+        }
+
+        log::warn!("Output configuration has been cancelled repeatedly");
+        Ok(false)
+    }
+}
+
+impl Drop for OutputManager {
+    fn drop(&mut self) {
+        // Outputs which have been removed meanwhile are not restored
+        if self.queue.roundtrip(&mut self.state).is_err() {
+            return;
+        }
+        let reverts: Vec<_> = std::mem::take(&mut self.reverts)
+            .into_iter()
+            .filter(|(head, _)| self.state.heads.contains_key(head))
+            .collect();
+        if reverts.is_empty() {
+            return;
+        }
+        match self.configure(&reverts) {
+            Ok(true) => log::info!("Restored output configuration"),
+            Ok(false) => (),
+            Err(err) => log::warn!("Failed to restore output configuration: {}", err),
+        }
+    }
+}
+
+impl Dispatch<WlRegistry, (), Self> for State {
+    fn event(
+        state: &mut Self,
+        proxy: &WlRegistry,
+        event: <WlRegistry as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        qhandle: &wayland_client::QueueHandle<Self>,
+    ) {
+        if let wl_registry::Event::Global {
+            name,
+            interface,
+            version,
+        } = event
+        {
+            if interface == ZwlrOutputManagerV1::interface().name {
+                state.manager = Some(proxy.bind(name, version.min(4), qhandle, ()));
+            }
+        }
+    }
+}
+
+impl Dispatch<ZwlrOutputManagerV1, ()> for State {
+    fn event(
+        state: &mut Self,
+        _proxy: &ZwlrOutputManagerV1,
+        event: <ZwlrOutputManagerV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &wayland_client::QueueHandle<Self>,
+    ) {
+        match event {
+            zwlr_output_manager_v1::Event::Head { head } => {
+                state.heads.insert(head, HeadInfo::default());
+            }
+            zwlr_output_manager_v1::Event::Done { serial } => {
+                state.serial = Some(serial);
+            }
+            _ => (),
+        }
+    }
+
+    event_created_child!(State, ZwlrOutputManagerV1, [
+        zwlr_output_manager_v1::EVT_HEAD_OPCODE => (ZwlrOutputHeadV1, ()),
+    ]);
+}
+
+impl Dispatch<ZwlrOutputHeadV1, ()> for State {
+    fn event(
+        state: &mut Self,
+        proxy: &ZwlrOutputHeadV1,
+        event: <ZwlrOutputHeadV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &wayland_client::QueueHandle<Self>,
+    ) {
+        if let zwlr_output_head_v1::Event::Finished = event {
+            if let Some(info) = state.heads.remove(proxy) {
+                for mode in info.modes {
+                    state.modes.remove(&mode);
+                }
+            }
+            if proxy.version() >= 3 {
+                proxy.release();
+            }
+            return;
+        }
+
+        let Some(info) = state.heads.get_mut(proxy) else {
+            return;
+        };
+        match event {
+            zwlr_output_head_v1::Event::Name { name } => info.properties.name = Some(name),
+            zwlr_output_head_v1::Event::Description { description } => {
+                info.properties.description = Some(description)
+            }
+            zwlr_output_head_v1::Event::Make { make } => info.properties.make = Some(make),
+            zwlr_output_head_v1::Event::Model { model } => info.properties.model = Some(model),
+            zwlr_output_head_v1::Event::SerialNumber { serial_number } => {
+                info.properties.serial = Some(serial_number)
+            }
+            zwlr_output_head_v1::Event::Mode { mode } => {
+                info.modes.push(mode.clone());
+                state.modes.insert(mode, ModeInfo::default());
+            }
+            zwlr_output_head_v1::Event::Enabled { enabled } => info.enabled = enabled != 0,
+            zwlr_output_head_v1::Event::CurrentMode { mode } => info.current_mode = Some(mode),
+            zwlr_output_head_v1::Event::Position { x, y } => info.position = (x, y),
+            zwlr_output_head_v1::Event::Transform { transform } => {
+                info.transform = match transform {
+                    WEnum::Value(transform) => Some(transform),
+                    WEnum::Unknown(_) => None,
+                }
+            }
+            zwlr_output_head_v1::Event::Scale { scale } => info.scale = scale,
+            _ => (),
+        }
+    }
+
+    event_created_child!(State, ZwlrOutputHeadV1, [
+        zwlr_output_head_v1::EVT_MODE_OPCODE => (ZwlrOutputModeV1, ()),
+    ]);
+}
+
+impl Dispatch<ZwlrOutputModeV1, ()> for State {
+    fn event(
+        state: &mut Self,
+        proxy: &ZwlrOutputModeV1,
+        event: <ZwlrOutputModeV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &wayland_client::QueueHandle<Self>,
+    ) {
+        if let zwlr_output_mode_v1::Event::Finished = event {
+            state.modes.remove(proxy);
+            for info in state.heads.values_mut() {
+                info.modes.retain(|mode| mode != proxy);
+                if info.current_mode.as_ref() == Some(proxy) {
+                    info.current_mode = None;
+                }
+            }
+            if proxy.version() >= 3 {
+                proxy.release();
+            }
+            return;
+        }
+
+        let Some(info) = state.modes.get_mut(proxy) else {
+            return;
+        };
+        match event {
+            zwlr_output_mode_v1::Event::Size { width, height } => {
+                info.width = width;
+                info.height = height;
+            }
+            zwlr_output_mode_v1::Event::Refresh { refresh } => info.refresh = refresh,
+            _ => (),
+        }
+    }
+}
+
+impl Dispatch<ZwlrOutputConfigurationV1, ()> for State {
+    fn event(
+        state: &mut Self,
+        _proxy: &ZwlrOutputConfigurationV1,
+        event: <ZwlrOutputConfigurationV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &wayland_client::QueueHandle<Self>,
+    ) {
+        state.result = match event {
+            zwlr_output_configuration_v1::Event::Succeeded => Some(ApplyResult::Succeeded),
+            zwlr_output_configuration_v1::Event::Failed => Some(ApplyResult::Failed),
+            zwlr_output_configuration_v1::Event::Cancelled => Some(ApplyResult::Cancelled),
+            _ => return,
+        };
+    }
+}
+
+impl Dispatch<ZwlrOutputConfigurationHeadV1, ()> for State {
+    fn event(
+        _state: &mut Self,
+        _proxy: &ZwlrOutputConfigurationHeadV1,
+        _event: <ZwlrOutputConfigurationHeadV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &wayland_client::QueueHandle<Self>,
+    ) {
+    }
+}
@@ -0,0 +1,208 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Selection of outputs by their properties, which stay the same across reboots and docks unlike names
+
+use std::fmt;
+
+use crate::config::OutputMatch;
+
+// Make and model (joined with a space) reported by AR glasses in their EDID
+const KNOWN_GLASSES: &[&str] = &[
+    "MRG *", // XREAL (Nreal)
+    "*XREAL*",
+    "*Nreal*",
+    "*Rokid*",
+    "*VITURE*",
+    "*Grawoow*",
+    "*Mad Gaze*",
+];
+
+// Properties of an output reported by the compositor. Unknown ones are None.
+#[derive(Clone, Debug, Default)]
+pub struct OutputProperties {
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub make: Option<String>,
+    pub model: Option<String>,
+    pub serial: Option<String>,
+}
+
+impl OutputMatch {
+    pub fn by_name(name: &str) -> Self {
+        Self {
+            name: Some(name.to_owned()),
+            ..Default::default()
+        }
+    }
+
+    // All specified patterns have to match
+    pub fn matches(&self, properties: &OutputProperties) -> bool {
+        let pairs = [
+            (&self.name, &properties.name),
+            (&self.description, &properties.description),
+            (&self.make, &properties.make),
+            (&self.model, &properties.model),
+            (&self.serial, &properties.serial),
+        ];
+        pairs.iter().all(|(pattern, value)| match (pattern, value) {
+            (None, _) => true,
+            (Some(pattern), Some(value)) => glob_match(pattern, value),
+            (Some(_), None) => false,
+        })
+    }
+}
+
+impl fmt::Display for OutputMatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let fields = [
+            ("name", &self.name),
+            ("description", &self.description),
+            ("make", &self.make),
+            ("model", &self.model),
+            ("serial", &self.serial),
+        ];
+        let mut first = true;
+        for (key, pattern) in fields {
+            if let Some(pattern) = pattern {
+                write!(
+                    f,
+                    "{}{} = {:?}",
+                    if first { "" } else { ", " },
+                    key,
+                    pattern
+                )?;
+                first = false;
+            }
+        }
+        if first {
+            write!(f, "(any)")?;
+        }
+        Ok(())
+    }
+}
+
+impl OutputProperties {
+    pub fn is_glasses(&self) -> bool {
+        let make_model = format!(
+            "{} {}",
+            self.make.as_deref().unwrap_or_default(),
+            self.model.as_deref().unwrap_or_default()
+        );
+        KNOWN_GLASSES
+            .iter()
+            .any(|pattern| glob_match(pattern, &make_model))
+    }
+}
+
+// Shell-style pattern, where `*` matches any string and `?` matches any character
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    // Position after the last `*` in the pattern, and where it started matching in the text
+    let mut star: Option<(usize, usize)> = None;
+    let (mut p, mut t) = (0, 0);
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p + 1, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            // Let the `*` match one more character
+            p = star_p;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn properties(make: &str, model: &str) -> OutputProperties {
+        OutputProperties {
+            name: Some("DP-1".to_owned()),
+            make: Some(make.to_owned()),
+            model: Some(model.to_owned()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn glob() {
+        assert!(glob_match("", ""));
+        assert!(!glob_match("", "DP-1"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("DP-*", "DP-"));
+        assert!(glob_match("DP-*", "DP-10"));
+        assert!(!glob_match("DP-*", "HDMI-A-1"));
+        assert!(glob_match("*-1", "HDMI-A-1"));
+        assert!(glob_match("a*b*c", "aXbYbZc"));
+        assert!(!glob_match("a*b*c", "aXbYbZ"));
+        assert!(glob_match("DP-?", "DP-1"));
+        assert!(!glob_match("DP-?", "DP-10"));
+        // `?` is a character, not a byte
+        assert!(glob_match("?cran", "écran"));
+        assert!(glob_match("??", "日本"));
+        assert!(!glob_match("?", "日本"));
+    }
+
+    #[test]
+    fn matches() {
+        let output = properties("MRG", "Air");
+        assert!(OutputMatch::default().matches(&output));
+        assert!(OutputMatch::by_name("DP-*").matches(&output));
+        assert!(!OutputMatch::by_name("HDMI-*").matches(&output));
+
+        let selector = OutputMatch {
+            make: Some("MRG".to_owned()),
+            model: Some("Air*".to_owned()),
+            ..Default::default()
+        };
+        assert!(selector.matches(&output));
+        assert!(!selector.matches(&properties("MRG", "One")));
+
+        // A pattern does not match an unknown property
+        let selector = OutputMatch {
+            serial: Some("*".to_owned()),
+            ..Default::default()
+        };
+        assert!(!selector.matches(&output));
+    }
+
+    #[test]
+    fn glasses() {
+        for (make, model) in [
+            ("MRG", "Air"),
+            ("MRG", "XREAL One"),
+            ("Rokid", "Max"),
+            ("VITURE", "Pro"),
+        ] {
+            assert!(properties(make, model).is_glasses(), "{} {}", make, model);
+        }
+        for (make, model) in [("Dell Inc.", "U2720Q"), ("MRGX", "Monitor"), ("", "")] {
+            assert!(!properties(make, model).is_glasses(), "{} {}", make, model);
+        }
+        assert!(!OutputProperties::default().is_glasses());
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(OutputMatch::default().to_string(), "(any)");
+        assert_eq!(OutputMatch::by_name("DP-1").to_string(), "name = \"DP-1\"");
+        let selector = OutputMatch {
+            make: Some("MRG".to_owned()),
+            serial: Some("0x1*".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(selector.to_string(), "make = \"MRG\", serial = \"0x1*\"");
+    }
+}
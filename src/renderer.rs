@@ -2,22 +2,51 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    rc::Rc,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
 
 use glium::{
+    backend::{Context, Facade},
+    framebuffer::SimpleFrameBuffer,
     glutin::surface::WindowSurface,
     implement_vertex,
     index::{NoIndices, PrimitiveType},
-    uniform, Display, DrawParameters, Frame, Program, Rect, Surface, Texture2d, VertexBuffer,
+    texture::RawImage2d,
+    uniform, Blend, BlendingFunction, Display, DrawParameters, Frame, LinearBlendingFactor,
+    Program, Rect, Surface, Texture2d, VertexBuffer,
 };
-use na::{Matrix4, Scale3, Translation3};
+use image::RgbaImage;
+use na::{Matrix4, Rotation3, Scale3, Translation3, Vector3};
+use tinyjson::JsonValue;
+
+use mesh::ScreenSurface;
 
+#[cfg(feature = "pipewire")]
+use crate::capturer::pipewire::PipeWireCapturer;
 use crate::{
-    capturer::{fake::FakeCapturer, wayland::WaylandCapturer, Capturer},
-    config::Config,
-    glasses::GlassesController,
+    capturer::{
+        fake::{FakeCapturer, Pattern},
+        file::{FileCapturer, FileClock},
+        limiter::FrameLimiter,
+        monotonic_now,
+        retry::Retrying,
+        wayland::{cursor::CursorCapturer, WaylandCapturer},
+        x11::X11Capturer,
+        CaptureError, Capturer, FrameTiming, Unavailable,
+    },
+    config::{Backend, Config, CursorMode, Region, ScreenCommand, VirtualScreen},
+    glasses::Viewer,
+    latency::LatencyStats,
+    snapshot::{self, number, Snapshot},
 };
 
+mod mesh;
+
 #[repr(C)]
 #[derive(Copy, Clone, Debug)]
 struct Vertex {
@@ -27,6 +56,9 @@ struct Vertex {
 
 implement_vertex!(Vertex, position, tex_coords);
 
+// Head movements smaller than this (in matrix elements) do not trigger redrawing
+const CAMERA_EPSILON: f32 = 1e-5;
+
 const QUAD_VERTICES: &[Vertex] = &[
     Vertex {
         position: [-0.5, 0.5, 0.0],
@@ -54,125 +86,814 @@ const QUAD_VERTICES: &[Vertex] = &[
     }, // top right
 ];
 
+// Blending for images with premultiplied alpha
+const PREMULTIPLIED_ALPHA_BLENDING: Blend = Blend {
+    color: BlendingFunction::Addition {
+        source: LinearBlendingFactor::One,
+        destination: LinearBlendingFactor::OneMinusSourceAlpha,
+    },
+    alpha: BlendingFunction::Addition {
+        source: LinearBlendingFactor::One,
+        destination: LinearBlendingFactor::OneMinusSourceAlpha,
+    },
+    constant_value: (0.0, 0.0, 0.0, 0.0),
+};
+
+// Horizontal angle between screens placed next to each other, in degrees
+const SCREEN_SPACING: f32 = 40.0;
+
+// Changes of the placement by each command
+const DISTANCE_STEP: f32 = 1.1; // Ratio
+const SIZE_STEP: f32 = 1.1; // Ratio
+const ANGLE_STEP: f32 = 5.0; // Degrees
+
+// Within the clipping planes of the cameras
+const MIN_DISTANCE: f32 = 0.3;
+const MAX_DISTANCE: f32 = 8.0;
+// Time constant of moving toward the target placement
+const ANIMATION_TIME: Duration = Duration::from_millis(100);
+
 pub struct Renderer {
-    display: Arc<Display<WindowSurface>>,
+    context: Rc<Context>,
     vertex_buffer: VertexBuffer<Vertex>,
     index_buffer: NoIndices,
     program: Program,
+    cursor_program: Program,
+    placeholder: Arc<Texture2d>, // Shown while a screen cannot be captured
+    screens: Vec<Screen>,
+    last_cameras: Option<(Matrix4<f32>, Matrix4<f32>)>, // Camera matrices used in the last drawn frame
+    latency: LatencyStats,
+    config: Config,
+    snapshot_requested: bool,
+    last_update: Instant, // For animation of the placement
+}
+
+// Virtual screen showing one capture source
+struct Screen {
     capturer: Box<dyn Capturer>,
-    screen_transform: Matrix4<f32>, // Position and rotation of the virtual screen in world coordinates
+    config: VirtualScreen,
+    window_index: Option<usize>, // Index in `windows` of the config, or None for `virtual_screen`
+    initial: Placement,          // Placement at startup, for `ScreenCommand::Reset`
+    target: Placement,           // Placement changed by commands
+    placement: Placement,        // Current placement, moving toward `target`
+    texture: Option<Arc<Texture2d>>, // Latest captured frame
+    resolution: (u32, u32),      // Size of the captured frame used for `surface`
+    transform: Matrix4<f32>,     // Position and rotation of the virtual screen in world coordinates
+    surface: ScreenSurface,      // Shape and size of the screen, following the captured frame
+    mesh: Option<(ScreenSurface, VertexBuffer<Vertex>)>, // Vertices of normalized `surface`
+    mesh_scale: Vector3<f32>,    // Scale from the mesh to `surface`
+    region: Option<Region>,      // Captured part of the output
+    cursor: Option<CursorOverlay>,
+}
+
+// Part of the placement of a screen changed at runtime, in the units of the config
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Placement {
+    distance: f32,
+    height: f32,
+    yaw: f32,
+    pitch: f32,
+}
+
+// Mouse cursor drawn over a screen
+struct CursorOverlay {
+    capturer: Retrying<CursorCapturer>,
+    scale: f32,
+    depth: f32,
+    texture: Option<Arc<Texture2d>>, // None if the cursor is not on the screen
+    position: (i32, i32),            // Position of the hotspot in the captured output
+    hotspot: (i32, i32),
 }
 
 impl Renderer {
     pub fn new(display: Arc<Display<WindowSurface>>, config: &Config) -> Self {
-        let vertex_buffer = VertexBuffer::new(display.as_ref(), QUAD_VERTICES).unwrap();
+        let mut screens = Vec::new();
+
+        // The output is not captured if only windows are specified
+        if config.capture.output_match().is_some() || config.windows.is_empty() {
+            let pattern = config
+                .capture
+                .output_name
+                .as_deref()
+                .and_then(Pattern::from_output_name);
+            let fake = pattern.is_some();
+            let pipewire = config.capture.backend == Backend::Pipewire;
+            let x11 = config.capture.backend == Backend::X11;
+            let file = config.capture.backend == Backend::File;
+
+            // Cursor overlay uses Wayland protocols
+            let mut overlay_cursor = config.capture.cursor == CursorMode::Overlay;
+            if overlay_cursor && (fake || pipewire || x11 || file) {
+                log::warn!("Cursor overlay is only available with Wayland capture");
+                overlay_cursor = false;
+            }
+            let mut region = config.capture.region;
+            if region.is_some() && pipewire {
+                log::warn!("Region is not available with PipeWire capture");
+                region = None;
+            }
+
+            let limiter = FrameLimiter::new(config.capture.max_frame_rate);
+            let capturer: Box<dyn Capturer> = if let Some(pattern) = pattern {
+                Box::new(FakeCapturer::new(
+                    display.as_ref(),
+                    pattern,
+                    region,
+                    limiter,
+                ))
+            } else if file {
+                match &config.capture.file {
+                    Some(source) => FileCapturer::new(
+                        display.as_ref(),
+                        source,
+                        region,
+                        limiter,
+                        FileClock::Wall,
+                    )
+                    .map(|capturer| Box::new(capturer) as Box<dyn Capturer>),
+                    None => Err(CaptureError::Unsupported(
+                        "file backend without [capture.file]".to_owned(),
+                    )),
+                }
+                .unwrap_or_else(unavailable)
+            } else if pipewire {
+                pipewire_capturer(&display, config.capture.pipewire_node, limiter)
+                    .unwrap_or_else(unavailable)
+            } else if x11 {
+                // Recreated when e.g. the monitor appears
+                let display = Arc::clone(&display);
+                let output = config.capture.output_match();
+                Box::new(Retrying::new(move || {
+                    X11Capturer::new(display.as_ref(), output.as_ref(), region, limiter)
+                }))
+            } else {
+                // Recreated when e.g. the compositor restarts
+                let display = Arc::clone(&display);
+                let output = config.capture.output_match();
+                let allocator = config.capture.allocator;
+                Box::new(Retrying::new(move || {
+                    WaylandCapturer::new(
+                        Arc::clone(&display),
+                        output.as_ref(),
+                        region,
+                        !overlay_cursor,
+                        limiter,
+                        allocator,
+                    )
+                }))
+            };
+
+            let cursor = overlay_cursor.then(|| CursorOverlay {
+                capturer: {
+                    let display = Arc::clone(&display);
+                    let output = config.capture.output_match();
+                    let allocator = config.capture.allocator;
+                    Retrying::new(move || {
+                        CursorCapturer::new(Arc::clone(&display), output.as_ref(), allocator)
+                    })
+                },
+                scale: config.capture.cursor_scale,
+                depth: config.capture.cursor_depth,
+                texture: None,
+                position: (0, 0),
+                hotspot: (0, 0),
+            });
+
+            screens.push(Screen::new(
+                capturer,
+                config.virtual_screen.clone(),
+                None,
+                region,
+                cursor,
+            ));
+        }
+
+        for (index, window) in config.windows.iter().enumerate() {
+            let capturer = {
+                let display = Arc::clone(&display);
+                let (app_id, title) = (window.app_id.clone(), window.title.clone());
+                let max_frame_rate = config.capture.max_frame_rate;
+                let allocator = config.capture.allocator;
+                Retrying::new(move || {
+                    WaylandCapturer::new_window(
+                        Arc::clone(&display),
+                        app_id.as_deref(),
+                        title.as_deref(),
+                        FrameLimiter::new(max_frame_rate),
+                        allocator,
+                    )
+                })
+            };
+            screens.push(Screen::new(
+                Box::new(capturer),
+                window.virtual_screen.clone(),
+                Some(index),
+                None,
+                None,
+            ));
+        }
+
+        Self::with_screens(display.get_context(), config, screens)
+    }
+
+    // Show frames of given capturers (e.g. in tests), with the main virtual screen config
+    pub fn with_capturers(
+        facade: &impl Facade,
+        config: &Config,
+        capturers: Vec<Box<dyn Capturer>>,
+    ) -> Self {
+        let screens = capturers
+            .into_iter()
+            .map(|capturer| Screen::new(capturer, config.virtual_screen.clone(), None, None, None))
+            .collect();
+        Self::with_screens(facade.get_context(), config, screens)
+    }
+
+    fn with_screens(context: &Rc<Context>, config: &Config, mut screens: Vec<Screen>) -> Self {
+        let vertex_buffer = VertexBuffer::new(context, QUAD_VERTICES).unwrap();
         let index_buffer = NoIndices(PrimitiveType::TrianglesList);
         let program = Program::from_source(
-            display.as_ref(),
+            context,
             include_str!("shader.vert"),
             include_str!("shader.frag"),
             None,
         )
         .unwrap();
+        let cursor_program = Program::from_source(
+            context,
+            include_str!("shader.vert"),
+            include_str!("cursor.frag"),
+            None,
+        )
+        .unwrap();
 
-        let capturer: Box<dyn Capturer> =
-            if let Some("_fake_desktop") = config.capture.output_name.as_deref() {
-                Box::new(FakeCapturer::new(display.as_ref()))
-            } else {
-                Box::new(WaylandCapturer::new(
-                    Arc::clone(&display),
-                    config.capture.output_name.as_deref(),
-                ))
-            };
+        // Dark gray 16:9 image. Shared only with screens on this thread.
+        #[allow(clippy::arc_with_non_send_sync)]
+        let placeholder = Arc::new(
+            Texture2d::new(context, vec![vec![(48u8, 48u8, 48u8, 255u8); 16]; 9]).unwrap(),
+        );
 
-        let resolution = capturer.resolution();
-        let aspect = resolution.0 as f32 / resolution.1 as f32;
-        let screen_transform = Translation3::new(0.0, 0.0, -config.virtual_screen.distance)
-            .to_homogeneous()
-            * Scale3::new(
-                config.virtual_screen.height * aspect,
-                config.virtual_screen.height,
-                1.0,
-            )
-            .to_homogeneous();
+        // Place screens alternately to the right and left of the first one, unless specified
+        for (i, screen) in screens.iter_mut().enumerate() {
+            if screen.config.yaw.is_none() {
+                let side = if i % 2 == 1 { 1.0 } else { -1.0 };
+                screen.initial.yaw = side * i.div_ceil(2) as f32 * SCREEN_SPACING;
+                screen.target = screen.initial;
+                screen.placement = screen.initial;
+            }
+        }
 
         Self {
-            display,
+            context: Rc::clone(context),
             vertex_buffer,
             index_buffer,
             program,
-            capturer,
-            screen_transform,
+            cursor_program,
+            placeholder,
+            screens,
+            last_cameras: None,
+            latency: LatencyStats::new(),
+            config: config.clone(),
+            snapshot_requested: false,
+            last_update: Instant::now(),
         }
     }
 
-    pub fn render(&mut self, glasses: &GlassesController) {
-        let texture = self.capturer.capture();
+    // Draw into the window, unless neither the captured frames nor the head pose has changed
+    pub fn render(&mut self, viewer: &impl Viewer) {
+        let (damaged, new_frames) = self.update_screens();
 
-        let mut frame = self.display.draw();
+        let dimensions = self.context.get_framebuffer_dimensions();
+        let cameras = eye_cameras(viewer, dimensions);
 
-        frame.clear_color(0.0, 0.0, 0.0, 1.0);
+        if self.snapshot_requested {
+            self.snapshot_requested = false;
+            self.save_snapshot(&cameras, viewer);
+        }
 
-        let (width, height) = frame.get_dimensions();
-        let aspect = width as f32 / 2.0 / height as f32;
-        self.render_view(
-            &mut frame,
-            &texture,
-            &glasses.camera_mat(ar_drivers::Side::Left, aspect),
-            -1.0,
-            0.0,
-        );
-        self.render_view(
-            &mut frame,
-            &texture,
-            &glasses.camera_mat(ar_drivers::Side::Right, aspect),
-            0.0,
-            1.0,
-        );
+        // Skip drawing if neither the desktop nor the head pose has changed
+        if !damaged {
+            if let Some(ref last_cameras) = self.last_cameras {
+                if (cameras.0 - last_cameras.0).amax() < CAMERA_EPSILON
+                    && (cameras.1 - last_cameras.1).amax() < CAMERA_EPSILON
+                {
+                    return;
+                }
+            }
+        }
 
+        let mut frame = Frame::new(Rc::clone(&self.context), dimensions);
+        self.draw(&mut frame, &cameras);
         frame.finish().unwrap();
+
+        let rendered = monotonic_now();
+        for timing in new_frames {
+            self.latency.record(timing, rendered);
+        }
+
+        self.last_cameras = Some(cameras);
+    }
+
+    // Capture and draw into `surface` every time, for rendering without a window
+    pub fn render_to(&mut self, surface: &mut impl Surface, viewer: &impl Viewer) {
+        self.update_screens();
+        let cameras = eye_cameras(viewer, surface.get_dimensions());
+        self.draw(surface, &cameras);
+    }
+
+    // Capture a frame of each screen. Returns whether any screen has changed, and the timing of new
+    // frames.
+    fn update_screens(&mut self) -> (bool, Vec<FrameTiming>) {
+        let now = Instant::now();
+        let elapsed = now - self.last_update;
+        self.last_update = now;
+
+        let mut damaged = false;
+        let mut new_frames = Vec::new(); // Timing of frames rendered for the first time
+        for screen in &mut self.screens {
+            damaged |= screen.animate(&self.context, elapsed);
+
+            // The cursor moves even while the screen itself has no new frame
+            if let Some(ref mut cursor) = screen.cursor {
+                damaged |= cursor.update();
+            }
+
+            let captured = match screen.capturer.capture() {
+                Ok(captured) => captured,
+                Err(CaptureError::NotReady) => continue,
+                Err(CaptureError::SourceLost) => {
+                    // Hide the screen until a matching window appears again
+                    damaged |= screen.texture.take().is_some();
+                    continue;
+                }
+                Err(_) => {
+                    // Keep showing the last frame, or the placeholder if there is none
+                    if screen.texture.is_none() {
+                        if screen.resolution == (0, 0) {
+                            screen.update_transform(&self.context, self.placeholder.dimensions());
+                        }
+                        screen.texture = Some(Arc::clone(&self.placeholder));
+                        damaged = true;
+                    }
+                    continue;
+                }
+            };
+
+            // Follow changes of the captured resolution (e.g. mode change of the virtual output)
+            let resolution = captured.texture.dimensions();
+            if resolution != screen.resolution {
+                screen.update_transform(&self.context, resolution);
+                damaged = true;
+            }
+            damaged |= captured.is_damaged() || screen.texture.is_none();
+            if let Some(timing) = captured.timing.filter(|_| captured.is_damaged()) {
+                new_frames.push(timing);
+            }
+
+            screen.texture = Some(captured.texture);
+        }
+
+        (damaged, new_frames)
+    }
+
+    pub fn latency_stats(&self) -> &LatencyStats {
+        &self.latency
+    }
+
+    // Change the placement of all screens. They move there smoothly in the following frames.
+    pub fn control(&mut self, command: ScreenCommand) {
+        for screen in &mut self.screens {
+            let target = &mut screen.target;
+            match command {
+                ScreenCommand::Closer => {
+                    target.distance = (target.distance / DISTANCE_STEP).max(MIN_DISTANCE)
+                }
+                ScreenCommand::Farther => {
+                    target.distance = (target.distance * DISTANCE_STEP).min(MAX_DISTANCE)
+                }
+                ScreenCommand::Larger => target.height *= SIZE_STEP,
+                ScreenCommand::Smaller => target.height /= SIZE_STEP,
+                ScreenCommand::Left => target.yaw -= ANGLE_STEP,
+                ScreenCommand::Right => target.yaw += ANGLE_STEP,
+                ScreenCommand::Up => target.pitch = (target.pitch + ANGLE_STEP).min(90.0),
+                ScreenCommand::Down => target.pitch = (target.pitch - ANGLE_STEP).max(-90.0),
+                ScreenCommand::Reset => *target = screen.initial,
+                // Needs the config file, see `store_placement`
+                ScreenCommand::Save => (),
+            }
+        }
+    }
+
+    // Move the screens to the placement set by commands at once, without animation (e.g. for tests)
+    pub fn finish_animation(&mut self) {
+        for screen in &mut self.screens {
+            screen.placement = screen.target;
+            // Otherwise placed when the first frame arrives
+            if screen.resolution != (0, 0) {
+                screen.update_transform(&self.context, screen.resolution);
+            }
+        }
+        // Redraw at the next rendering
+        self.last_cameras = None;
+    }
+
+    // Write the placement changed by commands into `config`, from which the screens were created
+    pub fn store_placement(&self, config: &mut Config) {
+        for screen in &self.screens {
+            let slot = match screen.window_index {
+                None => &mut config.virtual_screen,
+                Some(index) => match config.windows.get_mut(index) {
+                    Some(window) => &mut window.virtual_screen,
+                    None => continue,
+                },
+            };
+            let target = &screen.target;
+            slot.distance = target.distance;
+            slot.height = target.height;
+            slot.pitch = target.pitch;
+            // Automatic placement is kept unless the screen is moved
+            if screen.config.yaw.is_some() || target.yaw != screen.initial.yaw {
+                slot.yaw = Some(target.yaw);
+            }
+        }
+    }
+
+    // Save the captured frames and the rendered frame at the next rendering
+    pub fn request_snapshot(&mut self) {
+        self.snapshot_requested = true;
+    }
+
+    // Draw the view of each eye side by side
+    fn draw(&self, surface: &mut impl Surface, cameras: &(Matrix4<f32>, Matrix4<f32>)) {
+        surface.clear_color(0.0, 0.0, 0.0, 1.0);
+
+        for (camera, viewport_left_ndc, viewport_right_ndc) in
+            [(&cameras.0, -1.0, 0.0), (&cameras.1, 0.0, 1.0)]
+        {
+            let viewport = eye_viewport(surface, viewport_left_ndc, viewport_right_ndc);
+
+            for screen in &self.screens {
+                let (Some(ref texture), Some((_, ref mesh))) = (&screen.texture, &screen.mesh)
+                else {
+                    continue;
+                };
+                self.draw_layer(
+                    surface,
+                    viewport,
+                    &self.program,
+                    mesh,
+                    texture,
+                    &(camera
+                        * screen.transform
+                        * Matrix4::new_nonuniform_scaling(&screen.mesh_scale)),
+                    Blend::default(),
+                );
+
+                if let Some((cursor_texture, cursor_transform)) = screen.cursor_layer() {
+                    self.draw_layer(
+                        surface,
+                        viewport,
+                        &self.cursor_program,
+                        &self.vertex_buffer,
+                        cursor_texture,
+                        &(camera * cursor_transform),
+                        PREMULTIPLIED_ALPHA_BLENDING,
+                    );
+                }
+            }
+        }
+    }
+
+    // Read back the captured frames and draw the frame offscreen, then save them on another thread
+    fn save_snapshot(&self, cameras: &(Matrix4<f32>, Matrix4<f32>), viewer: &impl Viewer) {
+        let (width, height) = self.context.get_framebuffer_dimensions();
+        let target = Texture2d::empty(&self.context, width, height).unwrap();
+        let mut framebuffer = SimpleFrameBuffer::new(&self.context, &target).unwrap();
+        self.draw(&mut framebuffer, cameras);
+        // Rows of framebuffers start from the bottom
+        let eyes = image::imageops::flip_vertical(&read_texture(&target, false));
+
+        // Captured frames have red and blue swapped (see shader.frag)
+        let screens = self
+            .screens
+            .iter()
+            .map(|screen| {
+                screen
+                    .texture
+                    .as_ref()
+                    .map(|texture| read_texture(texture, true))
+            })
+            .collect();
+
+        let screen_metadata = self.screens.iter().map(Screen::metadata).collect();
+        let metadata = HashMap::from([
+            ("pose".to_owned(), snapshot::quaternion(&viewer.pose())),
+            ("fov_degrees".to_owned(), number(viewer.fov().to_degrees())),
+            (
+                "frame_size".to_owned(),
+                JsonValue::Array(vec![number(width), number(height)]),
+            ),
+            ("camera_left".to_owned(), snapshot::matrix(&cameras.0)),
+            ("camera_right".to_owned(), snapshot::matrix(&cameras.1)),
+            ("screens".to_owned(), JsonValue::Array(screen_metadata)),
+            ("config".to_owned(), snapshot::config(&self.config)),
+        ]);
+
+        let snapshot = Snapshot {
+            screens,
+            eyes,
+            metadata,
+        };
+        let directory = self.config.snapshot.directory.clone();
+        thread::spawn(move || match snapshot.save(&directory) {
+            Ok(path) => log::info!("Snapshot saved to {}", path.display()),
+            Err(err) => log::error!("Cannot save snapshot: {}", err),
+        });
     }
 
-    // camera_matrix: projection_matrix*world_to_camera
-    fn render_view(
-        &mut self,
-        frame: &mut Frame,
+    // transform: projection_matrix*world_to_camera*model_to_world
+    #[allow(clippy::too_many_arguments)]
+    fn draw_layer(
+        &self,
+        frame: &mut impl Surface,
+        viewport: Rect,
+        program: &Program,
+        vertices: &VertexBuffer<Vertex>,
         texture: &Texture2d,
-        camera_matrix: &Matrix4<f32>,
-        viewport_left_ndc: f32,
-        viewport_right_ndc: f32,
+        transform: &Matrix4<f32>,
+        blend: Blend,
     ) {
-        let (width, height) = frame.get_dimensions();
-        let left = remap(viewport_left_ndc, -1.0, 1.0, 0.0, width as f32).round() as u32;
-        let right = remap(viewport_right_ndc, -1.0, 1.0, 0.0, width as f32).round() as u32;
-        let viewport = Rect {
-            left,
-            bottom: 0,
-            width: right - left,
-            height,
-        };
         let parameters = DrawParameters {
             viewport: Some(viewport),
+            blend,
             ..Default::default()
         };
 
         let uniforms = uniform! {
             tex: texture,
-            transform: Into::<[[f32; 4]; 4]>::into(camera_matrix * self.screen_transform),
+            transform: Into::<[[f32; 4]; 4]>::into(*transform),
         };
 
         frame
-            .draw(
-                &self.vertex_buffer,
-                &self.index_buffer,
-                &self.program,
-                &uniforms,
-                &parameters,
-            )
+            .draw(vertices, self.index_buffer, program, &uniforms, &parameters)
             .unwrap();
     }
 }
 
+impl Screen {
+    fn new(
+        capturer: Box<dyn Capturer>,
+        mut config: VirtualScreen,
+        window_index: Option<usize>,
+        region: Option<Region>,
+        cursor: Option<CursorOverlay>,
+    ) -> Self {
+        let placement = Placement {
+            distance: config.distance,
+            height: config.height,
+            yaw: config.yaw.unwrap_or(0.0),
+            pitch: config.pitch,
+        };
+        // Replaced when the first frame arrives, but checks the config earlier
+        let surface = ScreenSurface::new(&config, 1.0);
+        if surface.shape != config.shape {
+            log::warn!("Radius and arc of a curved screen must be positive. The screen is flat.");
+            config.shape = surface.shape;
+        }
+        Self {
+            capturer,
+            surface,
+            config,
+            window_index,
+            initial: placement,
+            target: placement,
+            placement,
+            texture: None,
+            resolution: (0, 0),
+            transform: Matrix4::identity(),
+            mesh: None,
+            mesh_scale: Vector3::repeat(1.0),
+            region,
+            cursor,
+        }
+    }
+
+    fn metadata(&self) -> JsonValue {
+        let region = match self.region {
+            Some(region) => JsonValue::Array(
+                [region.x, region.y, region.width, region.height]
+                    .into_iter()
+                    .map(number)
+                    .collect(),
+            ),
+            None => JsonValue::Null,
+        };
+        JsonValue::Object(HashMap::from([
+            (
+                "resolution".to_owned(),
+                JsonValue::Array(vec![number(self.resolution.0), number(self.resolution.1)]),
+            ),
+            // Positive to the right as in the config
+            ("yaw_degrees".to_owned(), number(self.placement.yaw)),
+            ("transform".to_owned(), snapshot::matrix(&self.transform)),
+            (
+                "size".to_owned(),
+                JsonValue::Array(vec![
+                    number(self.surface.width),
+                    number(self.surface.height),
+                ]),
+            ),
+            ("region".to_owned(), region),
+        ]))
+    }
+
+    // Texture and model matrix of the cursor, if it is on this screen
+    fn cursor_layer(&self) -> Option<(&Texture2d, Matrix4<f32>)> {
+        let cursor = self.cursor.as_ref()?;
+        let texture = cursor.texture.as_ref()?;
+
+        let (width, height) = (self.resolution.0 as f32, self.resolution.1 as f32);
+        let (cursor_width, cursor_height) = texture.dimensions();
+        let (cursor_width, cursor_height) = (
+            cursor_width as f32 * cursor.scale,
+            cursor_height as f32 * cursor.scale,
+        );
+
+        // Cursor position is relative to the whole output, whose scale may differ from logical coordinates
+        let offset = match self.region {
+            Some(region) => (
+                region.x as f32 * width / region.width as f32,
+                region.y as f32 * height / region.height as f32,
+            ),
+            None => (0.0, 0.0),
+        };
+
+        // Scale around the hotspot
+        let left = cursor.position.0 as f32 - offset.0 - cursor.hotspot.0 as f32 * cursor.scale;
+        let top = cursor.position.1 as f32 - offset.1 - cursor.hotspot.1 as f32 * cursor.scale;
+        let center_x = ((left + cursor_width / 2.0) / width - 0.5) * self.surface.width;
+        let center_y = (0.5 - (top + cursor_height / 2.0) / height) * self.surface.height;
+
+        // Along the surface, facing the same way as it
+        let transform = self.transform
+            * self.surface.frame_at(center_x, center_y).to_homogeneous()
+            * Translation3::new(0.0, 0.0, cursor.depth).to_homogeneous()
+            * Scale3::new(
+                cursor_width / width * self.surface.width,
+                cursor_height / height * self.surface.height,
+                1.0,
+            )
+            .to_homogeneous();
+
+        Some((texture, transform))
+    }
+
+    // Move the placement toward the target. Returns true if it has changed.
+    fn animate(&mut self, facade: &impl Facade, elapsed: Duration) -> bool {
+        if self.placement == self.target {
+            return false;
+        }
+
+        let progress = 1.0 - (-elapsed.as_secs_f32() / ANIMATION_TIME.as_secs_f32()).exp();
+        // Snap to the target when the rest is too small to see
+        let approach = |current: f32, target: f32, epsilon: f32| {
+            if (target - current).abs() < epsilon {
+                target
+            } else {
+                current + (target - current) * progress
+            }
+        };
+        let (current, target) = (self.placement, self.target);
+        self.placement = Placement {
+            distance: approach(current.distance, target.distance, 0.001),
+            height: approach(current.height, target.height, 0.001),
+            yaw: approach(current.yaw, target.yaw, 0.01),
+            pitch: approach(current.pitch, target.pitch, 0.01),
+        };
+
+        // Otherwise placed when the first frame arrives
+        if self.resolution != (0, 0) {
+            self.update_transform(facade, self.resolution);
+        }
+        true
+    }
+
+    fn update_transform(&mut self, facade: &impl Facade, resolution: (u32, u32)) {
+        let aspect = resolution.0 as f32 / resolution.1 as f32;
+        let placement = &self.placement;
+        let config = VirtualScreen {
+            distance: placement.distance,
+            height: placement.height,
+            ..self.config.clone()
+        };
+        // Yaw of the config is positive to the right
+        self.transform =
+            Rotation3::from_axis_angle(&Vector3::y_axis(), -placement.yaw.to_radians())
+                .to_homogeneous()
+                * Rotation3::from_axis_angle(&Vector3::x_axis(), placement.pitch.to_radians())
+                    .to_homogeneous()
+                * Translation3::new(config.offset.0, config.offset.1, -placement.distance)
+                    .to_homogeneous()
+                * Rotation3::from_axis_angle(&Vector3::z_axis(), -config.roll.to_radians())
+                    .to_homogeneous();
+        self.surface = ScreenSurface::new(&config, aspect);
+
+        // Uploaded again only when the shape changes, e.g. not while the screen moves
+        let (normalized, scale) = self.surface.normalized();
+        if !matches!(self.mesh, Some((ref surface, _)) if surface.same_mesh(&normalized)) {
+            let vertices = VertexBuffer::new(facade, &normalized.vertices()).unwrap();
+            self.mesh = Some((normalized, vertices));
+        }
+        self.mesh_scale = scale;
+        self.resolution = resolution;
+    }
+}
+
+impl CursorOverlay {
+    // Returns true if the cursor has to be redrawn
+    fn update(&mut self) -> bool {
+        let frame = match self.capturer.with(CursorCapturer::capture) {
+            Ok(Some(frame)) => frame,
+            // Keep the last image while capture is failing
+            Err(CaptureError::Failed(_)) => return false,
+            // The cursor is not on the screen, or cannot be captured
+            _ => return self.texture.take().is_some(),
+        };
+
+        let changed = frame.image.is_damaged()
+            || self.texture.is_none()
+            || frame.position != self.position
+            || frame.hotspot != self.hotspot;
+        self.texture = Some(frame.image.texture);
+        self.position = frame.position;
+        self.hotspot = frame.hotspot;
+
+        changed
+    }
+}
+
+#[cfg(feature = "pipewire")]
+fn pipewire_capturer(
+    display: &Display<WindowSurface>,
+    node: Option<u32>,
+    limiter: FrameLimiter,
+) -> Result<Box<dyn Capturer>, CaptureError> {
+    Ok(Box::new(PipeWireCapturer::new(
+        display, node, true, limiter,
+    )))
+}
+
+#[cfg(not(feature = "pipewire"))]
+fn pipewire_capturer(
+    _display: &Display<WindowSurface>,
+    _node: Option<u32>,
+    _limiter: FrameLimiter,
+) -> Result<Box<dyn Capturer>, CaptureError> {
+    Err(CaptureError::Unsupported(
+        "PipeWire capture (build with `--features pipewire` to enable it)".to_owned(),
+    ))
+}
+
+// Show the placeholder instead of a capturer which cannot be created
+fn unavailable(err: CaptureError) -> Box<dyn Capturer> {
+    log::error!("{}", err);
+    Box::new(Unavailable(err))
+}
+
+// RGBA image with opaque alpha (undefined in XRGB textures)
+fn read_texture(texture: &Texture2d, swap_red_blue: bool) -> RgbaImage {
+    let raw: RawImage2d<u8> = texture.read();
+    let mut image = RgbaImage::from_raw(raw.width, raw.height, raw.data.into_owned()).unwrap();
+    for pixel in image.pixels_mut() {
+        if swap_red_blue {
+            pixel.0.swap(0, 2);
+        }
+        pixel.0[3] = 255;
+    }
+    image
+}
+
+// Camera matrices of the left and right eyes, for a side-by-side frame of `dimensions`
+fn eye_cameras(viewer: &impl Viewer, dimensions: (u32, u32)) -> (Matrix4<f32>, Matrix4<f32>) {
+    let aspect = dimensions.0 as f32 / 2.0 / dimensions.1 as f32;
+    (
+        viewer.camera_mat(ar_drivers::Side::Left, aspect),
+        viewer.camera_mat(ar_drivers::Side::Right, aspect),
+    )
+}
+
+fn eye_viewport(frame: &impl Surface, viewport_left_ndc: f32, viewport_right_ndc: f32) -> Rect {
+    let (width, height) = frame.get_dimensions();
+    let left = remap(viewport_left_ndc, -1.0, 1.0, 0.0, width as f32).round() as u32;
+    let right = remap(viewport_right_ndc, -1.0, 1.0, 0.0, width as f32).round() as u32;
+    Rect {
+        left,
+        bottom: 0,
+        width: right - left,
+        height,
+    }
+}
+
 fn remap(x: f32, from_min: f32, from_max: f32, to_min: f32, to_max: f32) -> f32 {
     to_min + (x - from_min) * (to_max - to_min) / (from_max - from_min)
 }
@@ -0,0 +1,279 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Geometry of virtual screens, flat or curved around the viewer
+
+use na::{Isometry3, Point3, Translation3, UnitQuaternion, Vector3};
+
+use super::Vertex;
+use crate::config::{ScreenShape, VirtualScreen};
+
+// Segments of curved meshes, enough for lines on the screen to look smooth
+const HORIZONTAL_SEGMENTS: u32 = 64;
+const VERTICAL_SEGMENTS: u32 = 32;
+
+// Surface of a virtual screen in meters, centered at the origin and facing +Z.
+// Curved ones bend toward +Z around the center of curvature at (0, 0, radius).
+#[derive(Clone, Copy, Debug)]
+pub struct ScreenSurface {
+    pub shape: ScreenShape,
+    pub width: f32,
+    pub height: f32,
+    pub radius: f32,
+}
+
+impl ScreenSurface {
+    // `aspect` is of the captured frame
+    pub fn new(config: &VirtualScreen, aspect: f32) -> Self {
+        let width = config.height * aspect;
+        let radius = match config.arc {
+            Some(arc) => width / arc.to_radians(),
+            None => config.radius.unwrap_or(config.distance),
+        };
+        // Flat if the radius cannot be used (see `Screen::new`)
+        let shape = if radius.is_finite() && radius > 0.0 {
+            config.shape
+        } else {
+            ScreenShape::Flat
+        };
+
+        Self {
+            shape,
+            width,
+            height: config.height,
+            radius,
+        }
+    }
+
+    // Position on the surface and its orientation (Z is the normal), at `x` and `y` meters from the
+    // center measured along the surface
+    pub fn frame_at(&self, x: f32, y: f32) -> Isometry3<f32> {
+        let (rotation, offset) = match self.shape {
+            ScreenShape::Flat => return Isometry3::translation(x, y, 0.0),
+            ScreenShape::Cylinder => (
+                UnitQuaternion::from_axis_angle(&Vector3::y_axis(), -x / self.radius),
+                Vector3::new(0.0, y, 0.0),
+            ),
+            ScreenShape::Sphere => (
+                UnitQuaternion::from_axis_angle(&Vector3::y_axis(), -x / self.radius)
+                    * UnitQuaternion::from_axis_angle(&Vector3::x_axis(), y / self.radius),
+                Vector3::zeros(),
+            ),
+        };
+        let center = Point3::new(0.0, 0.0, self.radius);
+        let position = center - rotation * Vector3::z() * self.radius + offset;
+        Isometry3::from_parts(Translation3::from(position.coords), rotation)
+    }
+
+    // Surface of the same shape, which becomes this one when scaled by the returned factors.
+    // Flat ones are 1x1 and curved ones have a radius of 1, so that the mesh is kept while a screen
+    // moves or is resized at a fixed arc.
+    pub fn normalized(&self) -> (Self, Vector3<f32>) {
+        match self.shape {
+            ScreenShape::Flat => (
+                Self {
+                    width: 1.0,
+                    height: 1.0,
+                    radius: 1.0,
+                    ..*self
+                },
+                Vector3::new(self.width, self.height, 1.0),
+            ),
+            ScreenShape::Cylinder | ScreenShape::Sphere => (
+                Self {
+                    width: self.width / self.radius,
+                    height: self.height / self.radius,
+                    radius: 1.0,
+                    ..*self
+                },
+                Vector3::repeat(self.radius),
+            ),
+        }
+    }
+
+    // Whether the meshes of the surfaces are the same, apart from rounding errors
+    pub fn same_mesh(&self, other: &Self) -> bool {
+        let near = |a: f32, b: f32| (a - b).abs() <= 1e-4 * a.abs().max(b.abs());
+        self.shape == other.shape
+            && near(self.width, other.width)
+            && near(self.height, other.height)
+            && near(self.radius, other.radius)
+    }
+
+    // Triangles covering the screen. Texture coordinates are proportional to the length along the
+    // surface, with (0, 0) at the top left.
+    pub fn vertices(&self) -> Vec<Vertex> {
+        let (columns, rows) = match self.shape {
+            ScreenShape::Flat => (1, 1),
+            ScreenShape::Cylinder => (HORIZONTAL_SEGMENTS, 1),
+            ScreenShape::Sphere => (HORIZONTAL_SEGMENTS, VERTICAL_SEGMENTS),
+        };
+        let vertex = |column: u32, row: u32| {
+            let u = column as f32 / columns as f32;
+            let v = row as f32 / rows as f32;
+            let frame = self.frame_at((u - 0.5) * self.width, (0.5 - v) * self.height);
+            Vertex {
+                position: frame.translation.vector.into(),
+                tex_coords: [u, v],
+            }
+        };
+
+        let mut vertices = Vec::with_capacity((columns * rows * 6) as usize);
+        for row in 0..rows {
+            for column in 0..columns {
+                let top_left = vertex(column, row);
+                let bottom_left = vertex(column, row + 1);
+                let bottom_right = vertex(column + 1, row + 1);
+                let top_right = vertex(column + 1, row);
+                vertices.extend([
+                    top_left,
+                    bottom_left,
+                    bottom_right,
+                    top_left,
+                    bottom_right,
+                    top_right,
+                ]);
+            }
+        }
+        vertices
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+
+    use super::*;
+
+    fn surface(shape: ScreenShape, radius: Option<f32>, arc: Option<f32>) -> ScreenSurface {
+        let config = VirtualScreen {
+            shape,
+            height: 1.0,
+            distance: 3.0,
+            radius,
+            arc,
+            ..Default::default()
+        };
+        ScreenSurface::new(&config, 2.0)
+    }
+
+    fn assert_near(a: Vector3<f32>, b: Vector3<f32>) {
+        assert!((a - b).norm() < 1e-5, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn radius() {
+        // Width 2 m over 90°
+        let curved = surface(ScreenShape::Cylinder, Some(1.0), Some(90.0));
+        assert!((curved.radius - 4.0 / PI).abs() < 1e-5);
+        assert_eq!(surface(ScreenShape::Sphere, Some(1.5), None).radius, 1.5);
+        assert_eq!(surface(ScreenShape::Cylinder, None, None).radius, 3.0);
+
+        for (radius, arc) in [
+            (Some(-1.0), None),
+            (None, Some(0.0)),
+            (Some(f32::NAN), None),
+        ] {
+            let curved = surface(ScreenShape::Cylinder, radius, arc);
+            assert_eq!(curved.shape, ScreenShape::Flat);
+        }
+    }
+
+    #[test]
+    fn frame_at() {
+        let flat = surface(ScreenShape::Flat, None, None);
+        assert_eq!(
+            flat.frame_at(0.5, -0.25),
+            Isometry3::translation(0.5, -0.25, 0.0)
+        );
+
+        // A quarter of the circle to the right faces the center of curvature
+        let cylinder = surface(ScreenShape::Cylinder, Some(2.0), None);
+        assert_near(
+            cylinder.frame_at(0.0, 0.0).translation.vector,
+            Vector3::zeros(),
+        );
+        let frame = cylinder.frame_at(PI, 0.3);
+        assert_near(frame.translation.vector, Vector3::new(2.0, 0.3, 2.0));
+        assert_near(frame.rotation * Vector3::z(), -Vector3::x());
+
+        let sphere = surface(ScreenShape::Sphere, Some(2.0), None);
+        let frame = sphere.frame_at(0.0, PI);
+        assert_near(frame.translation.vector, Vector3::new(0.0, 2.0, 2.0));
+        assert_near(frame.rotation * Vector3::z(), -Vector3::y());
+    }
+
+    #[test]
+    fn vertices() {
+        let counts = [
+            (ScreenShape::Flat, 6),
+            (ScreenShape::Cylinder, 64 * 6),
+            (ScreenShape::Sphere, 64 * 32 * 6),
+        ];
+        for (shape, count) in counts {
+            let surface = surface(shape, Some(2.0), None);
+            let vertices = surface.vertices();
+            assert_eq!(vertices.len(), count, "{:?}", shape);
+
+            // Texture coordinates span the texture, with the top left at the left edge of the screen
+            let coords = |f: fn(&Vertex) -> f32| vertices.iter().map(f);
+            assert_eq!(coords(|v| v.tex_coords[0]).fold(1.0, f32::min), 0.0);
+            assert_eq!(coords(|v| v.tex_coords[0]).fold(0.0, f32::max), 1.0);
+            assert_eq!(coords(|v| v.tex_coords[1]).fold(1.0, f32::min), 0.0);
+            assert_eq!(coords(|v| v.tex_coords[1]).fold(0.0, f32::max), 1.0);
+            let top_left = vertices[0];
+            assert_eq!(top_left.tex_coords, [0.0, 0.0]);
+            let expected = surface.frame_at(-1.0, 0.5).translation.vector;
+            assert_near(Vector3::from(top_left.position), expected);
+        }
+        let flat = surface(ScreenShape::Flat, None, None).vertices();
+        assert_eq!(flat[0].position, [-1.0, 0.5, 0.0]);
+    }
+
+    #[test]
+    fn normalized() {
+        for shape in [
+            ScreenShape::Flat,
+            ScreenShape::Cylinder,
+            ScreenShape::Sphere,
+        ] {
+            let surface = surface(shape, Some(2.0), None);
+            let (normalized, scale) = surface.normalized();
+            for (vertex, expected) in normalized.vertices().iter().zip(surface.vertices()) {
+                let position = Vector3::from(vertex.position).component_mul(&scale);
+                assert_near(position, Vector3::from(expected.position));
+                assert_eq!(vertex.tex_coords, expected.tex_coords);
+            }
+        }
+
+        // Resizing keeps the mesh at a fixed arc, but not at a fixed radius
+        let config = |height, radius, arc| VirtualScreen {
+            shape: ScreenShape::Sphere,
+            height,
+            radius,
+            arc,
+            ..Default::default()
+        };
+        let mesh = |config| ScreenSurface::new(&config, 16.0 / 9.0).normalized().0;
+        assert!(mesh(config(1.0, None, Some(90.0))).same_mesh(&mesh(config(
+            1.3,
+            None,
+            Some(90.0)
+        ))));
+        assert!(!mesh(config(1.0, Some(2.0), None)).same_mesh(&mesh(config(1.3, Some(2.0), None))));
+        let flat = |height| {
+            ScreenSurface::new(
+                &VirtualScreen {
+                    height,
+                    ..Default::default()
+                },
+                2.0,
+            )
+        };
+        assert!(flat(1.0)
+            .normalized()
+            .0
+            .same_mesh(&flat(1.5).normalized().0));
+    }
+}
@@ -0,0 +1,118 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Snapshots of captured frames and the rendered side-by-side frame, with the pose and the config
+// in a JSON file, for bug reports about distortion, colors or alignment
+
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use image::RgbaImage;
+use na::{Matrix4, UnitQuaternion};
+use tinyjson::JsonValue;
+
+use crate::config::Config;
+
+pub struct Snapshot {
+    pub screens: Vec<Option<RgbaImage>>, // Latest captured frame of each screen
+    pub eyes: RgbaImage,                 // Frame shown on the glasses
+    pub metadata: HashMap<String, JsonValue>,
+}
+
+impl Snapshot {
+    // Files are named after the current time. Returns the path of the JSON file.
+    pub fn save(mut self, directory: &Path) -> io::Result<PathBuf> {
+        fs::create_dir_all(directory)?;
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let stem = format!("okulekrano-{}", time.as_millis());
+
+        let mut screen_files = Vec::new();
+        for (index, image) in self.screens.iter().enumerate() {
+            let Some(image) = image else {
+                screen_files.push(JsonValue::Null);
+                continue;
+            };
+            let name = format!("{}-screen{}.png", stem, index);
+            image
+                .save(directory.join(&name))
+                .map_err(io::Error::other)?;
+            screen_files.push(JsonValue::String(name));
+        }
+        let eyes_name = format!("{}-eyes.png", stem);
+        self.eyes
+            .save(directory.join(&eyes_name))
+            .map_err(io::Error::other)?;
+
+        let metadata = &mut self.metadata;
+        metadata.insert("time".to_owned(), number(time.as_secs_f64()));
+        metadata.insert("screen_files".to_owned(), JsonValue::Array(screen_files));
+        metadata.insert("eyes_file".to_owned(), JsonValue::String(eyes_name));
+
+        let path = directory.join(format!("{}.json", stem));
+        let json = JsonValue::Object(self.metadata)
+            .format()
+            .map_err(io::Error::other)?;
+        fs::write(&path, json)?;
+        Ok(path)
+    }
+}
+
+pub fn number(value: impl Into<f64>) -> JsonValue {
+    JsonValue::Number(value.into())
+}
+
+// Elements in column-major order, as uploaded to shaders
+pub fn matrix(matrix: &Matrix4<f32>) -> JsonValue {
+    JsonValue::Array(matrix.iter().map(|&element| number(element)).collect())
+}
+
+pub fn quaternion(quat: &UnitQuaternion<f32>) -> JsonValue {
+    let (roll, pitch, yaw) = quat.euler_angles();
+    JsonValue::Object(HashMap::from([
+        (
+            "xyzw".to_owned(),
+            JsonValue::Array(quat.coords.iter().map(|&element| number(element)).collect()),
+        ),
+        (
+            "roll_pitch_yaw_degrees".to_owned(),
+            JsonValue::Array(
+                [roll, pitch, yaw]
+                    .iter()
+                    .map(|angle| number(angle.to_degrees()))
+                    .collect(),
+            ),
+        ),
+    ]))
+}
+
+// Same structure as the config file
+pub fn config(config: &Config) -> JsonValue {
+    match toml::Value::try_from(config) {
+        Ok(value) => toml_to_json(value),
+        Err(err) => JsonValue::String(format!("cannot serialize config: {}", err)),
+    }
+}
+
+fn toml_to_json(value: toml::Value) -> JsonValue {
+    match value {
+        toml::Value::String(string) => JsonValue::String(string),
+        toml::Value::Integer(integer) => number(integer as f64),
+        toml::Value::Float(float) => number(float),
+        toml::Value::Boolean(boolean) => JsonValue::Boolean(boolean),
+        toml::Value::Datetime(datetime) => JsonValue::String(datetime.to_string()),
+        toml::Value::Array(array) => {
+            JsonValue::Array(array.into_iter().map(toml_to_json).collect())
+        }
+        toml::Value::Table(table) => JsonValue::Object(
+            table
+                .into_iter()
+                .map(|(key, value)| (key, toml_to_json(value)))
+                .collect(),
+        ),
+    }
+}
@@ -4,10 +4,22 @@
 
 use std::{
     num::NonZero,
-    sync::{mpsc, Arc},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc,
+    },
 };
 
-use crate::{config::Config, glasses::GlassesController, renderer::Renderer};
+use crate::{
+    capturer::wayland::list_outputs,
+    config::{self, Backend, Config, ScreenCommand},
+    control::{self, ControlSocket},
+    glasses::GlassesController,
+    headless::{self, HeadlessOutput},
+    output_manager::OutputManager,
+    output_match::glob_match,
+    renderer::Renderer,
+};
 use glium::glutin::{
     self,
     config::ConfigTemplateBuilder,
@@ -18,18 +30,24 @@ use glium::glutin::{
 use winit::{
     application::ApplicationHandler,
     dpi::PhysicalSize,
-    event::WindowEvent,
+    event::{ElementState, KeyEvent, WindowEvent},
     event_loop::{ControlFlow, EventLoop},
+    keyboard::{Key, ModifiersState, NamedKey},
     raw_window_handle::{HasDisplayHandle, HasWindowHandle},
     window::{Fullscreen, Window, WindowAttributes, WindowButtons},
 };
 
+// Set by SIGUSR1, to save a snapshot from outside (e.g. `pkill -USR1 okulekrano`)
+static SNAPSHOT_SIGNALED: AtomicBool = AtomicBool::new(false);
+
 struct App {
     window: Option<Arc<Window>>,
     renderer: Option<Renderer>,
     config: Config,
     glasses: GlassesController,
     stop_receiver: mpsc::Receiver<()>,
+    control_socket: Option<ControlSocket>,
+    modifiers: ModifiersState,
 }
 
 impl App {
@@ -41,12 +59,49 @@ impl App {
         })
         .unwrap();
 
+        let handler = on_snapshot_signal as extern "C" fn(libc::c_int);
+        unsafe { libc::signal(libc::SIGUSR1, handler as libc::sighandler_t) };
+
+        // Keys in the window still work without it
+        let control_socket = ControlSocket::bind()
+            .inspect_err(|err| log::warn!("Cannot open control socket: {}", err))
+            .ok();
+
         Self {
             window: None,
             renderer: None,
             config,
             glasses,
             stop_receiver,
+            control_socket,
+            modifiers: ModifiersState::empty(),
+        }
+    }
+
+    // Commands from the control socket and buttons of the glasses
+    fn pending_commands(&self) -> Vec<ScreenCommand> {
+        let mut commands: Vec<_> = self
+            .control_socket
+            .iter()
+            .flat_map(ControlSocket::commands)
+            .collect();
+        for key in self.glasses.pressed_buttons() {
+            match self.config.glasses.buttons.get(key as usize) {
+                Some(&command) => commands.push(command),
+                None => log::debug!("No command for button {}", key),
+            }
+        }
+        commands
+    }
+
+    fn control(&mut self, command: ScreenCommand) {
+        let Some(ref mut renderer) = self.renderer else {
+            return;
+        };
+        if command == ScreenCommand::Save {
+            save_placement(renderer);
+        } else {
+            renderer.control(command);
         }
     }
 }
@@ -57,7 +112,7 @@ impl ApplicationHandler for App {
             let monitor = event_loop
                 .available_monitors()
                 .find(|monitor| match monitor.name() {
-                    Some(name) => name == monitor_name.as_str(),
+                    Some(name) => glob_match(monitor_name, &name),
                     None => false,
                 });
             log::info!(
@@ -132,12 +187,48 @@ impl ApplicationHandler for App {
                     event_loop.exit();
                 }
 
+                for command in self.pending_commands() {
+                    self.control(command);
+                }
+
                 if let Some(ref mut renderer) = self.renderer {
+                    if SNAPSHOT_SIGNALED.swap(false, Ordering::Relaxed) {
+                        renderer.request_snapshot();
+                    }
+
                     self.glasses.update_pose();
 
                     renderer.render(&self.glasses);
                 }
             }
+            WindowEvent::KeyboardInput {
+                event:
+                    KeyEvent {
+                        logical_key: Key::Named(NamedKey::F12),
+                        state: ElementState::Pressed,
+                        repeat: false,
+                        ..
+                    },
+                ..
+            } => {
+                if let Some(ref mut renderer) = self.renderer {
+                    renderer.request_snapshot();
+                }
+            }
+            WindowEvent::KeyboardInput {
+                event:
+                    KeyEvent {
+                        logical_key,
+                        state: ElementState::Pressed,
+                        ..
+                    },
+                ..
+            } => {
+                if let Some(command) = control::key_command(&logical_key, self.modifiers) {
+                    self.control(command);
+                }
+            }
+            WindowEvent::ModifiersChanged(modifiers) => self.modifiers = modifiers.state(),
             _ => (),
         }
     }
@@ -145,14 +236,64 @@ impl ApplicationHandler for App {
     fn about_to_wait(&mut self, _event_loop: &winit::event_loop::ActiveEventLoop) {
         self.window.as_ref().unwrap().request_redraw();
     }
+
+    fn exiting(&mut self, _event_loop: &winit::event_loop::ActiveEventLoop) {
+        if let Some(report) = self
+            .renderer
+            .as_ref()
+            .and_then(|renderer| renderer.latency_stats().last_report())
+        {
+            log::info!("{}", report);
+        }
+    }
 }
 
 pub fn run() {
-    let config: Config = confy::load("okulekrano", None).unwrap();
+    let mut config: Config = confy::load("okulekrano", None).unwrap();
 
-    let glasses = GlassesController::new();
+    // Neither glasses nor a compositor is used
+    if let Some(ref offscreen) = config.offscreen {
+        crate::offscreen::run(&config, offscreen);
+        return;
+    }
+
+    // Kept until exit, to remove the output when dropped.
+    // Without it, the output of [capture] is captured as usual.
+    let headless_output = config.headless_output.as_ref().and_then(|headless_output| {
+        headless::sway_socket()
+            .and_then(|socket| HeadlessOutput::create(&socket, headless_output))
+            .inspect_err(|err| log::warn!("Cannot create headless output: {}", err))
+            .ok()
+    });
+    if let Some(ref headless_output) = headless_output {
+        if config.capture.output_match().is_some() {
+            log::warn!(
+                "Output setting of [capture] is ignored because a headless output is created"
+            );
+        }
+        config.capture.output_name = Some(headless_output.name().to_owned());
+        config.capture.output = None;
+    }
+
+    // The fullscreen window is opened on the glasses, found by its name
+    if !config.glasses.window_mode && config.capture.backend != Backend::X11 {
+        config.glasses.monitor_name = find_glasses(&config.glasses);
+        config.glasses.monitor = None;
+    }
+
+    // Restores the outputs when dropped (before the headless output is removed)
+    let _output_manager = OutputManager::apply(&config);
+
+    let glasses = GlassesController::new(config.glasses.record_imu.as_deref());
 
-    if let Some(ref monitor_name) = config.glasses.monitor_name {
+    // Mode of the glasses is watched through Wayland, so it is not waited for on X11
+    let wait_for_mode = config.capture.backend != Backend::X11;
+    if let Some(monitor_name) = config
+        .glasses
+        .monitor_name
+        .as_ref()
+        .filter(|_| wait_for_mode)
+    {
         log::info!("Waiting until the AR glasses becomes 3D mode...");
         // Wait until the AR glasses becomes 3D mode (has width larger than 3000 pixels)
         for info in crate::mode_refresh::query_monitors() {
@@ -171,3 +312,61 @@ pub fn run() {
 
     event_loop.run_app(&mut app).unwrap();
 }
+
+// Write the placement of screens into the config file. The file is read again, because the loaded
+// config is changed at startup (e.g. the name of the created headless output).
+fn save_placement(renderer: &Renderer) {
+    let mut config: Config = match confy::load("okulekrano", None) {
+        Ok(config) => config,
+        Err(err) => {
+            log::error!("Cannot read the config file: {}", err);
+            return;
+        }
+    };
+    renderer.store_placement(&mut config);
+    match confy::store("okulekrano", None, &config) {
+        Ok(()) => log::info!("Placement of screens saved to the config file"),
+        Err(err) => log::error!("Cannot save placement of screens: {}", err),
+    }
+}
+
+extern "C" fn on_snapshot_signal(_signal: libc::c_int) {
+    SNAPSHOT_SIGNALED.store(true, Ordering::Relaxed);
+}
+
+// Name of the output of the glasses, selected by the config or detected from its make and model
+fn find_glasses(config: &config::Glasses) -> Option<String> {
+    let outputs = match list_outputs() {
+        Ok(outputs) => outputs,
+        Err(err) => {
+            log::warn!("Cannot list outputs: {}", err);
+            return config.monitor_name.clone();
+        }
+    };
+    for properties in &outputs {
+        log::debug!("{:?}", properties);
+    }
+
+    let selector = config.monitor_match();
+    let found = match selector {
+        Some(ref selector) => outputs
+            .iter()
+            .find(|properties| selector.matches(properties)),
+        None => outputs.iter().find(|properties| properties.is_glasses()),
+    };
+    let Some(properties) = found else {
+        match selector {
+            Some(selector) => panic!("Output of the glasses ({}) is not found", selector),
+            None => {
+                panic!("Output of the glasses is not detected. Set `monitor_name` in [glasses].")
+            }
+        }
+    };
+    log::info!(
+        "Output of the glasses: {} ({})",
+        properties.name.as_deref().unwrap_or_default(),
+        properties.description.as_deref().unwrap_or_default()
+    );
+
+    properties.name.clone()
+}
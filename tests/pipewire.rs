@@ -0,0 +1,49 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Capture of a node of the local PipeWire daemon, without the portal. Ignored by default; start a
+// test source and pass its node ID, e.g.
+//   gst-launch-1.0 videotestsrc ! video/x-raw,format=BGRx ! pipewiresink mode=provide &
+//   OKULEKRANO_PIPEWIRE_NODE=42 cargo test --features pipewire --test pipewire -- --ignored
+
+#![cfg(feature = "pipewire")]
+
+use std::{
+    env, thread,
+    time::{Duration, Instant},
+};
+
+use okulekrano::{
+    capturer::{limiter::FrameLimiter, pipewire::PipeWireCapturer, CaptureError, Capturer},
+    offscreen,
+};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[test]
+#[ignore = "needs a PipeWire daemon with a video source"]
+fn capture_node() {
+    let node = env::var("OKULEKRANO_PIPEWIRE_NODE")
+        .expect("OKULEKRANO_PIPEWIRE_NODE is not set")
+        .parse()
+        .expect("OKULEKRANO_PIPEWIRE_NODE is not a node ID");
+    let context = offscreen::create_context((64, 64)).unwrap();
+    let mut capturer = PipeWireCapturer::new(&context, Some(node), false, FrameLimiter::new(None));
+
+    let deadline = Instant::now() + TIMEOUT;
+    let frame = loop {
+        match capturer.capture() {
+            Ok(frame) => break frame,
+            Err(CaptureError::NotReady) if Instant::now() < deadline => {
+                thread::sleep(Duration::from_millis(50))
+            }
+            Err(err) => panic!("No frame captured from node {}: {}", node, err),
+        }
+    };
+
+    assert!(frame.is_damaged());
+    let (width, height) = frame.texture.dimensions();
+    assert!(width > 0 && height > 0);
+    assert!(frame.timing.is_some());
+}
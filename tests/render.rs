@@ -0,0 +1,315 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Rendering of test patterns at scripted head poses, compared with golden images in tests/golden.
+// Needs the surfaceless EGL platform of Mesa (llvmpipe is enough).
+// After intended changes of rendering, run with OKULEKRANO_UPDATE_GOLDEN=1 to update the images.
+
+use std::{cell::Cell, env, fs, path::Path, rc::Rc, time::Duration};
+
+use glium::{backend::Context, framebuffer::SimpleFrameBuffer, texture::RawImage2d, Texture2d};
+use image::RgbaImage;
+use nalgebra::{UnitQuaternion, Vector3};
+use okulekrano::{
+    capturer::{
+        fake::{FakeCapturer, Pattern},
+        file::{FileCapturer, FileClock},
+        limiter::FrameLimiter,
+        CaptureError, Capturer,
+    },
+    config::{Config, FileSource, Region, ScreenCommand, ScreenShape},
+    glasses::FixedViewer,
+    offscreen,
+    renderer::Renderer,
+};
+
+// Side by side like the glasses, at 1/3 of the resolution
+const FRAME_SIZE: (u32, u32) = (1280, 360);
+
+// Pixels differing more than this in any channel are counted as different
+const CHANNEL_TOLERANCE: u8 = 24;
+// Fraction of different pixels allowed, for differences of rasterization between drivers
+const MAX_DIFFERENT_PIXELS: f64 = 0.005;
+
+// Render a frame for each of `viewers` in order, with one screen for each of `patterns`
+fn render(config: &Config, patterns: &[Pattern], viewers: &[FixedViewer]) -> Vec<RgbaImage> {
+    let capturers = |context: &Rc<Context>| {
+        patterns
+            .iter()
+            .map(|&pattern| {
+                Box::new(FakeCapturer::new(
+                    context,
+                    pattern,
+                    None,
+                    FrameLimiter::new(None),
+                )) as Box<dyn Capturer>
+            })
+            .collect()
+    };
+    render_with(config, capturers, &[], viewers)
+}
+
+// Same as `render`, with screens of the capturers, moved by `commands`
+fn render_with(
+    config: &Config,
+    capturers: impl FnOnce(&Rc<Context>) -> Vec<Box<dyn Capturer>>,
+    commands: &[ScreenCommand],
+    viewers: &[FixedViewer],
+) -> Vec<RgbaImage> {
+    let context = offscreen::create_context(FRAME_SIZE).unwrap();
+    let capturers = capturers(&context);
+    let mut renderer = Renderer::with_capturers(&context, config, capturers);
+    for &command in commands {
+        renderer.control(command);
+    }
+    renderer.finish_animation();
+
+    let target = Texture2d::empty(&context, FRAME_SIZE.0, FRAME_SIZE.1).unwrap();
+    let mut framebuffer = SimpleFrameBuffer::new(&context, &target).unwrap();
+    viewers
+        .iter()
+        .map(|viewer| {
+            renderer.render_to(&mut framebuffer, viewer);
+
+            let raw: RawImage2d<u8> = target.read();
+            let image = RgbaImage::from_raw(raw.width, raw.height, raw.data.into_owned()).unwrap();
+            // Rows of framebuffers start from the bottom
+            image::imageops::flip_vertical(&image)
+        })
+        .collect()
+}
+
+fn assert_golden(name: &str, image: &RgbaImage) {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/golden")
+        .join(format!("{}.png", name));
+    if env::var_os("OKULEKRANO_UPDATE_GOLDEN").is_some() {
+        image.save(&path).unwrap();
+        return;
+    }
+
+    let golden = image::open(&path)
+        .unwrap_or_else(|err| panic!("cannot open {}: {}", path.display(), err))
+        .into_rgba8();
+    assert_eq!(golden.dimensions(), image.dimensions(), "size of {}", name);
+
+    let different = golden
+        .pixels()
+        .zip(image.pixels())
+        .filter(|(expected, actual)| {
+            expected
+                .0
+                .iter()
+                .zip(actual.0.iter())
+                .any(|(&e, &a)| e.abs_diff(a) > CHANNEL_TOLERANCE)
+        })
+        .count();
+    let fraction = different as f64 / golden.len() as f64 * 4.0;
+    if fraction > MAX_DIFFERENT_PIXELS {
+        let actual_path = Path::new(env!("CARGO_TARGET_TMPDIR")).join(format!("{}.png", name));
+        image.save(&actual_path).unwrap();
+        panic!(
+            "{:.2}% of pixels differ from {} (rendered image: {})",
+            fraction * 100.0,
+            path.display(),
+            actual_path.display()
+        );
+    }
+}
+
+// Far enough to see the whole screen
+fn far_screen() -> Config {
+    let mut config = Config::default();
+    config.virtual_screen.distance = 5.0;
+    config
+}
+
+// Angles in degrees. Y axis is up and the viewer looks toward -Z.
+fn viewer(yaw: f32, pitch: f32, roll: f32) -> FixedViewer {
+    FixedViewer::new(
+        UnitQuaternion::from_axis_angle(&Vector3::y_axis(), yaw.to_radians())
+            * UnitQuaternion::from_axis_angle(&Vector3::x_axis(), pitch.to_radians())
+            * UnitQuaternion::from_axis_angle(&Vector3::z_axis(), roll.to_radians()),
+    )
+}
+
+// Wide enough to see the curvature of a close screen
+fn wide_viewer() -> FixedViewer {
+    let mut viewer = viewer(0.0, 0.0, 0.0);
+    viewer.fov = 100f32.to_radians();
+    viewer
+}
+
+#[test]
+fn looking_forward() {
+    let frames = render(
+        &far_screen(),
+        &[Pattern::ColorBars],
+        &[viewer(0.0, 0.0, 0.0)],
+    );
+    assert_golden("looking_forward", &frames[0]);
+}
+
+#[test]
+fn head_motion() {
+    // Turning right, then looking down, then tilting the head
+    let viewers = [
+        viewer(-10.0, 0.0, 0.0),
+        viewer(-10.0, -5.0, 0.0),
+        viewer(-10.0, -5.0, 15.0),
+    ];
+    let frames = render(&far_screen(), &[Pattern::TextSharpness], &viewers);
+    for (index, frame) in frames.iter().enumerate() {
+        assert_golden(&format!("head_motion_{}", index), frame);
+    }
+}
+
+#[test]
+fn multiple_screens() {
+    // Looking at the second screen, which is on the right of the first one
+    let frames = render(
+        &far_screen(),
+        &[Pattern::Grid, Pattern::TextSharpness],
+        &[viewer(-40.0, 0.0, 0.0)],
+    );
+    assert_golden("multiple_screens", &frames[0]);
+}
+
+#[test]
+fn placed_screen() {
+    // Below eye level and shifted to the right, slightly rotated
+    let mut config = far_screen();
+    config.virtual_screen.pitch = -10.0;
+    config.virtual_screen.roll = 5.0;
+    config.virtual_screen.offset = (0.4, 0.0);
+    let frames = render(&config, &[Pattern::Grid], &[viewer(0.0, -10.0, 0.0)]);
+    assert_golden("placed_screen", &frames[0]);
+}
+
+#[test]
+fn controlled_screen() {
+    // Closer, smaller, and moved to the right and up
+    let commands = [
+        ScreenCommand::Closer,
+        ScreenCommand::Closer,
+        ScreenCommand::Smaller,
+        ScreenCommand::Right,
+        ScreenCommand::Right,
+        ScreenCommand::Up,
+    ];
+    let grid = |context: &Rc<Context>| {
+        vec![Box::new(FakeCapturer::new(
+            context,
+            Pattern::Grid,
+            None,
+            FrameLimiter::new(None),
+        )) as Box<dyn Capturer>]
+    };
+    let frames = render_with(&far_screen(), grid, &commands, &[viewer(-10.0, 5.0, 0.0)]);
+    assert_golden("controlled_screen", &frames[0]);
+}
+
+#[test]
+fn image_file() {
+    // Whole image, and the top left quarter scaled to the same screen
+    let source = FileSource {
+        path: Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/images/quadrants.png"),
+        frame_rate: None,
+        raw_size: None,
+    };
+    let regions = [
+        None,
+        Some(Region {
+            x: 0,
+            y: 0,
+            width: 160,
+            height: 90,
+        }),
+    ];
+    for (index, region) in regions.into_iter().enumerate() {
+        let capturer = |context: &Rc<Context>| {
+            vec![Box::new(
+                FileCapturer::new(
+                    context,
+                    &source,
+                    region,
+                    FrameLimiter::new(None),
+                    FileClock::Wall,
+                )
+                .unwrap(),
+            ) as Box<dyn Capturer>]
+        };
+        let frames = render_with(&far_screen(), capturer, &[], &[viewer(0.0, 0.0, 0.0)]);
+        assert_golden(&format!("image_file_{}", index), &frames[0]);
+    }
+}
+
+#[test]
+fn file_clock() {
+    // Red, then blue at 2 images per second
+    let directory = Path::new(env!("CARGO_TARGET_TMPDIR")).join("file_clock");
+    fs::create_dir_all(&directory).unwrap();
+    for (name, color) in [("0.png", [255, 0, 0, 255]), ("1.png", [0, 0, 255, 255])] {
+        RgbaImage::from_pixel(8, 8, image::Rgba(color))
+            .save(directory.join(name))
+            .unwrap();
+    }
+    let source = FileSource {
+        path: directory,
+        frame_rate: Some(2.0),
+        raw_size: None,
+    };
+
+    let context = offscreen::create_context((64, 64)).unwrap();
+    let time = Rc::new(Cell::new(Duration::ZERO));
+    let mut capturer = FileCapturer::new(
+        &context,
+        &source,
+        None,
+        FrameLimiter::new(None),
+        FileClock::Manual(Rc::clone(&time)),
+    )
+    .unwrap();
+    // Texture data is BGRX
+    let mut capture = |seconds: f32| {
+        time.set(Duration::from_secs_f32(seconds));
+        let frame = capturer.capture().unwrap();
+        let raw: RawImage2d<u8> = frame.texture.read();
+        (raw.data[..3].to_vec(), !frame.damage.is_empty())
+    };
+    assert_eq!(capture(0.0), (vec![0, 0, 255], true));
+    assert_eq!(capture(0.25), (vec![0, 0, 255], false));
+    assert_eq!(capture(0.5), (vec![255, 0, 0], true));
+    assert_eq!(capture(1.25), (vec![0, 0, 255], true));
+
+    let missing = FileSource {
+        path: source.path.join("missing.png"),
+        ..source
+    };
+    let result = FileCapturer::new(
+        &context,
+        &missing,
+        None,
+        FrameLimiter::new(None),
+        FileClock::Wall,
+    );
+    assert!(matches!(result, Err(CaptureError::Failed(_))));
+}
+
+#[test]
+fn cylinder_screen() {
+    let mut config = Config::default();
+    config.virtual_screen.shape = ScreenShape::Cylinder;
+    config.virtual_screen.arc = Some(120.0);
+    let frames = render(&config, &[Pattern::Grid], &[wide_viewer()]);
+    assert_golden("cylinder_screen", &frames[0]);
+}
+
+#[test]
+fn sphere_screen() {
+    let mut config = Config::default();
+    config.virtual_screen.shape = ScreenShape::Sphere;
+    let frames = render(&config, &[Pattern::Grid], &[wide_viewer()]);
+    assert_golden("sphere_screen", &frames[0]);
+}
@@ -0,0 +1,105 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// X11 capture from a virtual X server. Ignored by default, as it needs `Xvfb`:
+//   cargo test --test x11 -- --ignored
+
+use std::{
+    env,
+    path::Path,
+    process::{Child, Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use okulekrano::{
+    capturer::{limiter::FrameLimiter, x11::X11Capturer, CaptureError, Capturer},
+    config::{OutputMatch, Region},
+    offscreen,
+};
+
+const SCREEN_SIZE: (u32, u32) = (320, 240);
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+// Killed when dropped
+struct Xvfb(Child);
+
+impl Drop for Xvfb {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+// Start a virtual X server on an unused display, and point DISPLAY to it
+fn start_xvfb() -> Xvfb {
+    let number = (90..200)
+        .find(|n| !Path::new(&format!("/tmp/.X11-unix/X{}", n)).exists())
+        .expect("no free X display");
+    let child = Command::new("Xvfb")
+        .arg(format!(":{}", number))
+        .args(["-screen", "0"])
+        .arg(format!("{}x{}x24", SCREEN_SIZE.0, SCREEN_SIZE.1))
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("cannot start Xvfb");
+    let xvfb = Xvfb(child);
+
+    let socket = format!("/tmp/.X11-unix/X{}", number);
+    let deadline = Instant::now() + TIMEOUT;
+    while !Path::new(&socket).exists() {
+        assert!(Instant::now() < deadline, "Xvfb did not start");
+        thread::sleep(Duration::from_millis(50));
+    }
+    env::set_var("DISPLAY", format!(":{}", number));
+    xvfb
+}
+
+// Size of the first captured frame
+fn capture_size(capturer: &mut X11Capturer) -> (u32, u32) {
+    let deadline = Instant::now() + TIMEOUT;
+    loop {
+        match capturer.capture() {
+            Ok(frame) => return frame.texture.dimensions(),
+            Err(CaptureError::NotReady) if Instant::now() < deadline => {
+                thread::sleep(Duration::from_millis(20))
+            }
+            Err(err) => panic!("No frame captured: {}", err),
+        }
+    }
+}
+
+fn region(x: i32, y: i32, width: i32, height: i32) -> Option<Region> {
+    Some(Region {
+        x,
+        y,
+        width,
+        height,
+    })
+}
+
+// One test, because DISPLAY is shared by the whole process
+#[test]
+#[ignore = "needs Xvfb"]
+fn xvfb_capture() {
+    let _xvfb = start_xvfb();
+    let context = offscreen::create_context((64, 64)).unwrap();
+    let limiter = FrameLimiter::new(None);
+
+    let mut whole = X11Capturer::new(&context, None, None, limiter).unwrap();
+    assert_eq!(capture_size(&mut whole), SCREEN_SIZE);
+
+    // Clipped to the screen
+    let mut clipped =
+        X11Capturer::new(&context, None, region(300, 200, 100, 100), limiter).unwrap();
+    assert_eq!(capture_size(&mut clipped), (20, 40));
+
+    let outside = X11Capturer::new(&context, None, region(400, 0, 10, 10), limiter).err();
+    assert!(matches!(outside, Some(CaptureError::Unsupported(_))));
+
+    let missing = OutputMatch::by_name("NO-SUCH-MONITOR");
+    let missing = X11Capturer::new(&context, Some(&missing), None, limiter).err();
+    assert!(matches!(missing, Some(CaptureError::OutputNotFound(_))));
+}